@@ -4,36 +4,204 @@ use axum::{
 };
 use serde::Serialize;
 
-#[derive(Debug, Clone, bon::Builder, Serialize)]
+/// API error, returned in Fastly's error body schema.
+#[derive(Debug, Clone, bon::Builder)]
 #[builder(on(String, into))]
 pub struct Error {
     #[builder(default = http::StatusCode::INTERNAL_SERVER_ERROR)]
-    #[serde(skip)]
     status_code: http::StatusCode,
+    /// Short description, `msg` in the body
     message: String,
+    /// Longer explanation of what was wrong with the request
+    detail: Option<String>,
+    /// Seconds after which retrying should succeed, sent as `Retry-After`
+    retry_after: Option<u64>,
+}
+
+/// Error body of the Fastly API.
+///
+/// The Fastly CLI reads `msg` and `detail`, while newer API clients read `errors`.
+#[derive(Debug, Serialize, utoipa::ToSchema)]
+#[schema(as = Error)]
+pub struct ErrorBody<'a> {
+    msg: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+    errors: [ErrorObject<'a>; 1],
+}
+
+#[derive(Debug, Serialize, utoipa::ToSchema)]
+struct ErrorObject<'a> {
+    title: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+    status: String,
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
-        tracing::debug!(error.message = self.message, "API error");
-        (self.status_code, axum::Json(self)).into_response()
+        tracing::debug!(
+            error.message = self.message,
+            error.detail = self.detail,
+            "API error"
+        );
+
+        let body = ErrorBody {
+            msg: &self.message,
+            detail: self.detail.as_deref(),
+            errors: [ErrorObject {
+                title: &self.message,
+                detail: self.detail.as_deref(),
+                status: self.status_code.as_u16().to_string(),
+            }],
+        };
+
+        let mut response = (self.status_code, axum::Json(body)).into_response();
+        if let Some(retry_after) = self.retry_after {
+            response
+                .headers_mut()
+                .insert(http::header::RETRY_AFTER, retry_after.into());
+        }
+
+        response
     }
 }
 
-impl<E> From<E> for Error
-where
-    E: std::error::Error,
-{
-    fn from(err: E) -> Self {
-        tracing::error!(error.message = %err, "Internal error");
-        Error::builder().message("Internal server error").build()
+impl From<redb::Error> for Error {
+    fn from(err: redb::Error) -> Self {
+        use redb::Error as E;
+
+        let builder = Error::builder();
+        let builder = match &err {
+            // Handlers check for the tables they expect to be missing, so this is a store or
+            // item that went away since, or a handler missing a check.
+            E::TableDoesNotExist(_) => {
+                tracing::warn!(error.message = %err, "Missing table");
+                builder.not_found().message("Not found")
+            }
+            E::TableAlreadyOpen(..)
+            | E::TransactionInProgress
+            | E::ReadTransactionStillInUse(_) => {
+                tracing::warn!(error.message = %err, "Conflicting transaction");
+                builder
+                    .status_code(http::StatusCode::SERVICE_UNAVAILABLE)
+                    .message("Conflicting concurrent request, try again")
+            }
+            E::ValueTooLarge(_) => builder
+                .status_code(http::StatusCode::PAYLOAD_TOO_LARGE)
+                .message("Value too large"),
+            E::Io(io) if io.kind() == std::io::ErrorKind::StorageFull => builder
+                .status_code(http::StatusCode::INSUFFICIENT_STORAGE)
+                .message("Storage full"),
+            E::DatabaseClosed | E::PreviousIo | E::LockPoisoned(_) => builder
+                .status_code(http::StatusCode::SERVICE_UNAVAILABLE)
+                .message("Service unavailable"),
+            _ => {
+                tracing::error!(error.message = %err, "Internal error");
+                return Error::builder().message("Internal server error").build();
+            }
+        };
+
+        // Another transaction holds what this one needs, retrying should succeed.
+        let retry_after = matches!(
+            err,
+            E::TableAlreadyOpen(..) | E::TransactionInProgress | E::ReadTransactionStillInUse(_)
+        )
+        .then_some(1);
+
+        builder
+            .detail(err.to_string())
+            .maybe_retry_after(retry_after)
+            .build()
     }
 }
 
+/// Rewrite error responses not built from an [`Error`], like axum's rejections of invalid
+/// requests and unknown routes, into Fastly's error schema.
+pub async fn fastly_error_body(response: Response) -> Response {
+    use http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+
+    let status = response.status();
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+    if !(status.is_client_error() || status.is_server_error()) || is_json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let detail = axum::body::to_bytes(body, 64 * 1024)
+        .await
+        .ok()
+        .map(|body| String::from_utf8_lossy(&body).into_owned())
+        .filter(|detail| !detail.is_empty());
+
+    // Fastly reports request bodies that fail to deserialize as bad requests.
+    let status = match status {
+        http::StatusCode::UNPROCESSABLE_ENTITY => http::StatusCode::BAD_REQUEST,
+        status => status,
+    };
+
+    let mut response = Error::builder()
+        .status_code(status)
+        .message(status.canonical_reason().unwrap_or("Error"))
+        .maybe_detail(detail)
+        .build()
+        .into_response();
+
+    // Keep headers like `Allow`, describing the error.
+    parts.headers.remove(CONTENT_TYPE);
+    parts.headers.remove(CONTENT_LENGTH);
+    response.headers_mut().extend(parts.headers);
+
+    response
+}
+
+macro_rules! from_redb_error {
+    ($($ty:ty),*) => {
+        $(
+            impl From<$ty> for Error {
+                fn from(err: $ty) -> Self {
+                    redb::Error::from(err).into()
+                }
+            }
+        )*
+    };
+}
+
+from_redb_error!(
+    redb::StorageError,
+    redb::TableError,
+    redb::TransactionError,
+    redb::CommitError
+);
+
 const _: () = {
     use error_builder::*;
 
     impl<S: State> ErrorBuilder<S> {
+        pub fn bad_request(self) -> ErrorBuilder<SetStatusCode<S>>
+        where
+            S::StatusCode: IsUnset,
+        {
+            self.status_code(http::StatusCode::BAD_REQUEST)
+        }
+
+        pub fn unauthorized(self) -> ErrorBuilder<SetStatusCode<S>>
+        where
+            S::StatusCode: IsUnset,
+        {
+            self.status_code(http::StatusCode::UNAUTHORIZED)
+        }
+
+        pub fn forbidden(self) -> ErrorBuilder<SetStatusCode<S>>
+        where
+            S::StatusCode: IsUnset,
+        {
+            self.status_code(http::StatusCode::FORBIDDEN)
+        }
+
         pub fn not_found(self) -> ErrorBuilder<SetStatusCode<S>>
         where
             S::StatusCode: IsUnset,
@@ -48,6 +216,13 @@ const _: () = {
             self.status_code(http::StatusCode::CONFLICT)
         }
 
+        pub fn precondition_failed(self) -> ErrorBuilder<SetStatusCode<S>>
+        where
+            S::StatusCode: IsUnset,
+        {
+            self.status_code(http::StatusCode::PRECONDITION_FAILED)
+        }
+
         #[allow(unused)]
         pub fn not_implemented(self) -> ErrorBuilder<SetMessage<SetStatusCode<S>>>
         where
@@ -59,3 +234,49 @@ const _: () = {
         }
     }
 };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn redb_errors_get_fastly_statuses() {
+        let cases = [
+            (
+                redb::Error::TableDoesNotExist("table".to_string()),
+                http::StatusCode::NOT_FOUND,
+                None,
+            ),
+            (
+                redb::Error::TransactionInProgress,
+                http::StatusCode::SERVICE_UNAVAILABLE,
+                Some("1"),
+            ),
+            (
+                redb::Error::DatabaseClosed,
+                http::StatusCode::SERVICE_UNAVAILABLE,
+                None,
+            ),
+        ];
+
+        for (err, status, retry_after) in cases {
+            let detail = err.to_string();
+            let response = Error::from(err).into_response();
+            assert_eq!(response.status(), status);
+            assert_eq!(
+                response
+                    .headers()
+                    .get(http::header::RETRY_AFTER)
+                    .map(|value| value.to_str().unwrap()),
+                retry_after
+            );
+
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert!(body["msg"].is_string());
+            assert_eq!(body["detail"], detail);
+        }
+    }
+}
@@ -5,13 +5,30 @@ use miette::IntoDiagnostic;
 use redb::Database;
 use tokio_graceful_shutdown::SubsystemHandle;
 
+use crate::crypto::{ClientKeys, MasterKey};
+
 mod error;
+mod openapi;
+mod rate_limit;
+mod service;
 mod stores;
+#[cfg(test)]
+pub(crate) mod testing;
+mod tokens;
 mod util;
 
 #[derive(Clone)]
 struct Context {
     pub db: Arc<Database>,
+    /// Reject writes exceeding Fastly's store limits instead of only warning about them
+    pub strict_limits: bool,
+    /// Reject requests without a valid `Fastly-Key` token
+    pub require_auth: bool,
+    /// Hourly write limits per token, if enabled
+    pub rate_limiter: Option<Arc<rate_limit::RateLimiter>>,
+    pub client_keys: Arc<ClientKeys>,
+    /// Key secrets are encrypted with before being stored
+    pub master_key: Arc<MasterKey>,
 }
 
 type Result<T> = std::result::Result<T, error::Error>;
@@ -21,12 +38,23 @@ pub async fn run(
     subsys: &mut SubsystemHandle,
     db: Arc<Database>,
     listen_addr: SocketAddr,
+    strict_limits: bool,
+    require_auth: bool,
+    rate_limit: Option<u32>,
+    master_key: Arc<MasterKey>,
 ) -> miette::Result<()> {
     use tokio::net::TcpListener;
 
-    let ctx = Context { db };
+    let ctx = Context {
+        db,
+        strict_limits,
+        require_auth,
+        rate_limiter: rate_limit.map(|limit| Arc::new(rate_limit::RateLimiter::new(limit))),
+        client_keys: Arc::new(ClientKeys::generate()),
+        master_key,
+    };
 
-    let app = router().with_state(ctx);
+    let app = router(&ctx).with_state(ctx);
 
     let listener = TcpListener::bind(listen_addr)
         .await
@@ -41,7 +69,8 @@ pub async fn run(
         .into_diagnostic()
 }
 
-fn router() -> Router {
+fn router(ctx: &Context) -> Router {
+    use axum::middleware;
     use tower_http::trace::TraceLayer;
 
     use crate::util::OtelTrace;
@@ -55,5 +84,18 @@ fn router() -> Router {
 
     Router::new()
         .nest("/resources/stores", stores::router())
+        .nest("/service", service::router())
+        .nest("/tokens", tokens::router())
+        .merge(openapi::router())
+        .layer(middleware::map_response(error::fastly_error_body))
+        // Runs after authentication, to count writes against the token they're made with.
+        .layer(middleware::from_fn_with_state(
+            ctx.clone(),
+            rate_limit::rate_limit,
+        ))
+        .layer(middleware::from_fn_with_state(
+            ctx.clone(),
+            tokens::authenticate,
+        ))
         .layer(trace_layer)
 }
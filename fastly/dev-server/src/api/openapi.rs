@@ -0,0 +1,272 @@
+//! OpenAPI description of the store management API, served with Swagger UI.
+
+use axum::routing::{MethodFilter, MethodRouter};
+use utoipa::OpenApi;
+use utoipa::openapi::OpenApi as Document;
+
+use crate::api::{Context, Router, stores};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "fastly-dev-server",
+        description = "Local emulation of the Fastly store management API"
+    ),
+    nest((path = "/resources/stores", api = stores::ApiDoc)),
+    components(schemas(super::error::ErrorBody)),
+    modifiers(&ErrorResponses),
+)]
+pub struct ApiDoc;
+
+/// Document errors, which every operation can return, as the default response.
+struct ErrorResponses;
+
+impl utoipa::Modify for ErrorResponses {
+    fn modify(&self, openapi: &mut Document) {
+        use utoipa::openapi::{ContentBuilder, Ref, ResponseBuilder};
+
+        let response = ResponseBuilder::new()
+            .description("Error, in Fastly's schema")
+            .content(
+                "application/json",
+                ContentBuilder::new()
+                    .schema(Some(Ref::from_schema_name("Error")))
+                    .build(),
+            )
+            .build();
+
+        for item in openapi.paths.paths.values_mut() {
+            let operations = [
+                &mut item.get,
+                &mut item.put,
+                &mut item.post,
+                &mut item.delete,
+                &mut item.patch,
+            ];
+            for operation in operations.into_iter().flatten() {
+                operation
+                    .responses
+                    .responses
+                    .entry("default".to_string())
+                    .or_insert_with(|| response.clone().into());
+            }
+        }
+    }
+}
+
+/// Declare the `ApiDoc` of a module and the `router` serving it from one list of handlers
+/// documented with `#[utoipa::path]`, so every route is documented and every documented
+/// operation is routed.
+///
+/// Each handler is routed at the paths and methods it's documented with, wrapped in the layer
+/// following it if any. The `ApiDoc` and routes of the modules listed in `merge` are included.
+macro_rules! documented_routes {
+    (
+        $($handler:ident $(=> $layer:expr)?),* $(,)?
+        $(; merge($($module:ident),* $(,)?))?
+    ) => {
+        #[derive(utoipa::OpenApi)]
+        #[openapi(paths($($handler),*), modifiers(&MergedModules))]
+        pub struct ApiDoc;
+
+        struct MergedModules;
+
+        impl utoipa::Modify for MergedModules {
+            #[allow(unused_variables)]
+            fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+                $($(openapi.merge(<$module::ApiDoc as utoipa::OpenApi>::openapi());)*)?
+            }
+        }
+
+        pub fn router() -> $crate::api::Router {
+            let document = <ApiDoc as utoipa::OpenApi>::openapi();
+
+            let router = $crate::api::Router::new();
+            $(
+                let router = $crate::api::openapi::route(
+                    router,
+                    &document,
+                    stringify!($handler),
+                    |method| {
+                        let method_router = axum::routing::on(method, $handler);
+                        $(let method_router = method_router.layer($layer);)?
+                        method_router
+                    },
+                );
+            )*
+            $($(let router = router.merge($module::router());)*)?
+
+            router
+        }
+    };
+}
+
+pub(crate) use documented_routes;
+
+/// Route a handler at every path and method its operation is documented with.
+pub fn route(
+    mut router: Router,
+    document: &Document,
+    operation_id: &str,
+    method_router: impl Fn(MethodFilter) -> MethodRouter<Context>,
+) -> Router {
+    let mut routed = false;
+
+    for (path, item) in &document.paths.paths {
+        let operations = [
+            (MethodFilter::GET, &item.get),
+            (MethodFilter::PUT, &item.put),
+            (MethodFilter::POST, &item.post),
+            (MethodFilter::DELETE, &item.delete),
+            (MethodFilter::PATCH, &item.patch),
+        ];
+
+        for (method, operation) in operations {
+            let documented = operation
+                .as_ref()
+                .is_some_and(|operation| operation.operation_id.as_deref() == Some(operation_id));
+            if documented {
+                // Operations at the root of a nested document have an empty path.
+                let path = if path.is_empty() { "/" } else { path };
+                router = router.route(path, method_router(method));
+                routed = true;
+            }
+        }
+    }
+
+    assert!(routed, "Operation `{operation_id}` is not documented");
+    router
+}
+
+/// Serve the document at `/openapi.json` and Swagger UI at `/docs`.
+pub fn router() -> Router {
+    use utoipa_swagger_ui::SwaggerUi;
+
+    SwaggerUi::new("/docs")
+        .url("/openapi.json", ApiDoc::openapi())
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use axum::body::Body;
+    use http::{Method, Request, StatusCode};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::api::testing;
+
+    const METHODS: [Method; 5] = [
+        Method::GET,
+        Method::PUT,
+        Method::POST,
+        Method::DELETE,
+        Method::PATCH,
+    ];
+
+    /// Status of requests no route matches, unlike any a handler returns.
+    const UNROUTED: StatusCode = StatusCode::IM_A_TEAPOT;
+
+    /// The API router, answering requests no route matches with [`UNROUTED`].
+    fn app() -> axum::Router {
+        let ctx = testing::context();
+        crate::api::router(&ctx)
+            .fallback(async || UNROUTED)
+            .with_state(ctx)
+    }
+
+    /// Whether a route handles the method on the path. The handler may still fail, but not with
+    /// the 404 or 405 of a missing route.
+    async fn is_routed(app: &axum::Router, method: &Method, path: &str) -> bool {
+        let request = Request::builder()
+            .method(method)
+            .uri(fill_params(path))
+            .body(Body::empty())
+            .unwrap();
+        let status = app.clone().oneshot(request).await.unwrap().status();
+
+        status != StatusCode::METHOD_NOT_ALLOWED && status != UNROUTED
+    }
+
+    /// Path with its parameters filled in, like `/resources/stores/kv/x/keys/x`.
+    fn fill_params(path: &str) -> String {
+        let mut filled = String::new();
+        let mut in_param = false;
+        for c in path.chars() {
+            match c {
+                '{' => in_param = true,
+                '}' => {
+                    in_param = false;
+                    filled.push('x');
+                }
+                _ if in_param => {}
+                _ => filled.push(c),
+            }
+        }
+        filled
+    }
+
+    fn documented_operations() -> HashSet<(String, Method)> {
+        let document = ApiDoc::openapi();
+
+        let mut operations = HashSet::new();
+        for (path, item) in &document.paths.paths {
+            let methods = [
+                (Method::GET, &item.get),
+                (Method::PUT, &item.put),
+                (Method::POST, &item.post),
+                (Method::DELETE, &item.delete),
+                (Method::PATCH, &item.patch),
+            ];
+            for (method, _) in methods.into_iter().filter(|(_, op)| op.is_some()) {
+                operations.insert((path.clone(), method));
+            }
+        }
+        operations
+    }
+
+    /// Store routes are built from their documentation, so this checks both are served whole.
+    #[tokio::test]
+    async fn documented_operations_are_routed() {
+        let app = app();
+
+        let operations = documented_operations();
+        for path in [
+            "/resources/stores/kv",
+            "/resources/stores/kv/{store_id}/keys/{key}",
+            "/resources/stores/config/{store_id}/items",
+            "/resources/stores/secret/client-key",
+            "/resources/stores/secret/{store_id}/secrets",
+        ] {
+            assert!(
+                operations.iter().any(|(documented, _)| documented == path),
+                "{path} is not documented"
+            );
+        }
+
+        let paths: HashSet<_> = operations.iter().map(|(path, _)| path).collect();
+        for path in paths {
+            for method in &METHODS {
+                let documented = operations.contains(&(path.clone(), method.clone()));
+                assert_eq!(
+                    is_routed(&app, method, path).await,
+                    documented,
+                    "{method} {path} is {} but not {}",
+                    if documented { "documented" } else { "routed" },
+                    if documented { "routed" } else { "documented" },
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn operations_are_named_after_their_handler() {
+        let document = stores::ApiDoc::openapi();
+        let item = &document.paths.paths["/kv/{store_id}/keys/{key}"];
+
+        let operation = item.put.as_ref().unwrap();
+        assert_eq!(operation.operation_id.as_deref(), Some("upsert_kv_item"));
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use axum::extract::{Request, State};
+use axum::http::{HeaderName, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+
+use super::tokens::Authenticated;
+use crate::api::{Context, error::Error};
+
+static RATE_LIMIT_REMAINING: HeaderName = HeaderName::from_static("fastly-ratelimit-remaining");
+static RATE_LIMIT_RESET: HeaderName = HeaderName::from_static("fastly-ratelimit-reset");
+
+/// Hourly write limits per token, like Fastly's API rate limits.
+///
+/// Windows start on the hour, and every write counts towards the limit, failed or not.
+pub struct RateLimiter {
+    limit: u32,
+    window: Mutex<Window>,
+}
+
+/// Writes counted in the current hour, by token.
+#[derive(Default)]
+struct Window {
+    start: DateTime<Utc>,
+    counts: HashMap<String, u32>,
+}
+
+/// Outcome of counting a write against its token's limit.
+struct Usage {
+    allowed: bool,
+    remaining: u32,
+    reset: DateTime<Utc>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32) -> Self {
+        RateLimiter {
+            limit,
+            window: Mutex::default(),
+        }
+    }
+
+    fn hit(&self, key: &str, now: DateTime<Utc>) -> Usage {
+        let start = now
+            .duration_trunc(TimeDelta::hours(1))
+            .expect("An hour fits in a timestamp");
+
+        let mut window = self.window.lock().expect("Rate limiter lock poisoned");
+        // Every token's window starts on the hour, so they all pass at once.
+        if window.start != start {
+            *window = Window {
+                start,
+                counts: HashMap::new(),
+            };
+        }
+
+        let count = window.counts.entry(key.to_string()).or_default();
+        let allowed = *count < self.limit;
+        if allowed {
+            *count += 1;
+        }
+
+        Usage {
+            allowed,
+            remaining: self.limit - *count,
+            reset: start + TimeDelta::hours(1),
+        }
+    }
+}
+
+/// Count writes against the limit of the token they're made with, when rate limiting is on.
+///
+/// Requests without a token share a single limit. Like Fastly, reads aren't limited and only
+/// writes get the `Fastly-RateLimit-*` headers.
+pub async fn rate_limit(State(ctx): State<Context>, req: Request, next: Next) -> Response {
+    let Some(limiter) = &ctx.rate_limiter else {
+        return next.run(req).await;
+    };
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.run(req).await;
+    }
+
+    let key = req
+        .extensions()
+        .get::<Authenticated>()
+        .map_or("", Authenticated::id);
+    let usage = limiter.hit(key, Utc::now());
+
+    let mut response = if usage.allowed {
+        next.run(req).await
+    } else {
+        let retry_after = (usage.reset - Utc::now()).num_seconds().max(1);
+
+        Error::builder()
+            .status_code(StatusCode::TOO_MANY_REQUESTS)
+            .message("Rate limit exceeded")
+            .detail(format!(
+                "Too many write requests, the limit resets at {}",
+                usage.reset.to_rfc3339()
+            ))
+            .retry_after(retry_after as u64)
+            .build()
+            .into_response()
+    };
+
+    let headers = response.headers_mut();
+    headers.insert(
+        RATE_LIMIT_REMAINING.clone(),
+        HeaderValue::from(usage.remaining),
+    );
+    headers.insert(
+        RATE_LIMIT_RESET.clone(),
+        HeaderValue::from(usage.reset.timestamp()),
+    );
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::TimeZone;
+    use http::Method;
+
+    use super::*;
+    use crate::api::testing::{self, send};
+
+    #[test]
+    fn writes_are_limited_per_token_and_hour() {
+        let limiter = RateLimiter::new(2);
+        let now = Utc.with_ymd_and_hms(2025, 1, 1, 10, 59, 0).unwrap();
+        let reset = Utc.with_ymd_and_hms(2025, 1, 1, 11, 0, 0).unwrap();
+
+        for (allowed, remaining) in [(true, 1), (true, 0), (false, 0)] {
+            let usage = limiter.hit("a", now);
+            assert_eq!((usage.allowed, usage.remaining), (allowed, remaining));
+            assert_eq!(usage.reset, reset);
+        }
+
+        let usage = limiter.hit("b", now);
+        assert!(usage.allowed);
+        assert_eq!(usage.remaining, 1);
+    }
+
+    #[test]
+    fn windows_roll_over_on_the_hour() {
+        let limiter = RateLimiter::new(1);
+        let now = Utc.with_ymd_and_hms(2025, 1, 1, 10, 59, 59).unwrap();
+        assert!(limiter.hit("a", now).allowed);
+        assert!(limiter.hit("b", now).allowed);
+        assert!(!limiter.hit("a", now).allowed);
+
+        let later = Utc.with_ymd_and_hms(2025, 1, 1, 11, 0, 0).unwrap();
+        let usage = limiter.hit("a", later);
+        assert!(usage.allowed);
+        assert_eq!(usage.reset, later + TimeDelta::hours(1));
+
+        // Tokens that didn't write since are forgotten.
+        let window = limiter.window.lock().unwrap();
+        assert_eq!(window.start, later);
+        assert_eq!(window.counts.keys().collect::<Vec<_>>(), ["a"]);
+    }
+
+    #[tokio::test]
+    async fn writes_get_rate_limit_headers() {
+        let ctx = crate::api::Context {
+            rate_limiter: Some(Arc::new(RateLimiter::new(1))),
+            ..testing::context()
+        };
+        let app = testing::app(ctx);
+
+        let read = send(&app, Method::GET, "/resources/stores/kv", &[], "").await;
+        assert_eq!(read.status, StatusCode::OK);
+        assert!(!read.headers.contains_key(&RATE_LIMIT_REMAINING));
+
+        let body = || serde_json::json!({ "name": "store" }).to_string();
+        let write = send(&app, Method::POST, "/resources/stores/kv", &[], body()).await;
+        assert_eq!(write.status, StatusCode::OK);
+        assert_eq!(write.headers[&RATE_LIMIT_REMAINING], "0");
+        let reset: i64 = write.headers[&RATE_LIMIT_RESET]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(reset % 3600, 0);
+        assert!(reset > Utc::now().timestamp());
+
+        let limited = send(&app, Method::POST, "/resources/stores/kv", &[], body()).await;
+        assert_eq!(limited.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(limited.headers[&RATE_LIMIT_REMAINING], "0");
+        assert!(limited.headers.contains_key(http::header::RETRY_AFTER));
+        assert_eq!(limited.json()["msg"], "Rate limit exceeded");
+    }
+}
@@ -0,0 +1,176 @@
+use axum::extract::{Form, Json, Path, State};
+use chrono::{DateTime, Utc};
+use redb::ReadableDatabase;
+use serde::{Deserialize, Serialize};
+
+use super::{StatusResponse, find_editable_version, find_version, load_metadata, read_metadata};
+use crate::api::{Context, Result, Router, error::Error};
+use crate::tables::{DomainMetadata, METADATA_TABLE};
+use crate::util::JsonRecord;
+
+pub fn router() -> Router {
+    use axum::routing;
+
+    Router::new()
+        .route(
+            "/{service_id}/version/{version}/domain",
+            routing::get(list_domains).post(create_domain),
+        )
+        .route(
+            "/{service_id}/version/{version}/domain/{name}",
+            routing::get(get_domain).delete(delete_domain),
+        )
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Domain {
+    name: String,
+    comment: String,
+    service_id: String,
+    version: u32,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+impl Domain {
+    fn new(service_id: &str, version: u32, domain_meta: &DomainMetadata) -> Self {
+        Domain {
+            name: domain_meta.name.clone(),
+            comment: domain_meta.comment.clone(),
+            service_id: service_id.to_string(),
+            version,
+            created_at: domain_meta.created_at,
+            updated_at: domain_meta.updated_at,
+            deleted_at: None,
+        }
+    }
+}
+
+async fn list_domains(
+    Path((service_id, version)): Path<(String, u32)>,
+    State(ctx): State<Context>,
+) -> Result<Json<Vec<Domain>>> {
+    let tx = ctx.db.begin_read()?;
+
+    let metadata = &read_metadata(&tx)?;
+
+    let version_meta = find_version(metadata, &service_id, version)?;
+
+    let entries = version_meta
+        .domains
+        .iter()
+        .map(|domain_meta| Domain::new(&service_id, version, domain_meta))
+        .collect::<Vec<Domain>>();
+
+    Ok(Json(entries))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct CreateDomainRequest {
+    name: String,
+    #[serde(default)]
+    comment: String,
+}
+
+async fn create_domain(
+    Path((service_id, version)): Path<(String, u32)>,
+    State(ctx): State<Context>,
+    Form(payload): Form<CreateDomainRequest>,
+) -> Result<Json<Domain>> {
+    let tx = ctx.db.begin_write()?;
+
+    let domain = {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let mut metadata = load_metadata(&metadata_table)?;
+
+        let version_meta = find_editable_version(&mut metadata, &service_id, version)?;
+
+        if version_meta
+            .domains
+            .iter()
+            .any(|domain_meta| domain_meta.name == payload.name)
+        {
+            return Err(Error::builder()
+                .conflict()
+                .message("Domain already exists")
+                .build());
+        }
+
+        let now = Utc::now();
+
+        let domain_meta = DomainMetadata {
+            name: payload.name,
+            comment: payload.comment,
+            created_at: now,
+            updated_at: now,
+        };
+        let domain = Domain::new(&service_id, version, &domain_meta);
+        version_meta.domains.push(domain_meta);
+        version_meta.updated_at = now;
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+
+        domain
+    };
+
+    tx.commit()?;
+
+    Ok(Json(domain))
+}
+
+async fn get_domain(
+    Path((service_id, version, name)): Path<(String, u32, String)>,
+    State(ctx): State<Context>,
+) -> Result<Json<Domain>> {
+    let tx = ctx.db.begin_read()?;
+
+    let metadata = &read_metadata(&tx)?;
+
+    let version_meta = find_version(metadata, &service_id, version)?;
+
+    let Some(domain_meta) = version_meta
+        .domains
+        .iter()
+        .find(|domain_meta| domain_meta.name == name)
+    else {
+        return Err(Error::builder()
+            .not_found()
+            .message("Domain not found")
+            .build());
+    };
+
+    Ok(Json(Domain::new(&service_id, version, domain_meta)))
+}
+
+async fn delete_domain(
+    Path((service_id, version, name)): Path<(String, u32, String)>,
+    State(ctx): State<Context>,
+) -> Result<Json<StatusResponse>> {
+    let tx = ctx.db.begin_write()?;
+
+    {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let mut metadata = load_metadata(&metadata_table)?;
+
+        let version_meta = find_editable_version(&mut metadata, &service_id, version)?;
+
+        let count = version_meta.domains.len();
+        version_meta
+            .domains
+            .retain(|domain_meta| domain_meta.name != name);
+        if version_meta.domains.len() == count {
+            return Err(Error::builder()
+                .not_found()
+                .message("Domain not found")
+                .build());
+        }
+        version_meta.updated_at = Utc::now();
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+    }
+
+    tx.commit()?;
+
+    Ok(Json(StatusResponse { status: "ok" }))
+}
@@ -0,0 +1,419 @@
+use axum::extract::{Form, Json, Path, Query, State};
+use chrono::{DateTime, Utc};
+use redb::{ReadTransaction, ReadableDatabase, ReadableTable};
+use serde::{Deserialize, Serialize};
+
+use crate::api::{Context, Result, Router, error::Error};
+use crate::tables::{
+    METADATA_TABLE, Metadata, PACKAGES_TABLE, ServiceMetadata, ServiceVersionMetadata,
+};
+use crate::util::JsonRecord;
+
+mod domain;
+mod package;
+mod resource;
+mod version;
+
+use version::Version;
+
+pub fn router() -> Router {
+    use axum::routing;
+
+    Router::new()
+        .route("/", routing::get(list_services).post(create_service))
+        .route("/search", routing::get(search_service))
+        .route(
+            "/{service_id}",
+            routing::get(get_service)
+                .put(update_service)
+                .delete(delete_service),
+        )
+        .route("/{service_id}/details", routing::get(get_service_details))
+        .merge(version::router())
+        .merge(package::router())
+        .merge(domain::router())
+        .merge(resource::router())
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Service {
+    id: String,
+    name: String,
+    #[serde(rename = "type")]
+    service_type: &'static str,
+    comment: String,
+    /// Active version number
+    version: Option<u32>,
+    versions: Vec<Version>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+impl Service {
+    fn new(id: &str, service_meta: &ServiceMetadata) -> Self {
+        Service {
+            id: id.to_string(),
+            name: service_meta.name.clone(),
+            service_type: "wasm",
+            comment: service_meta.comment.clone(),
+            version: service_meta
+                .active_version()
+                .map(|version_meta| version_meta.number),
+            versions: service_meta
+                .versions
+                .iter()
+                .map(|version_meta| Version::new(id, version_meta))
+                .collect(),
+            created_at: service_meta.created_at,
+            updated_at: service_meta.updated_at,
+            deleted_at: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ServiceDetails {
+    id: String,
+    name: String,
+    #[serde(rename = "type")]
+    service_type: &'static str,
+    comment: String,
+    active_version: Option<Version>,
+    /// Latest version
+    version: Option<Version>,
+    versions: Vec<Version>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+/// Read the metadata, which is empty until anything was written, so looking up a service in
+/// it fails with a 404 like for any other missing service.
+fn read_metadata(tx: &ReadTransaction) -> Result<Metadata> {
+    match tx.open_table(METADATA_TABLE) {
+        Ok(table) => load_metadata(&table),
+        Err(redb::TableError::TableDoesNotExist(_)) => Ok(Metadata::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Load the metadata from its table, to modify it or look services up in it.
+fn load_metadata(table: &impl ReadableTable<(), JsonRecord<Metadata>>) -> Result<Metadata> {
+    Ok(table
+        .get(&())?
+        .map(|record| record.value().0)
+        .unwrap_or_default())
+}
+
+fn service_not_found() -> Error {
+    Error::builder()
+        .not_found()
+        .message("Service not found")
+        .build()
+}
+
+fn version_not_found() -> Error {
+    Error::builder()
+        .not_found()
+        .message("Service version not found")
+        .build()
+}
+
+/// Look up a service, failing with a 404 when it doesn't exist.
+fn find_service<'a>(metadata: &'a Metadata, service_id: &str) -> Result<&'a ServiceMetadata> {
+    metadata
+        .services
+        .get(service_id)
+        .ok_or_else(service_not_found)
+}
+
+/// Look up a service for modification, failing with a 404 when it doesn't exist.
+fn find_service_mut<'a>(
+    metadata: &'a mut Metadata,
+    service_id: &str,
+) -> Result<&'a mut ServiceMetadata> {
+    metadata
+        .services
+        .get_mut(service_id)
+        .ok_or_else(service_not_found)
+}
+
+/// Look up a service version, failing with a 404 when either doesn't exist.
+fn find_version<'a>(
+    metadata: &'a Metadata,
+    service_id: &str,
+    version: u32,
+) -> Result<&'a ServiceVersionMetadata> {
+    find_service(metadata, service_id)?
+        .version(version)
+        .ok_or_else(version_not_found)
+}
+
+/// Look up a service version for modification, failing with a 404 when either doesn't exist.
+///
+/// Locked versions are returned too, for changing their state rather than their configuration,
+/// see [`find_editable_version`].
+fn find_version_mut<'a>(
+    metadata: &'a mut Metadata,
+    service_id: &str,
+    version: u32,
+) -> Result<&'a mut ServiceVersionMetadata> {
+    find_service_mut(metadata, service_id)?
+        .version_mut(version)
+        .ok_or_else(version_not_found)
+}
+
+/// Look up a service version for modification, failing when it is locked.
+fn find_editable_version<'a>(
+    metadata: &'a mut Metadata,
+    service_id: &str,
+    version: u32,
+) -> Result<&'a mut ServiceVersionMetadata> {
+    let version_meta = find_version_mut(metadata, service_id, version)?;
+
+    if version_meta.locked {
+        return Err(Error::builder()
+            .bad_request()
+            .message(format!("Version {version} is locked"))
+            .build());
+    }
+
+    Ok(version_meta)
+}
+
+async fn list_services(State(ctx): State<Context>) -> Result<Json<Vec<Service>>> {
+    let tx = ctx.db.begin_read()?;
+
+    let metadata = &read_metadata(&tx)?;
+
+    let entries = metadata
+        .services
+        .iter()
+        .map(|(id, service_meta)| Service::new(id, service_meta))
+        .collect::<Vec<Service>>();
+
+    Ok(Json(entries))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct CreateServiceRequest {
+    name: String,
+    #[serde(default)]
+    comment: String,
+    #[serde(rename = "type")]
+    service_type: Option<String>,
+}
+
+async fn create_service(
+    State(ctx): State<Context>,
+    Form(payload): Form<CreateServiceRequest>,
+) -> Result<Json<Service>> {
+    if payload
+        .service_type
+        .as_deref()
+        .is_some_and(|service_type| service_type != "wasm")
+    {
+        return Err(Error::builder()
+            .bad_request()
+            .message("Only `wasm` services are supported")
+            .build());
+    }
+
+    let tx = ctx.db.begin_write()?;
+
+    let service = {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let mut metadata = load_metadata(&metadata_table)?;
+
+        let now = Utc::now();
+        let id = ulid::Ulid::new().to_string();
+
+        // Like on Fastly, a new service starts with an empty, editable first version.
+        let service_meta = ServiceMetadata {
+            name: payload.name,
+            comment: payload.comment,
+            versions: vec![ServiceVersionMetadata {
+                number: 1,
+                active: false,
+                locked: false,
+                comment: String::new(),
+                domains: Vec::new(),
+                package: None,
+                created_at: now,
+                updated_at: now,
+            }],
+            created_at: now,
+            updated_at: now,
+        };
+        let service = Service::new(&id, &service_meta);
+        metadata.services.insert(id, service_meta);
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+
+        service
+    };
+
+    tx.commit()?;
+
+    Ok(Json(service))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SearchServiceQuery {
+    name: String,
+}
+
+async fn search_service(
+    State(ctx): State<Context>,
+    Query(query): Query<SearchServiceQuery>,
+) -> Result<Json<Service>> {
+    let tx = ctx.db.begin_read()?;
+
+    let metadata = &read_metadata(&tx)?;
+
+    let service = metadata
+        .services
+        .iter()
+        .find(|(_, service_meta)| service_meta.name == query.name)
+        .map(|(id, service_meta)| Service::new(id, service_meta));
+    let Some(service) = service else {
+        return Err(Error::builder()
+            .not_found()
+            .message("Service not found")
+            .build());
+    };
+
+    Ok(Json(service))
+}
+
+async fn get_service(
+    Path(service_id): Path<String>,
+    State(ctx): State<Context>,
+) -> Result<Json<Service>> {
+    let tx = ctx.db.begin_read()?;
+
+    let metadata = &read_metadata(&tx)?;
+
+    let service_meta = find_service(metadata, &service_id)?;
+
+    Ok(Json(Service::new(&service_id, service_meta)))
+}
+
+async fn get_service_details(
+    Path(service_id): Path<String>,
+    State(ctx): State<Context>,
+) -> Result<Json<ServiceDetails>> {
+    let tx = ctx.db.begin_read()?;
+
+    let metadata = &read_metadata(&tx)?;
+
+    let service_meta = find_service(metadata, &service_id)?;
+
+    Ok(Json(ServiceDetails {
+        id: service_id.clone(),
+        name: service_meta.name.clone(),
+        service_type: "wasm",
+        comment: service_meta.comment.clone(),
+        active_version: service_meta
+            .active_version()
+            .map(|version_meta| Version::new(&service_id, version_meta)),
+        version: service_meta
+            .versions
+            .last()
+            .map(|version_meta| Version::new(&service_id, version_meta)),
+        versions: service_meta
+            .versions
+            .iter()
+            .map(|version_meta| Version::new(&service_id, version_meta))
+            .collect(),
+        created_at: service_meta.created_at,
+        updated_at: service_meta.updated_at,
+        deleted_at: None,
+    }))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct UpdateServiceRequest {
+    name: Option<String>,
+    comment: Option<String>,
+}
+
+async fn update_service(
+    Path(service_id): Path<String>,
+    State(ctx): State<Context>,
+    Form(payload): Form<UpdateServiceRequest>,
+) -> Result<Json<Service>> {
+    let tx = ctx.db.begin_write()?;
+
+    let service = {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let mut metadata = load_metadata(&metadata_table)?;
+
+        let service_meta = find_service_mut(&mut metadata, &service_id)?;
+
+        if let Some(name) = payload.name {
+            service_meta.name = name;
+        }
+        if let Some(comment) = payload.comment {
+            service_meta.comment = comment;
+        }
+        service_meta.updated_at = Utc::now();
+
+        let service = Service::new(&service_id, service_meta);
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+
+        service
+    };
+
+    tx.commit()?;
+
+    Ok(Json(service))
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct StatusResponse {
+    status: &'static str,
+}
+
+async fn delete_service(
+    Path(service_id): Path<String>,
+    State(ctx): State<Context>,
+) -> Result<Json<StatusResponse>> {
+    let tx = ctx.db.begin_write()?;
+
+    {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let mut metadata = load_metadata(&metadata_table)?;
+
+        let service_meta = find_service(&metadata, &service_id)?;
+        if service_meta.active_version().is_some() {
+            return Err(Error::builder()
+                .bad_request()
+                .message("Service must be deactivated before being deleted")
+                .build());
+        }
+
+        metadata.services.remove(&service_id);
+        metadata
+            .resource_links
+            .retain(|_, link_meta| link_meta.service_id != service_id);
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+
+        let mut packages_table = tx.open_table(PACKAGES_TABLE)?;
+        let keys = packages_table
+            .range((service_id.clone(), 0)..=(service_id.clone(), u32::MAX))?
+            .filter_map(|entry| entry.ok())
+            .map(|(key, _)| key.value())
+            .collect::<Vec<_>>();
+        for key in keys {
+            packages_table.remove(&key)?;
+        }
+    }
+
+    tx.commit()?;
+
+    Ok(Json(StatusResponse { status: "ok" }))
+}
@@ -0,0 +1,176 @@
+use axum::extract::{DefaultBodyLimit, Json, Multipart, Path, State};
+use chrono::{DateTime, Utc};
+use redb::ReadableDatabase;
+use serde::Serialize;
+
+use super::{find_editable_version, find_version, load_metadata, read_metadata};
+use crate::api::{Context, Result, Router, error::Error};
+use crate::package::{MAX_PACKAGE_SIZE, Package};
+use crate::tables::{METADATA_TABLE, PACKAGES_TABLE, PackageMetadata};
+use crate::util::JsonRecord;
+
+pub fn router() -> Router {
+    use axum::routing;
+
+    Router::new().route(
+        "/{service_id}/version/{version}/package",
+        routing::get(get_package)
+            .put(upload_package)
+            .layer(DefaultBodyLimit::max(MAX_PACKAGE_SIZE)),
+    )
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct PackageResponse {
+    id: Option<String>,
+    service_id: String,
+    version: u32,
+    metadata: PackageResponseMetadata,
+    created_at: Option<DateTime<Utc>>,
+    updated_at: Option<DateTime<Utc>>,
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+struct PackageResponseMetadata {
+    name: String,
+    description: String,
+    authors: Vec<String>,
+    language: String,
+    size: u64,
+    hashsum: String,
+    files_hash: String,
+}
+
+impl PackageResponse {
+    fn new(service_id: &str, version: u32, package_meta: Option<&PackageMetadata>) -> Self {
+        let Some(package_meta) = package_meta else {
+            // Fastly describes versions without a package with empty metadata.
+            return PackageResponse {
+                id: None,
+                service_id: service_id.to_string(),
+                version,
+                metadata: PackageResponseMetadata::default(),
+                created_at: None,
+                updated_at: None,
+                deleted_at: None,
+            };
+        };
+
+        PackageResponse {
+            id: Some(package_meta.id.clone()),
+            service_id: service_id.to_string(),
+            version,
+            metadata: PackageResponseMetadata {
+                name: package_meta.name.clone(),
+                description: package_meta.description.clone(),
+                authors: package_meta.authors.clone(),
+                language: package_meta.language.clone(),
+                size: package_meta.size,
+                hashsum: package_meta.hashsum.clone(),
+                files_hash: package_meta.files_hash.clone(),
+            },
+            created_at: Some(package_meta.created_at),
+            updated_at: Some(package_meta.updated_at),
+            deleted_at: None,
+        }
+    }
+}
+
+async fn get_package(
+    Path((service_id, version)): Path<(String, u32)>,
+    State(ctx): State<Context>,
+) -> Result<Json<PackageResponse>> {
+    let tx = ctx.db.begin_read()?;
+
+    let metadata = &read_metadata(&tx)?;
+
+    let version_meta = find_version(metadata, &service_id, version)?;
+
+    Ok(Json(PackageResponse::new(
+        &service_id,
+        version,
+        version_meta.package.as_ref(),
+    )))
+}
+
+async fn upload_package(
+    Path((service_id, version)): Path<(String, u32)>,
+    State(ctx): State<Context>,
+    mut multipart: Multipart,
+) -> Result<Json<PackageResponse>> {
+    use sha2::{Digest, Sha512};
+
+    let mut archive = None;
+    while let Some(field) = multipart.next_field().await.map_err(|err| {
+        Error::builder()
+            .bad_request()
+            .message(err.body_text())
+            .build()
+    })? {
+        if field.name() == Some("package") {
+            let bytes = field.bytes().await.map_err(|err| {
+                Error::builder()
+                    .bad_request()
+                    .message(err.body_text())
+                    .build()
+            })?;
+            archive = Some(bytes);
+        }
+    }
+    let Some(archive) = archive else {
+        return Err(Error::builder()
+            .bad_request()
+            .message("Missing `package` field")
+            .build());
+    };
+
+    let package = Package::from_archive(&archive).map_err(|err| {
+        Error::builder()
+            .bad_request()
+            .message(err.to_string())
+            .build()
+    })?;
+
+    let tx = ctx.db.begin_write()?;
+
+    let package = {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let mut metadata = load_metadata(&metadata_table)?;
+
+        let version_meta = find_editable_version(&mut metadata, &service_id, version)?;
+
+        let now = Utc::now();
+
+        let package_meta = PackageMetadata {
+            id: ulid::Ulid::new().to_string(),
+            name: package.manifest.name,
+            description: package.manifest.description,
+            authors: package.manifest.authors,
+            language: package.manifest.language,
+            size: archive.len() as u64,
+            hashsum: format!("{:x}", Sha512::digest(&archive)),
+            files_hash: package.files_hash,
+            created_at: version_meta
+                .package
+                .as_ref()
+                .map(|package_meta| package_meta.created_at)
+                .unwrap_or(now),
+            updated_at: now,
+        };
+        let response = PackageResponse::new(&service_id, version, Some(&package_meta));
+        version_meta.package = Some(package_meta);
+        version_meta.updated_at = now;
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+
+        let mut packages_table = tx.open_table(PACKAGES_TABLE)?;
+        packages_table.insert((service_id.clone(), version), archive.as_ref())?;
+
+        response
+    };
+
+    tx.commit()?;
+
+    Ok(Json(package))
+}
@@ -0,0 +1,403 @@
+use axum::extract::{Form, Json, Path, State};
+use chrono::{DateTime, Utc};
+use redb::ReadableDatabase;
+use serde::{Deserialize, Serialize};
+
+use super::{find_editable_version, find_version, load_metadata, read_metadata};
+use crate::api::{Context, Result, Router, error::Error};
+use crate::tables::{METADATA_TABLE, Metadata, ResourceLinkMetadata};
+use crate::util::JsonRecord;
+
+pub fn router() -> Router {
+    use axum::routing;
+
+    Router::new()
+        .route(
+            "/{service_id}/version/{version}/resource",
+            routing::get(list_resource_links).post(create_resource_link),
+        )
+        .route(
+            "/{service_id}/version/{version}/resource/{id}",
+            routing::get(get_resource_link)
+                .put(update_resource_link)
+                .delete(delete_resource_link),
+        )
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ResourceLink {
+    id: String,
+    name: String,
+    resource_id: String,
+    resource_type: Option<&'static str>,
+    service_id: String,
+    version: u32,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+impl ResourceLink {
+    fn new(id: &str, link_meta: &ResourceLinkMetadata, metadata: &Metadata) -> Self {
+        ResourceLink {
+            id: id.to_string(),
+            name: link_meta.name.clone(),
+            resource_id: link_meta.resource_id.clone(),
+            resource_type: resource_type(metadata, &link_meta.resource_id),
+            service_id: link_meta.service_id.clone(),
+            version: link_meta.version,
+            created_at: link_meta.created_at,
+            updated_at: link_meta.updated_at,
+            deleted_at: None,
+        }
+    }
+}
+
+/// Resolve the Fastly resource type of a store ID, if such a store exists.
+fn resource_type(metadata: &Metadata, resource_id: &str) -> Option<&'static str> {
+    if metadata.config_stores.contains_key(resource_id) {
+        Some("config")
+    } else if metadata.kv_stores.contains_key(resource_id) {
+        Some("kv-store")
+    } else if metadata.secret_stores.contains_key(resource_id) {
+        Some("secret-store")
+    } else {
+        None
+    }
+}
+
+async fn list_resource_links(
+    Path((service_id, version)): Path<(String, u32)>,
+    State(ctx): State<Context>,
+) -> Result<Json<Vec<ResourceLink>>> {
+    let tx = ctx.db.begin_read()?;
+
+    let metadata = &read_metadata(&tx)?;
+
+    find_version(metadata, &service_id, version)?;
+
+    let entries = metadata
+        .resource_links
+        .iter()
+        .filter(|(_, link_meta)| link_meta.service_id == service_id && link_meta.version == version)
+        .map(|(id, link_meta)| ResourceLink::new(id, link_meta, metadata))
+        .collect::<Vec<ResourceLink>>();
+
+    Ok(Json(entries))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct CreateResourceLinkRequest {
+    resource_id: String,
+    name: Option<String>,
+}
+
+async fn create_resource_link(
+    Path((service_id, version)): Path<(String, u32)>,
+    State(ctx): State<Context>,
+    Form(payload): Form<CreateResourceLinkRequest>,
+) -> Result<Json<ResourceLink>> {
+    let tx = ctx.db.begin_write()?;
+
+    let link = {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let mut metadata = load_metadata(&metadata_table)?;
+
+        find_editable_version(&mut metadata, &service_id, version)?;
+
+        // Links default to the name of the linked store, like on Fastly. Soft deleted stores
+        // can't be linked.
+        let store_name = metadata
+            .config_stores
+            .get(&payload.resource_id)
+            .filter(|meta| meta.deleted_at.is_none())
+            .map(|meta| &meta.name)
+            .or_else(|| {
+                metadata
+                    .kv_stores
+                    .get(&payload.resource_id)
+                    .filter(|meta| meta.deleted_at.is_none())
+                    .map(|meta| &meta.name)
+            })
+            .or_else(|| {
+                metadata
+                    .secret_stores
+                    .get(&payload.resource_id)
+                    .filter(|meta| meta.deleted_at.is_none())
+                    .map(|meta| &meta.name)
+            });
+        let Some(store_name) = store_name else {
+            return Err(Error::builder()
+                .not_found()
+                .message("Resource not found")
+                .build());
+        };
+        let name = payload.name.unwrap_or_else(|| store_name.clone());
+
+        let name_taken = metadata.resource_links.values().any(|link_meta| {
+            link_meta.service_id == service_id
+                && link_meta.version == version
+                && link_meta.name == name
+        });
+        if name_taken {
+            return Err(Error::builder()
+                .conflict()
+                .message("A resource link with this name already exists")
+                .build());
+        }
+
+        let now = Utc::now();
+        let id = ulid::Ulid::new().to_string();
+
+        let link_meta = ResourceLinkMetadata {
+            service_id,
+            version,
+            name,
+            resource_id: payload.resource_id,
+            created_at: now,
+            updated_at: now,
+        };
+        let link = ResourceLink::new(&id, &link_meta, &metadata);
+        metadata.resource_links.insert(id, link_meta);
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+
+        link
+    };
+
+    tx.commit()?;
+
+    Ok(Json(link))
+}
+
+async fn get_resource_link(
+    Path((service_id, version, id)): Path<(String, u32, String)>,
+    State(ctx): State<Context>,
+) -> Result<Json<ResourceLink>> {
+    let tx = ctx.db.begin_read()?;
+
+    let metadata = &read_metadata(&tx)?;
+
+    let link_meta = match metadata.resource_links.get(&id) {
+        Some(meta) if meta.service_id == service_id && meta.version == version => meta,
+        _ => {
+            return Err(Error::builder()
+                .not_found()
+                .message("Resource link not found")
+                .build());
+        }
+    };
+
+    Ok(Json(ResourceLink::new(&id, link_meta, metadata)))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct UpdateResourceLinkRequest {
+    name: String,
+}
+
+async fn update_resource_link(
+    Path((service_id, version, id)): Path<(String, u32, String)>,
+    State(ctx): State<Context>,
+    Form(payload): Form<UpdateResourceLinkRequest>,
+) -> Result<Json<ResourceLink>> {
+    let tx = ctx.db.begin_write()?;
+
+    let link = {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let mut metadata = load_metadata(&metadata_table)?;
+
+        find_editable_version(&mut metadata, &service_id, version)?;
+
+        let name_taken = metadata.resource_links.iter().any(|(link_id, link_meta)| {
+            *link_id != id
+                && link_meta.service_id == service_id
+                && link_meta.version == version
+                && link_meta.name == payload.name
+        });
+        if name_taken {
+            return Err(Error::builder()
+                .conflict()
+                .message("A resource link with this name already exists")
+                .build());
+        }
+
+        let link_meta = match metadata.resource_links.get_mut(&id) {
+            Some(meta) if meta.service_id == service_id && meta.version == version => meta,
+            _ => {
+                return Err(Error::builder()
+                    .not_found()
+                    .message("Resource link not found")
+                    .build());
+            }
+        };
+        link_meta.name = payload.name;
+        link_meta.updated_at = Utc::now();
+
+        let link_meta = link_meta.clone();
+        let link = ResourceLink::new(&id, &link_meta, &metadata);
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+
+        link
+    };
+
+    tx.commit()?;
+
+    Ok(Json(link))
+}
+
+async fn delete_resource_link(
+    Path((service_id, version, id)): Path<(String, u32, String)>,
+    State(ctx): State<Context>,
+) -> Result<Json<()>> {
+    let tx = ctx.db.begin_write()?;
+
+    {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let mut metadata = load_metadata(&metadata_table)?;
+
+        find_editable_version(&mut metadata, &service_id, version)?;
+
+        match metadata.resource_links.get(&id) {
+            Some(meta) if meta.service_id == service_id && meta.version == version => {
+                metadata.resource_links.remove(&id);
+            }
+            _ => {
+                return Err(Error::builder()
+                    .not_found()
+                    .message("Resource link not found")
+                    .build());
+            }
+        }
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+    }
+
+    tx.commit()?;
+
+    Ok(Json(()))
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Method;
+
+    use crate::api::testing::{self, send};
+
+    const FORM: [(&str, &str); 1] = [("content-type", "application/x-www-form-urlencoded")];
+
+    #[tokio::test]
+    async fn links_are_created_listed_and_deleted() {
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "config", "settings").await;
+        let service_id = testing::create_service(&app, "app").await;
+        let uri = format!("/service/{service_id}/version/1/resource");
+
+        let body = format!("resource_id={store_id}");
+        let response = send(&app, Method::POST, &uri, &FORM, body).await;
+        assert_eq!(response.status, 200, "{:?}", response.body);
+        let link = response.json();
+        assert_eq!(link["name"], "settings");
+        assert_eq!(link["resource_id"], store_id.as_str());
+        assert_eq!(link["resource_type"], "config");
+        let link_uri = format!("{uri}/{}", link["id"].as_str().unwrap());
+
+        let links = send(&app, Method::GET, &uri, &[], "").await.json();
+        assert_eq!(links, serde_json::json!([link]));
+        let response = send(&app, Method::GET, &link_uri, &[], "").await;
+        assert_eq!(response.json(), link);
+
+        let response = send(&app, Method::DELETE, &link_uri, &[], "").await;
+        assert_eq!(response.status, 200);
+        let links = send(&app, Method::GET, &uri, &[], "").await.json();
+        assert_eq!(links, serde_json::json!([]));
+        let response = send(&app, Method::GET, &link_uri, &[], "").await;
+        assert_eq!(response.status, 404);
+        let response = send(&app, Method::DELETE, &link_uri, &[], "").await;
+        assert_eq!(response.status, 404);
+    }
+
+    #[tokio::test]
+    async fn links_can_alias_their_store() {
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "kv", "cache").await;
+        let service_id = testing::create_service(&app, "app").await;
+        let uri = format!("/service/{service_id}/version/1/resource");
+
+        let body = format!("resource_id={store_id}&name=sessions");
+        let response = send(&app, Method::POST, &uri, &FORM, body).await;
+        assert_eq!(response.status, 200, "{:?}", response.body);
+        assert_eq!(response.json()["name"], "sessions");
+        assert_eq!(response.json()["resource_type"], "kv-store");
+
+        // The same store can be linked again under another name
+        let body = format!("resource_id={store_id}");
+        let response = send(&app, Method::POST, &uri, &FORM, body).await;
+        assert_eq!(response.status, 200, "{:?}", response.body);
+        let link_uri = format!("{uri}/{}", response.json()["id"].as_str().unwrap());
+
+        let response = send(&app, Method::PUT, &link_uri, &FORM, "name=users").await;
+        assert_eq!(response.status, 200, "{:?}", response.body);
+        assert_eq!(response.json()["name"], "users");
+
+        let links = send(&app, Method::GET, &uri, &[], "").await.json();
+        let mut names: Vec<_> = links
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|link| link["name"].as_str().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["sessions", "users"]);
+    }
+
+    #[tokio::test]
+    async fn link_names_are_unique_per_version() {
+        let app = testing::app(testing::context());
+        let first = testing::create_store(&app, "kv", "first").await;
+        let second = testing::create_store(&app, "secret", "second").await;
+        let service_id = testing::create_service(&app, "app").await;
+        let uri = format!("/service/{service_id}/version/1/resource");
+
+        let body = format!("resource_id={first}&name=store");
+        let response = send(&app, Method::POST, &uri, &FORM, body).await;
+        assert_eq!(response.status, 200, "{:?}", response.body);
+
+        let body = format!("resource_id={second}&name=store");
+        let response = send(&app, Method::POST, &uri, &FORM, body).await;
+        assert_eq!(response.status, 409);
+
+        let body = format!("resource_id={second}");
+        let response = send(&app, Method::POST, &uri, &FORM, body).await;
+        assert_eq!(response.status, 200, "{:?}", response.body);
+        let link_uri = format!("{uri}/{}", response.json()["id"].as_str().unwrap());
+        let response = send(&app, Method::PUT, &link_uri, &FORM, "name=store").await;
+        assert_eq!(response.status, 409);
+
+        // Another service can use the same name
+        let other_id = testing::create_service(&app, "other").await;
+        let uri = format!("/service/{other_id}/version/1/resource");
+        let body = format!("resource_id={second}&name=store");
+        let response = send(&app, Method::POST, &uri, &FORM, body).await;
+        assert_eq!(response.status, 200, "{:?}", response.body);
+    }
+
+    #[tokio::test]
+    async fn links_need_an_existing_store_and_version() {
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "kv", "store").await;
+        let service_id = testing::create_service(&app, "app").await;
+
+        let uri = format!("/service/{service_id}/version/1/resource");
+        let response = send(&app, Method::POST, &uri, &FORM, "resource_id=missing").await;
+        assert_eq!(response.status, 404);
+
+        let uri = format!("/service/{service_id}/version/2/resource");
+        let body = format!("resource_id={store_id}");
+        let response = send(&app, Method::POST, &uri, &FORM, body).await;
+        assert_eq!(response.status, 404);
+        let response = send(&app, Method::GET, &uri, &[], "").await;
+        assert_eq!(response.status, 404);
+    }
+}
@@ -0,0 +1,478 @@
+use axum::extract::{Form, Json, Path, State};
+use chrono::{DateTime, Utc};
+use redb::{ReadableDatabase, ReadableTable};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    find_editable_version, find_service, find_service_mut, find_version, find_version_mut,
+    load_metadata, read_metadata,
+};
+use crate::api::{Context, Result, Router, error::Error};
+use crate::tables::{METADATA_TABLE, PACKAGES_TABLE, ResourceLinkMetadata, ServiceVersionMetadata};
+use crate::util::JsonRecord;
+
+pub fn router() -> Router {
+    use axum::routing;
+
+    Router::new()
+        .route(
+            "/{service_id}/version",
+            routing::get(list_versions).post(create_version),
+        )
+        .route(
+            "/{service_id}/version/{version}",
+            routing::get(get_version).put(update_version),
+        )
+        .route(
+            "/{service_id}/version/{version}/clone",
+            routing::put(clone_version),
+        )
+        .route(
+            "/{service_id}/version/{version}/activate",
+            routing::put(activate_version),
+        )
+        .route(
+            "/{service_id}/version/{version}/deactivate",
+            routing::put(deactivate_version),
+        )
+        .route(
+            "/{service_id}/version/{version}/lock",
+            routing::put(lock_version),
+        )
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Version {
+    number: u32,
+    service_id: String,
+    active: bool,
+    locked: bool,
+    deployed: bool,
+    staging: bool,
+    testing: bool,
+    comment: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+impl Version {
+    pub fn new(service_id: &str, version_meta: &ServiceVersionMetadata) -> Self {
+        Version {
+            number: version_meta.number,
+            service_id: service_id.to_string(),
+            active: version_meta.active,
+            locked: version_meta.locked,
+            deployed: version_meta.active,
+            staging: false,
+            testing: false,
+            comment: version_meta.comment.clone(),
+            created_at: version_meta.created_at,
+            updated_at: version_meta.updated_at,
+            deleted_at: None,
+        }
+    }
+}
+
+async fn list_versions(
+    Path(service_id): Path<String>,
+    State(ctx): State<Context>,
+) -> Result<Json<Vec<Version>>> {
+    let tx = ctx.db.begin_read()?;
+
+    let metadata = &read_metadata(&tx)?;
+
+    let service_meta = find_service(metadata, &service_id)?;
+
+    let entries = service_meta
+        .versions
+        .iter()
+        .map(|version_meta| Version::new(&service_id, version_meta))
+        .collect::<Vec<Version>>();
+
+    Ok(Json(entries))
+}
+
+async fn create_version(
+    Path(service_id): Path<String>,
+    State(ctx): State<Context>,
+) -> Result<Json<Version>> {
+    let tx = ctx.db.begin_write()?;
+
+    let version = {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let mut metadata = load_metadata(&metadata_table)?;
+
+        let service_meta = find_service_mut(&mut metadata, &service_id)?;
+
+        let now = Utc::now();
+
+        let version_meta = ServiceVersionMetadata {
+            number: service_meta.versions.len() as u32 + 1,
+            active: false,
+            locked: false,
+            comment: String::new(),
+            domains: Vec::new(),
+            package: None,
+            created_at: now,
+            updated_at: now,
+        };
+        let version = Version::new(&service_id, &version_meta);
+        service_meta.versions.push(version_meta);
+        service_meta.updated_at = now;
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+
+        version
+    };
+
+    tx.commit()?;
+
+    Ok(Json(version))
+}
+
+async fn get_version(
+    Path((service_id, version)): Path<(String, u32)>,
+    State(ctx): State<Context>,
+) -> Result<Json<Version>> {
+    let tx = ctx.db.begin_read()?;
+
+    let metadata = &read_metadata(&tx)?;
+
+    let version_meta = find_version(metadata, &service_id, version)?;
+
+    Ok(Json(Version::new(&service_id, version_meta)))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct UpdateVersionRequest {
+    comment: String,
+}
+
+async fn update_version(
+    Path((service_id, version)): Path<(String, u32)>,
+    State(ctx): State<Context>,
+    Form(payload): Form<UpdateVersionRequest>,
+) -> Result<Json<Version>> {
+    let tx = ctx.db.begin_write()?;
+
+    let version = {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let mut metadata = load_metadata(&metadata_table)?;
+
+        let version_meta = find_editable_version(&mut metadata, &service_id, version)?;
+        version_meta.comment = payload.comment;
+        version_meta.updated_at = Utc::now();
+
+        let version = Version::new(&service_id, version_meta);
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+
+        version
+    };
+
+    tx.commit()?;
+
+    Ok(Json(version))
+}
+
+/// Copy a version, including its package, domains and resource links, into a new editable
+/// version.
+async fn clone_version(
+    Path((service_id, version)): Path<(String, u32)>,
+    State(ctx): State<Context>,
+) -> Result<Json<Version>> {
+    let tx = ctx.db.begin_write()?;
+
+    let version = {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let mut metadata = load_metadata(&metadata_table)?;
+
+        let source_meta = find_version(&metadata, &service_id, version)?.clone();
+
+        let service_meta = find_service_mut(&mut metadata, &service_id)?;
+
+        let now = Utc::now();
+        let number = service_meta.versions.len() as u32 + 1;
+
+        let version_meta = ServiceVersionMetadata {
+            number,
+            active: false,
+            locked: false,
+            created_at: now,
+            updated_at: now,
+            ..source_meta
+        };
+        let cloned = Version::new(&service_id, &version_meta);
+        service_meta.versions.push(version_meta);
+        service_meta.updated_at = now;
+
+        let links = metadata
+            .resource_links
+            .values()
+            .filter(|link_meta| link_meta.service_id == service_id && link_meta.version == version)
+            .cloned()
+            .collect::<Vec<_>>();
+        for link_meta in links {
+            let id = ulid::Ulid::new().to_string();
+            metadata.resource_links.insert(
+                id,
+                ResourceLinkMetadata {
+                    version: number,
+                    created_at: now,
+                    updated_at: now,
+                    ..link_meta
+                },
+            );
+        }
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+
+        let mut packages_table = tx.open_table(PACKAGES_TABLE)?;
+        let package = packages_table
+            .get((service_id.clone(), version))?
+            .map(|record| record.value().to_vec());
+        if let Some(package) = package {
+            packages_table.insert((service_id.clone(), number), package.as_slice())?;
+        }
+
+        cloned
+    };
+
+    tx.commit()?;
+
+    Ok(Json(version))
+}
+
+async fn activate_version(
+    Path((service_id, version)): Path<(String, u32)>,
+    State(ctx): State<Context>,
+) -> Result<Json<Version>> {
+    let tx = ctx.db.begin_write()?;
+
+    let version = {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let mut metadata = load_metadata(&metadata_table)?;
+
+        if find_version(&metadata, &service_id, version)?
+            .package
+            .is_none()
+        {
+            return Err(Error::builder()
+                .bad_request()
+                .message(format!("Version {version} has no package"))
+                .build());
+        }
+
+        let service_meta = find_service_mut(&mut metadata, &service_id)?;
+
+        let now = Utc::now();
+
+        for version_meta in &mut service_meta.versions {
+            if version_meta.number == version {
+                version_meta.active = true;
+                version_meta.locked = true;
+                version_meta.updated_at = now;
+            } else if version_meta.active {
+                version_meta.active = false;
+                version_meta.updated_at = now;
+            }
+        }
+        service_meta.updated_at = now;
+
+        let version = Version::new(&service_id, find_version(&metadata, &service_id, version)?);
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+
+        version
+    };
+
+    tx.commit()?;
+
+    Ok(Json(version))
+}
+
+async fn deactivate_version(
+    Path((service_id, version)): Path<(String, u32)>,
+    State(ctx): State<Context>,
+) -> Result<Json<Version>> {
+    let tx = ctx.db.begin_write()?;
+
+    let version = {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let mut metadata = load_metadata(&metadata_table)?;
+
+        let version_meta = find_version_mut(&mut metadata, &service_id, version)?;
+
+        if !version_meta.active {
+            return Err(Error::builder()
+                .bad_request()
+                .message(format!("Version {version} is not active"))
+                .build());
+        }
+        version_meta.active = false;
+        version_meta.updated_at = Utc::now();
+
+        let version = Version::new(&service_id, version_meta);
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+
+        version
+    };
+
+    tx.commit()?;
+
+    Ok(Json(version))
+}
+
+async fn lock_version(
+    Path((service_id, version)): Path<(String, u32)>,
+    State(ctx): State<Context>,
+) -> Result<Json<Version>> {
+    let tx = ctx.db.begin_write()?;
+
+    let version = {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let mut metadata = load_metadata(&metadata_table)?;
+
+        let version_meta = find_version_mut(&mut metadata, &service_id, version)?;
+
+        version_meta.locked = true;
+        version_meta.updated_at = Utc::now();
+
+        let version = Version::new(&service_id, version_meta);
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+
+        version
+    };
+
+    tx.commit()?;
+
+    Ok(Json(version))
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Method;
+
+    use crate::api::testing::{self, send};
+    use crate::package::archive;
+
+    const FORM: [(&str, &str); 1] = [("content-type", "application/x-www-form-urlencoded")];
+
+    fn package() -> Vec<u8> {
+        archive(&[
+            ("app/fastly.toml", b"manifest_version = 3\nname = \"app\"\n"),
+            ("app/bin/main.wasm", b"\0asm\x01\0\0\0"),
+        ])
+    }
+
+    #[tokio::test]
+    async fn cloning_copies_the_version_into_an_editable_one() {
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "kv", "store").await;
+        let service_id = testing::create_service(&app, "app").await;
+        let uri = format!("/service/{service_id}/version/1");
+
+        let response = testing::upload_package(&app, &service_id, 1, &package()).await;
+        assert_eq!(response.status, 200, "{:?}", response.body);
+        let body = "name=example.com";
+        let response = send(&app, Method::POST, &format!("{uri}/domain"), &FORM, body).await;
+        assert_eq!(response.status, 200, "{:?}", response.body);
+        let body = format!("resource_id={store_id}&name=alias");
+        let response = send(&app, Method::POST, &format!("{uri}/resource"), &FORM, body).await;
+        assert_eq!(response.status, 200, "{:?}", response.body);
+        let response = send(&app, Method::PUT, &format!("{uri}/lock"), &[], "").await;
+        assert_eq!(response.status, 200);
+
+        let response = send(&app, Method::PUT, &format!("{uri}/clone"), &[], "").await;
+        assert_eq!(response.status, 200, "{:?}", response.body);
+        let version = response.json();
+        assert_eq!(version["number"], 2);
+        assert_eq!(version["locked"], false);
+        assert_eq!(version["active"], false);
+
+        let cloned = format!("/service/{service_id}/version/2");
+        let package = send(&app, Method::GET, &format!("{cloned}/package"), &[], "").await;
+        assert_eq!(package.json()["metadata"]["name"], "app");
+        let domains = send(&app, Method::GET, &format!("{cloned}/domain"), &[], "").await;
+        assert_eq!(domains.json()[0]["name"], "example.com");
+        let links = send(&app, Method::GET, &format!("{cloned}/resource"), &[], "").await;
+        assert_eq!(links.json()[0]["name"], "alias");
+        assert_eq!(links.json()[0]["version"], 2);
+
+        let response = send(&app, Method::PUT, &format!("{cloned}/clone"), &[], "").await;
+        assert_eq!(response.json()["number"], 3);
+        let missing = format!("/service/{service_id}/version/9/clone");
+        assert_eq!(send(&app, Method::PUT, &missing, &[], "").await.status, 404);
+    }
+
+    #[tokio::test]
+    async fn activating_locks_the_version_and_deactivates_the_others() {
+        let app = testing::app(testing::context());
+        let service_id = testing::create_service(&app, "app").await;
+        let activate = async |version| {
+            let uri = format!("/service/{service_id}/version/{version}/activate");
+            send(&app, Method::PUT, &uri, &[], "").await
+        };
+
+        // Versions need a package to be activated
+        assert_eq!(activate(1).await.status, 400);
+        assert_eq!(activate(2).await.status, 404);
+
+        testing::upload_package(&app, &service_id, 1, &package()).await;
+        let response = activate(1).await;
+        assert_eq!(response.status, 200, "{:?}", response.body);
+        assert_eq!(response.json()["active"], true);
+        assert_eq!(response.json()["locked"], true);
+
+        let uri = format!("/service/{service_id}/version/1/clone");
+        send(&app, Method::PUT, &uri, &[], "").await;
+        let response = activate(2).await;
+        assert_eq!(response.status, 200, "{:?}", response.body);
+
+        let uri = format!("/service/{service_id}");
+        let service = send(&app, Method::GET, &uri, &[], "").await.json();
+        assert_eq!(service["version"], 2);
+        assert_eq!(service["versions"][0]["active"], false);
+        assert_eq!(service["versions"][0]["locked"], true);
+
+        let uri = format!("/service/{service_id}/version/1/deactivate");
+        assert_eq!(send(&app, Method::PUT, &uri, &[], "").await.status, 400);
+        let uri = format!("/service/{service_id}/version/2/deactivate");
+        let response = send(&app, Method::PUT, &uri, &[], "").await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.json()["active"], false);
+    }
+
+    #[tokio::test]
+    async fn locked_versions_cant_be_edited() {
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "config", "store").await;
+        let service_id = testing::create_service(&app, "app").await;
+        let uri = format!("/service/{service_id}/version/1");
+
+        let response = send(&app, Method::PUT, &format!("{uri}/lock"), &[], "").await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.json()["locked"], true);
+        let missing = format!("/service/{service_id}/version/2/lock");
+        assert_eq!(send(&app, Method::PUT, &missing, &[], "").await.status, 404);
+
+        let response = send(&app, Method::PUT, &uri, &FORM, "comment=edited").await;
+        assert_eq!(response.status, 400);
+        let response = testing::upload_package(&app, &service_id, 1, &package()).await;
+        assert_eq!(response.status, 400);
+        let body = "name=example.com";
+        let response = send(&app, Method::POST, &format!("{uri}/domain"), &FORM, body).await;
+        assert_eq!(response.status, 400);
+        let body = format!("resource_id={store_id}");
+        let response = send(&app, Method::POST, &format!("{uri}/resource"), &FORM, body).await;
+        assert_eq!(response.status, 400);
+
+        let version = send(&app, Method::GET, &uri, &[], "").await.json();
+        assert_eq!(version["comment"], "");
+        let package = send(&app, Method::GET, &format!("{uri}/package"), &[], "").await;
+        assert_eq!(package.json()["id"], serde_json::Value::Null);
+    }
+}
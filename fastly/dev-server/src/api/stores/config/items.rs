@@ -1,27 +1,27 @@
 use axum::extract::{Form, Json, Path, State};
 use chrono::{DateTime, Utc};
-use redb::{ReadableDatabase, ReadableTable};
+use redb::{ReadableDatabase, ReadableTable, ReadableTableMetadata};
 use serde::{Deserialize, Serialize};
 
-use crate::api::{Context, Result, Router, error::Error};
-use crate::tables::{ConfigStoreItemMetadata, ConfigStoreTable as TableDefinition};
+use crate::api::openapi::documented_routes;
+use crate::api::stores::limits::{self, CONFIG_MAX_ITEMS};
+use crate::api::stores::{
+    StoreKind, check_limit, enforce_limit, resolve_store_id, resolve_store_id_for_write,
+};
+use crate::api::{Context, Result, error::Error};
+use crate::tables::{ConfigStoreItemMetadata, ConfigStoreTable as TableDefinition, StatsDelta};
 use crate::util::JsonRecord;
 
-pub fn router() -> Router {
-    use axum::routing;
-
-    Router::new()
-        .route("/{store_id}/items", routing::get(list_config_store_items))
-        .route("/{store_id}/item", routing::post(create_config_store_item))
-        .route(
-            "/{store_id}/item/{item_key}",
-            routing::get(get_config_store_item)
-                .put(update_config_store_item)
-                .delete(delete_config_store_item),
-        )
+documented_routes! {
+    list_config_store_items,
+    batch_update_config_store_items,
+    create_config_store_item,
+    get_config_store_item,
+    update_config_store_item,
+    delete_config_store_item,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, utoipa::ToSchema)]
 struct ConfigStoreItem {
     store_id: String,
     item_key: String,
@@ -31,10 +31,21 @@ struct ConfigStoreItem {
     deleted_at: Option<DateTime<Utc>>,
 }
 
+#[utoipa::path(
+    get,
+    path = "/{store_id}/items",
+    tag = "Config stores",
+    params(("store_id" = String, Path, description = "Store ID or name")),
+    responses(
+        (status = 200, body = Vec<ConfigStoreItem>),
+    )
+)]
 async fn list_config_store_items(
     Path(store_id): Path<String>,
     State(ctx): State<Context>,
 ) -> Result<Json<Vec<ConfigStoreItem>>> {
+    let store_id = resolve_store_id(&ctx, StoreKind::Config, store_id)?;
+
     let tx = ctx.db.begin_read()?;
 
     let definition = TableDefinition::new(&store_id);
@@ -68,24 +79,51 @@ async fn list_config_store_items(
     Ok(Json(entries))
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, utoipa::ToSchema)]
 struct CreateConfigStoreItem {
     item_key: String,
     item_value: String,
 }
 
+#[utoipa::path(
+    post,
+    path = "/{store_id}/item",
+    tag = "Config stores",
+    params(("store_id" = String, Path, description = "Store ID or name")),
+    request_body(
+        content = CreateConfigStoreItem,
+        content_type = "application/x-www-form-urlencoded",
+    ),
+    responses(
+        (status = 200, body = ConfigStoreItem),
+    )
+)]
 async fn create_config_store_item(
     Path(store_id): Path<String>,
     State(ctx): State<Context>,
     Form(payload): Form<CreateConfigStoreItem>,
 ) -> Result<Json<ConfigStoreItem>> {
+    enforce_limit(
+        &ctx,
+        limits::check_config_item(&payload.item_key, &payload.item_value),
+    )?;
+
     let tx = ctx.db.begin_write()?;
 
-    let item = {
+    let store_id = resolve_store_id_for_write(&tx, StoreKind::Config, store_id)?;
+
+    let (item, stats) = {
         let definition = TableDefinition::new(&store_id);
 
         let mut table = tx.open_table(definition)?;
 
+        if table.get(&payload.item_key)?.is_none() {
+            enforce_limit(
+                &ctx,
+                limits::check_item_count(table.len()?, CONFIG_MAX_ITEMS),
+            )?;
+        }
+
         let now = Utc::now();
 
         let meta = ConfigStoreItemMetadata {
@@ -102,30 +140,212 @@ async fn create_config_store_item(
             deleted_at: None,
         };
 
-        table.insert(&payload.item_key, &JsonRecord(meta))?;
+        let size = meta.size(&payload.item_key);
+        let previous = table
+            .insert(&payload.item_key, &JsonRecord(meta))?
+            .map(|record| record.value().0.size(&payload.item_key));
 
-        item
+        let mut stats = StatsDelta::default();
+        stats.write(previous, size);
+
+        (item, stats)
     };
 
+    stats.commit(&tx, &store_id)?;
+
     tx.commit()?;
 
     Ok(Json(item))
 }
 
+#[derive(Debug, Clone, Deserialize, utoipa::ToSchema)]
+struct BatchUpdateRequest {
+    items: Vec<BatchOperation>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum BatchOp {
+    Create,
+    Update,
+    Upsert,
+    Delete,
+}
+
+#[derive(Debug, Clone, Deserialize, utoipa::ToSchema)]
+struct BatchOperation {
+    op: BatchOp,
+    item_key: String,
+    item_value: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+struct BatchUpdateResponse {
+    status: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+struct BatchErrorResponse {
+    msg: &'static str,
+    detail: String,
+    errors: Vec<BatchOperationError>,
+}
+
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+struct BatchOperationError {
+    /// Index of the operation in `items`
+    index: usize,
+    item_key: String,
+    detail: String,
+}
+
+/// Apply a list of item operations, as sent by `fastly config-store-entry update --stdin`.
+///
+/// Operations are applied in order in a single transaction, each seeing the effect of the
+/// previous ones. When any of them fails, nothing is written and every failure is reported.
+#[utoipa::path(
+    patch,
+    path = "/{store_id}/items",
+    tag = "Config stores",
+    params(("store_id" = String, Path, description = "Store ID or name")),
+    request_body = BatchUpdateRequest,
+    responses(
+        (status = 200, body = BatchUpdateResponse),
+        (
+            status = 400,
+            description = "Some operations failed, none were applied",
+            body = BatchErrorResponse,
+        ),
+    )
+)]
+async fn batch_update_config_store_items(
+    Path(store_id): Path<String>,
+    State(ctx): State<Context>,
+    Json(payload): Json<BatchUpdateRequest>,
+) -> Result<axum::response::Response> {
+    use axum::response::IntoResponse;
+
+    let tx = ctx.db.begin_write()?;
+
+    let store_id = resolve_store_id_for_write(&tx, StoreKind::Config, store_id)?;
+
+    let mut errors = Vec::new();
+    let mut stats = StatsDelta::default();
+
+    {
+        let definition = TableDefinition::new(&store_id);
+
+        let mut table = tx.open_table(definition)?;
+
+        let now = Utc::now();
+
+        for (index, operation) in payload.items.into_iter().enumerate() {
+            let mut fail = |detail: &str| {
+                errors.push(BatchOperationError {
+                    index,
+                    item_key: operation.item_key.clone(),
+                    detail: detail.to_string(),
+                })
+            };
+
+            let existing = table
+                .get(&operation.item_key)?
+                .map(|record| record.value().0);
+
+            match (operation.op, &existing) {
+                (BatchOp::Create, Some(_)) => {
+                    fail("Item already exists");
+                    continue;
+                }
+                (BatchOp::Update | BatchOp::Delete, None) => {
+                    fail("Item not found");
+                    continue;
+                }
+                _ => {}
+            }
+
+            if operation.op == BatchOp::Delete {
+                if let Some(item) = &existing {
+                    stats.remove(item.size(&operation.item_key));
+                }
+                table.remove(&operation.item_key)?;
+                continue;
+            }
+
+            let Some(item_value) = operation.item_value else {
+                fail("Missing `item_value`");
+                continue;
+            };
+
+            let mut result = limits::check_config_item(&operation.item_key, &item_value);
+            if result.is_ok() && existing.is_none() {
+                result = limits::check_item_count(table.len()?, CONFIG_MAX_ITEMS);
+            }
+            if let Err(err) = check_limit(&ctx, result) {
+                fail(&err.to_string());
+                continue;
+            }
+
+            let meta = ConfigStoreItemMetadata {
+                item_value,
+                created_at: existing.as_ref().map_or(now, |item| item.created_at),
+                updated_at: now,
+            };
+            stats.write(
+                existing.map(|item| item.size(&operation.item_key)),
+                meta.size(&operation.item_key),
+            );
+            table.insert(&operation.item_key, &JsonRecord(meta))?;
+        }
+    }
+
+    if !errors.is_empty() {
+        tx.abort()?;
+
+        let response = BatchErrorResponse {
+            msg: "Bad request",
+            detail: format!("{} of the operations failed", errors.len()),
+            errors,
+        };
+        return Ok((http::StatusCode::BAD_REQUEST, Json(response)).into_response());
+    }
+
+    stats.commit(&tx, &store_id)?;
+
+    tx.commit()?;
+
+    Ok(Json(BatchUpdateResponse { status: "ok" }).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/{store_id}/item/{item_key}",
+    tag = "Config stores",
+    params(
+        ("store_id" = String, Path, description = "Store ID or name"),
+        ("item_key" = String, Path),
+    ),
+    responses(
+        (status = 200, body = ConfigStoreItem),
+    )
+)]
 async fn get_config_store_item(
     Path((store_id, item_key)): Path<(String, String)>,
     State(ctx): State<Context>,
 ) -> Result<Json<ConfigStoreItem>> {
+    let store_id = resolve_store_id(&ctx, StoreKind::Config, store_id)?;
+
     let tx = ctx.db.begin_read()?;
 
     let definition = TableDefinition::new(&store_id);
 
     let table = match tx.open_table(definition) {
         Ok(table) => table,
+        // The store exists but has never been written to.
         Err(redb::TableError::TableDoesNotExist(_)) => {
             return Err(Error::builder()
                 .not_found()
-                .message("Config store not found")
+                .message("Config store item not found")
                 .build());
         }
         Err(e) => return Err(e.into()),
@@ -154,23 +374,53 @@ async fn get_config_store_item(
     Ok(Json(config_store_item))
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, utoipa::ToSchema)]
 struct UpdateConfigStoreItem {
     item_value: String,
 }
 
+#[utoipa::path(
+    put,
+    path = "/{store_id}/item/{item_key}",
+    tag = "Config stores",
+    params(
+        ("store_id" = String, Path, description = "Store ID or name"),
+        ("item_key" = String, Path),
+    ),
+    request_body(
+        content = UpdateConfigStoreItem,
+        content_type = "application/x-www-form-urlencoded",
+    ),
+    responses(
+        (status = 200, body = ConfigStoreItem),
+    )
+)]
 async fn update_config_store_item(
     Path((store_id, item_key)): Path<(String, String)>,
     State(ctx): State<Context>,
     Form(payload): Form<UpdateConfigStoreItem>,
 ) -> Result<Json<ConfigStoreItem>> {
+    enforce_limit(
+        &ctx,
+        limits::check_config_item(&item_key, &payload.item_value),
+    )?;
+
     let tx = ctx.db.begin_write()?;
 
-    let item = {
+    let store_id = resolve_store_id_for_write(&tx, StoreKind::Config, store_id)?;
+
+    let (item, stats) = {
         let definition = TableDefinition::new(&store_id);
 
         let mut table = tx.open_table(definition)?;
 
+        if table.get(&item_key)?.is_none() {
+            enforce_limit(
+                &ctx,
+                limits::check_item_count(table.len()?, CONFIG_MAX_ITEMS),
+            )?;
+        }
+
         let now = Utc::now();
 
         let meta = ConfigStoreItemMetadata {
@@ -187,31 +437,168 @@ async fn update_config_store_item(
             deleted_at: None,
         };
 
-        table.insert(&item_key, &JsonRecord(meta))?;
+        let size = meta.size(&item_key);
+        let previous = table
+            .insert(&item_key, &JsonRecord(meta))?
+            .map(|record| record.value().0.size(&item_key));
+
+        let mut stats = StatsDelta::default();
+        stats.write(previous, size);
 
-        item
+        (item, stats)
     };
 
+    stats.commit(&tx, &store_id)?;
+
     tx.commit()?;
 
     Ok(Json(item))
 }
 
+#[utoipa::path(
+    delete,
+    path = "/{store_id}/item/{item_key}",
+    tag = "Config stores",
+    params(
+        ("store_id" = String, Path, description = "Store ID or name"),
+        ("item_key" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Item deleted"),
+    )
+)]
 async fn delete_config_store_item(
     Path((store_id, item_key)): Path<(String, String)>,
     State(ctx): State<Context>,
 ) -> Result<Json<()>> {
     let tx = ctx.db.begin_write()?;
 
-    {
+    let store_id = resolve_store_id_for_write(&tx, StoreKind::Config, store_id)?;
+
+    let stats = {
         let definition = TableDefinition::new(&store_id);
 
         let mut table = tx.open_table(definition)?;
 
-        table.remove(&item_key)?;
-    }
+        let mut stats = StatsDelta::default();
+        if let Some(record) = table.remove(&item_key)? {
+            stats.remove(record.value().0.size(&item_key));
+        }
+
+        stats
+    };
+
+    stats.commit(&tx, &store_id)?;
 
     tx.commit()?;
 
     Ok(Json(()))
 }
+
+#[cfg(test)]
+mod tests {
+    use http::Method;
+    use serde_json::json;
+
+    use crate::api::testing::{self, send};
+
+    async fn patch(
+        app: &axum::Router,
+        store_id: &str,
+        items: serde_json::Value,
+    ) -> testing::Response {
+        send(
+            app,
+            Method::PATCH,
+            &format!("/resources/stores/config/{store_id}/items"),
+            &[],
+            json!({ "items": items }).to_string(),
+        )
+        .await
+    }
+
+    /// Keys and values of every item, in key order.
+    async fn items(app: &axum::Router, store_id: &str) -> Vec<(String, String)> {
+        let uri = format!("/resources/stores/config/{store_id}/items");
+        let response = send(app, Method::GET, &uri, &[], "").await;
+        assert_eq!(response.status, 200);
+
+        response
+            .json()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| {
+                (
+                    item["item_key"].as_str().unwrap().to_string(),
+                    item["item_value"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn batch_operations_apply_in_order() {
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "config", "store").await;
+
+        let response = patch(
+            &app,
+            &store_id,
+            json!([
+                { "op": "create", "item_key": "a", "item_value": "1" },
+                { "op": "create", "item_key": "b", "item_value": "1" },
+                { "op": "update", "item_key": "a", "item_value": "2" },
+                { "op": "upsert", "item_key": "c", "item_value": "1" },
+                { "op": "delete", "item_key": "b" },
+            ]),
+        )
+        .await;
+        assert_eq!(response.status, 200, "{:?}", response.body);
+        assert_eq!(response.json(), json!({ "status": "ok" }));
+
+        assert_eq!(
+            items(&app, &store_id).await,
+            [("a".into(), "2".into()), ("c".into(), "1".into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn failing_batch_applies_nothing() {
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "config", "store").await;
+        let response = patch(
+            &app,
+            &store_id,
+            json!([{ "op": "create", "item_key": "a", "item_value": "1" }]),
+        )
+        .await;
+        assert_eq!(response.status, 200);
+
+        let response = patch(
+            &app,
+            &store_id,
+            json!([
+                { "op": "upsert", "item_key": "b", "item_value": "1" },
+                { "op": "create", "item_key": "a", "item_value": "2" },
+                { "op": "update", "item_key": "missing", "item_value": "1" },
+                { "op": "delete", "item_key": "missing" },
+                { "op": "upsert", "item_key": "c" },
+            ]),
+        )
+        .await;
+        assert_eq!(response.status, 400);
+
+        let body = response.json();
+        assert_eq!(body["msg"], "Bad request");
+        let failed: Vec<_> = body["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|error| error["index"].as_u64().unwrap())
+            .collect();
+        assert_eq!(failed, [1, 2, 3, 4]);
+
+        assert_eq!(items(&app, &store_id).await, [("a".into(), "1".into())]);
+    }
+}
@@ -1,15 +1,20 @@
-use axum::extract::{Form, Json, Path, State};
+use axum::extract::{Form, Json, Path, Query, State};
 use chrono::{DateTime, Utc};
 use redb::{ReadableDatabase, ReadableTable};
 use serde::{Deserialize, Serialize};
 
-use crate::api::{Context, Result, Router, error::Error};
-use crate::tables::{ConfigStoreMetadata, ConfigStoreTable, METADATA_TABLE};
+use super::{
+    ListStoresQuery, StoreInfo, StoreKind, ensure_unique_name, find_deleted_store_id,
+    find_store_id, store_info,
+};
+use crate::api::openapi::documented_routes;
+use crate::api::{Context, Result, error::Error};
+use crate::tables::{ConfigStoreMetadata, METADATA_TABLE, StoreStats};
 use crate::util::JsonRecord;
 
 mod items;
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
 struct ConfigStore {
     id: String,
     name: String,
@@ -18,22 +23,30 @@ struct ConfigStore {
     deleted_at: Option<DateTime<Utc>>,
 }
 
-pub fn router() -> Router {
-    use axum::routing;
-
-    Router::new()
-        .route(
-            "/",
-            routing::get(list_config_stores).post(create_config_store),
-        )
-        .route(
-            "/{id}",
-            routing::get(get_config_store).delete(delete_config_store),
-        )
-        .merge(items::router())
+documented_routes! {
+    list_config_stores,
+    create_config_store,
+    get_config_store,
+    update_config_store,
+    delete_config_store,
+    get_config_store_info,
+    undelete_config_store;
+    merge(items)
 }
 
-async fn list_config_stores(State(ctx): State<Context>) -> Result<Json<Vec<ConfigStore>>> {
+#[utoipa::path(
+    get,
+    path = "",
+    tag = "Config stores",
+    params(ListStoresQuery),
+    responses(
+        (status = 200, body = Vec<ConfigStore>),
+    )
+)]
+async fn list_config_stores(
+    State(ctx): State<Context>,
+    Query(query): Query<ListStoresQuery>,
+) -> Result<Json<Vec<ConfigStore>>> {
     let tx = ctx.db.begin_read()?;
 
     let metadata_table = match tx.open_table(METADATA_TABLE) {
@@ -51,23 +64,36 @@ async fn list_config_stores(State(ctx): State<Context>) -> Result<Json<Vec<Confi
     let entries = metadata
         .config_stores
         .iter()
+        .filter(|(_, store_meta)| query.include_deleted || store_meta.deleted_at.is_none())
         .map(|(id, store_meta)| ConfigStore {
             id: id.clone(),
             name: store_meta.name.clone(),
             created_at: store_meta.created_at,
             updated_at: store_meta.updated_at,
-            deleted_at: None,
+            deleted_at: store_meta.deleted_at,
         })
         .collect::<Vec<ConfigStore>>();
 
     Ok(Json(entries))
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, utoipa::ToSchema)]
 pub struct CreateConfigStoreRequest {
     pub name: String,
 }
 
+#[utoipa::path(
+    post,
+    path = "",
+    tag = "Config stores",
+    request_body(
+        content = CreateConfigStoreRequest,
+        content_type = "application/x-www-form-urlencoded",
+    ),
+    responses(
+        (status = 200, body = ConfigStore),
+    )
+)]
 async fn create_config_store(
     State(ctx): State<Context>,
     Form(payload): Form<CreateConfigStoreRequest>,
@@ -81,13 +107,17 @@ async fn create_config_store(
             .map(|record| record.value().0.clone())
             .unwrap_or_default();
 
+        ensure_unique_name(&metadata, StoreKind::Config, &payload.name)?;
+
         let now = Utc::now();
         let id = ulid::Ulid::new().to_string();
 
         let store_meta = ConfigStoreMetadata {
             name: payload.name.clone(),
+            stats: Some(StoreStats::default()),
             created_at: now,
             updated_at: now,
+            deleted_at: None,
         };
         metadata.config_stores.insert(id.clone(), store_meta);
 
@@ -107,6 +137,15 @@ async fn create_config_store(
     Ok(Json(store))
 }
 
+#[utoipa::path(
+    get,
+    path = "/{id}",
+    tag = "Config stores",
+    params(("id" = String, Path, description = "Store ID or name")),
+    responses(
+        (status = 200, body = ConfigStore),
+    )
+)]
 async fn get_config_store(
     State(ctx): State<Context>,
     axum::extract::Path(id): axum::extract::Path<String>,
@@ -131,7 +170,13 @@ async fn get_config_store(
     };
     let metadata = &metadata_record.value().0;
 
-    let store_meta = match metadata.config_stores.get(&id) {
+    let id = find_store_id(metadata, StoreKind::Config, &id).unwrap_or(id);
+
+    let store_meta = match metadata
+        .config_stores
+        .get(&id)
+        .filter(|meta| meta.deleted_at.is_none())
+    {
         Some(meta) => meta,
         None => {
             return Err(Error::builder()
@@ -150,6 +195,152 @@ async fn get_config_store(
     }))
 }
 
+#[derive(Debug, Clone, Deserialize, utoipa::ToSchema)]
+pub struct UpdateConfigStoreRequest {
+    pub name: String,
+}
+
+#[utoipa::path(
+    put,
+    path = "/{id}",
+    tag = "Config stores",
+    params(("id" = String, Path, description = "Store ID or name")),
+    request_body(
+        content = UpdateConfigStoreRequest,
+        content_type = "application/x-www-form-urlencoded",
+    ),
+    responses(
+        (status = 200, body = ConfigStore),
+    )
+)]
+async fn update_config_store(
+    State(ctx): State<Context>,
+    Path(id): Path<String>,
+    Form(payload): Form<UpdateConfigStoreRequest>,
+) -> Result<Json<ConfigStore>> {
+    let tx = ctx.db.begin_write()?;
+
+    let store = {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let mut metadata = metadata_table
+            .get(&())?
+            .map(|record| record.value().0.clone())
+            .unwrap_or_default();
+
+        let Some(id) = find_store_id(&metadata, StoreKind::Config, &id) else {
+            return Err(StoreKind::Config.not_found());
+        };
+
+        if payload.name != metadata.config_stores[&id].name {
+            ensure_unique_name(&metadata, StoreKind::Config, &payload.name)?;
+        }
+
+        let now = Utc::now();
+
+        let store_meta = metadata
+            .config_stores
+            .get_mut(&id)
+            .expect("Store was just found");
+        store_meta.name = payload.name;
+        store_meta.updated_at = now;
+
+        let store = ConfigStore {
+            id,
+            name: store_meta.name.clone(),
+            created_at: store_meta.created_at,
+            updated_at: store_meta.updated_at,
+            deleted_at: None,
+        };
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+
+        store
+    };
+
+    tx.commit()?;
+
+    Ok(Json(store))
+}
+
+#[utoipa::path(
+    get,
+    path = "/{id}/info",
+    tag = "Config stores",
+    params(("id" = String, Path, description = "Store ID or name")),
+    responses(
+        (status = 200, body = StoreInfo),
+    )
+)]
+async fn get_config_store_info(
+    State(ctx): State<Context>,
+    Path(id): Path<String>,
+) -> Result<Json<StoreInfo>> {
+    store_info(&ctx, StoreKind::Config, id).map(Json)
+}
+
+/// Restore a soft deleted store, unless another store took its name in the meantime.
+#[utoipa::path(
+    post,
+    path = "/{id}/undelete",
+    tag = "Config stores",
+    params(("id" = String, Path, description = "Store ID or name")),
+    responses(
+        (status = 200, body = ConfigStore),
+    )
+)]
+async fn undelete_config_store(
+    State(ctx): State<Context>,
+    Path(id): Path<String>,
+) -> Result<Json<ConfigStore>> {
+    let tx = ctx.db.begin_write()?;
+
+    let store = {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let mut metadata = metadata_table
+            .get(&())?
+            .map(|record| record.value().0.clone())
+            .unwrap_or_default();
+
+        let Some(id) = find_deleted_store_id(&metadata, StoreKind::Config, &id) else {
+            return Err(StoreKind::Config.not_found());
+        };
+
+        let name = metadata.config_stores[&id].name.clone();
+        ensure_unique_name(&metadata, StoreKind::Config, &name)?;
+
+        let store_meta = metadata
+            .config_stores
+            .get_mut(&id)
+            .expect("Store was just found");
+        store_meta.deleted_at = None;
+
+        let store = ConfigStore {
+            id,
+            name: store_meta.name.clone(),
+            created_at: store_meta.created_at,
+            updated_at: store_meta.updated_at,
+            deleted_at: None,
+        };
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+
+        store
+    };
+
+    tx.commit()?;
+
+    Ok(Json(store))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/{id}",
+    tag = "Config stores",
+    params(("id" = String, Path, description = "Store ID or name")),
+    responses(
+        (status = 200, description = "Store deleted"),
+    )
+)]
 async fn delete_config_store(State(ctx): State<Context>, Path(id): Path<String>) -> Result<()> {
     let tx = ctx.db.begin_write()?;
 
@@ -160,18 +351,21 @@ async fn delete_config_store(State(ctx): State<Context>, Path(id): Path<String>)
             .map(|record| record.value().0.clone())
             .unwrap_or_default();
 
-        if metadata.config_stores.remove(&id).is_none() {
+        let store_meta = find_store_id(&metadata, StoreKind::Config, &id)
+            .and_then(|id| metadata.config_stores.get_mut(&id));
+        let Some(store_meta) = store_meta else {
             return Err(Error::builder()
                 .not_found()
                 .message("Config store not found")
                 .build());
-        }
+        };
+
+        // The table is kept until the store is purged, so the store can be restored.
+        store_meta.deleted_at = Some(Utc::now());
 
         metadata_table.insert(&(), &JsonRecord(metadata))?;
     }
 
-    tx.delete_table(ConfigStoreTable::new(&id))?;
-
     tx.commit()?;
 
     Ok(())
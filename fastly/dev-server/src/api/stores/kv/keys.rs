@@ -1,62 +1,157 @@
-use axum::extract::{Json, Path, State};
+use axum::extract::{DefaultBodyLimit, Json, Path, Query, State};
 use bytes::Bytes;
 use chrono::Utc;
 use headers::{HeaderMap, HeaderMapExt};
-use redb::{ReadableDatabase, ReadableTable};
-use serde::Serialize;
-
-use crate::api::{Context, Result, Router, error::Error};
-use crate::tables::{KVStoreItemMetadata, KVStoreTable as TableDefinition};
-use crate::util::JsonRecord;
-
-pub fn router() -> Router {
-    use axum::routing;
-
-    Router::new()
-        .route("/{store_id}/keys", routing::get(list_kv_keys))
-        .route(
-            "/{store_id}/keys/{key}",
-            routing::get(get_kv_item)
-                .put(upsert_kv_item)
-                .delete(delete_kv_item),
-        )
+use http::StatusCode;
+use redb::ReadableDatabase;
+use serde::{Deserialize, Serialize};
+
+use crate::api::openapi::documented_routes;
+use crate::api::stores::limits;
+use crate::api::stores::{
+    StoreKind, check_limit, enforce_limit, resolve_store_id, resolve_store_id_for_write,
+};
+use crate::api::{Context, Result, error::Error};
+use crate::kv::{InsertMode, KVTables};
+use crate::tables::KVStoreTable as TableDefinition;
+
+/// Maximum size of a batch import body
+const MAX_BATCH_SIZE: usize = 100 * 1024 * 1024;
+/// Maximum size of an item value body, above Fastly's limit so oversized values reach the
+/// limit check instead of being cut off
+const MAX_VALUE_SIZE: usize = 50 * 1024 * 1024;
+
+documented_routes! {
+    list_kv_keys,
+    batch_upsert_kv_items => DefaultBodyLimit::max(MAX_BATCH_SIZE),
+    get_kv_item => DefaultBodyLimit::max(MAX_VALUE_SIZE),
+    upsert_kv_item => DefaultBodyLimit::max(MAX_VALUE_SIZE),
+    delete_kv_item => DefaultBodyLimit::max(MAX_VALUE_SIZE),
+}
+
+#[derive(Debug, Clone, Default, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum Consistency {
+    #[default]
+    Strong,
+    Eventual,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct KVKeyListQuery {
+    cursor: Option<String>,
+    limit: Option<usize>,
+    #[serde(default)]
+    prefix: String,
+    /// Every read is strongly consistent locally, so this is only echoed back.
+    #[serde(default)]
+    consistency: Consistency,
 }
 
-#[derive(Debug, Clone, Default, Serialize)]
+#[derive(Debug, Clone, Default, Serialize, utoipa::ToSchema)]
 struct KVKeyListResponse {
     data: Vec<String>,
+    meta: KVKeyListMeta,
+}
+
+#[derive(Debug, Clone, Default, Serialize, utoipa::ToSchema)]
+struct KVKeyListMeta {
+    limit: usize,
+    prefix: String,
+    next_cursor: Option<String>,
+    consistency: &'static str,
 }
 
+#[utoipa::path(
+    get,
+    path = "/{store_id}/keys",
+    tag = "KV stores",
+    params(("store_id" = String, Path, description = "Store ID or name"), KVKeyListQuery),
+    responses(
+        (status = 200, body = KVKeyListResponse),
+    )
+)]
 async fn list_kv_keys(
     Path(store_id): Path<String>,
+    Query(query): Query<KVKeyListQuery>,
     State(ctx): State<Context>,
 ) -> Result<Json<KVKeyListResponse>> {
+    use crate::kv::{DEFAULT_LIST_LIMIT, KeyPage, ListKeysError, MAX_LIST_LIMIT, list_keys};
+
+    let store_id = resolve_store_id(&ctx, StoreKind::KV, store_id)?;
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+
     let tx = ctx.db.begin_read()?;
 
     let definition = TableDefinition::new(&store_id);
 
-    let table = match tx.open_table(definition) {
-        Ok(table) => table,
-        Err(redb::TableError::TableDoesNotExist(_)) => {
-            return Ok(Json(KVKeyListResponse::default()));
+    let page = match tx.open_table(definition) {
+        Ok(table) => {
+            match list_keys(
+                &table,
+                &query.prefix,
+                query.cursor.as_deref(),
+                limit,
+                Utc::now(),
+            ) {
+                Ok(page) => page,
+                Err(ListKeysError::InvalidCursor) => {
+                    return Err(Error::builder()
+                        .bad_request()
+                        .message("Invalid cursor")
+                        .build());
+                }
+                Err(ListKeysError::Storage(e)) => return Err(e.into()),
+            }
         }
+        Err(redb::TableError::TableDoesNotExist(_)) => KeyPage::default(),
         Err(e) => return Err(e.into()),
     };
 
-    let entries = table
-        .iter()?
-        .filter_map(|entry| entry.ok())
-        .map(|(key, _record)| key.value())
-        .collect::<Vec<String>>();
-
-    Ok(Json(KVKeyListResponse { data: entries }))
+    Ok(Json(KVKeyListResponse {
+        data: page.keys,
+        meta: KVKeyListMeta {
+            limit,
+            prefix: query.prefix,
+            next_cursor: page.next_cursor,
+            consistency: match query.consistency {
+                Consistency::Strong => "strong",
+                Consistency::Eventual => "eventual",
+            },
+        },
+    }))
 }
 
+#[utoipa::path(
+    get,
+    path = "/{store_id}/keys/{key}",
+    tag = "KV stores",
+    params(("store_id" = String, Path, description = "Store ID or name"), ("key" = String, Path)),
+    responses(
+        (
+            status = 200,
+            description = "Item value",
+            content_type = "application/octet-stream",
+            body = String,
+            headers(
+                ("generation" = u64),
+                ("metadata" = String, description = "Item metadata, if any"),
+            ),
+        ),
+    )
+)]
 async fn get_kv_item(
     Path((store_id, key)): Path<(String, String)>,
     State(ctx): State<Context>,
 ) -> Result<(HeaderMap, Bytes)> {
-    use crate::api::util::Generation;
+    use crate::api::util::{Generation, ItemMetadata};
+
+    let store_id = resolve_store_id(&ctx, StoreKind::KV, store_id)?;
 
     let tx = ctx.db.begin_read()?;
 
@@ -64,17 +159,18 @@ async fn get_kv_item(
 
     let table = match tx.open_table(definition) {
         Ok(table) => table,
+        // The store exists but has never been written to.
         Err(redb::TableError::TableDoesNotExist(_)) => {
             return Err(Error::builder()
                 .not_found()
-                .message("KV store not found")
+                .message("KV store item not found")
                 .build());
         }
         Err(e) => return Err(e.into()),
     };
 
-    let record = match table.get(&key)? {
-        Some(record) => record,
+    let item = match table.get(&key)? {
+        Some(record) => record.value().0,
         None => {
             return Err(Error::builder()
                 .not_found()
@@ -82,57 +178,733 @@ async fn get_kv_item(
                 .build());
         }
     };
-    let item = record.value().0;
+    if item.is_expired(Utc::now()) {
+        return Err(Error::builder()
+            .not_found()
+            .message("KV store item not found")
+            .build());
+    }
 
     let mut headers = HeaderMap::new();
-    headers.typed_insert(Generation(1));
+    headers.typed_insert(Generation(item.generation));
+    if let Some(metadata) = item.metadata {
+        headers.typed_insert(ItemMetadata(metadata));
+    }
 
     Ok((headers, item.value))
 }
 
+#[derive(Debug, Clone, Default, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(default)]
+struct UpsertKVItemQuery {
+    add: bool,
+    append: bool,
+    prepend: bool,
+    // `background_fetch` is accepted but has no effect, as writes are always applied before
+    // responding.
+}
+
+impl UpsertKVItemQuery {
+    fn mode(&self) -> Result<InsertMode> {
+        InsertMode::from_flags(self.add, self.append, self.prepend).ok_or_else(|| {
+            Error::builder()
+                .bad_request()
+                .message("Only one of `add`, `append` and `prepend` can be set")
+                .build()
+        })
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/{store_id}/keys/{key}",
+    tag = "KV stores",
+    params(
+        ("store_id" = String, Path, description = "Store ID or name"),
+        ("key" = String, Path),
+        UpsertKVItemQuery,
+        (
+            "if-generation-match" = Option<u64>,
+            Header,
+            description = "Only write if the item is at this generation",
+        ),
+        ("metadata" = Option<String>, Header),
+        ("time_to_live_sec" = Option<u64>, Header),
+    ),
+    request_body(
+        content = String,
+        content_type = "application/octet-stream",
+        description = "Item value",
+    ),
+    responses(
+        (status = 200, description = "Item written"),
+    )
+)]
 async fn upsert_kv_item(
     Path((store_id, key)): Path<(String, String)>,
+    Query(query): Query<UpsertKVItemQuery>,
     State(ctx): State<Context>,
+    request_headers: HeaderMap,
     value: Bytes,
 ) -> Result<()> {
+    use crate::api::util::{IfGenerationMatch, ItemMetadata, TimeToLiveSec};
+    use crate::kv::{Write, WriteError, time_to_live_secs};
+
+    let mode = query.mode()?;
+
+    let invalid_header = |name: &str| {
+        Error::builder()
+            .bad_request()
+            .message(format!("Invalid `{name}` header"))
+            .build()
+    };
+    let metadata = request_headers
+        .typed_try_get::<ItemMetadata>()
+        .map_err(|_| invalid_header("metadata"))?
+        .map(|ItemMetadata(metadata)| metadata);
+    let time_to_live = request_headers
+        .typed_try_get::<TimeToLiveSec>()
+        .map_err(|_| invalid_header("time_to_live_sec"))?
+        .map(|TimeToLiveSec(secs)| {
+            time_to_live_secs(secs).ok_or_else(|| invalid_header("time_to_live_sec"))
+        })
+        .transpose()?;
+    let if_generation_match = request_headers
+        .typed_try_get::<IfGenerationMatch>()
+        .map_err(|_| invalid_header("if-generation-match"))?
+        .map(|IfGenerationMatch(generation)| generation);
+
+    enforce_limit(
+        &ctx,
+        limits::check_kv_item(&key, &value, metadata.as_deref()),
+    )?;
+
     let tx = ctx.db.begin_write()?;
 
-    {
-        let definition = TableDefinition::new(&store_id);
+    let store_id = resolve_store_id_for_write(&tx, StoreKind::KV, store_id)?;
 
-        let mut table = tx.open_table(definition)?;
+    let stats = {
+        let mut tables = KVTables::open(&tx, &store_id)?;
 
         let now = Utc::now();
 
-        let meta = KVStoreItemMetadata {
+        let write = Write {
             value,
-            created_at: now,
-            updated_at: now,
+            metadata,
+            time_to_live,
+            mode,
+            if_generation_match,
         };
+        // Checked against the item being written, so concurrent appends can't outgrow it.
+        if matches!(mode, InsertMode::Append | InsertMode::Prepend) {
+            let size = tables.written_value_size(&key, &write, now)?;
+            enforce_limit(&ctx, limits::check_kv_value_size(size))?;
+        }
+        match tables.write_item(key, write, now) {
+            Ok(_generation) => {}
+            Err(WriteError::Storage(e)) => return Err(e.into()),
+            Err(e) => {
+                return Err(Error::builder()
+                    .precondition_failed()
+                    .message(e.to_string())
+                    .build());
+            }
+        }
 
-        table.insert(&key, &JsonRecord(meta))?;
-    }
+        tables.stats()
+    };
+
+    stats.commit(&tx, &store_id)?;
 
     tx.commit()?;
 
     Ok(())
 }
 
+/// One line of a batch import.
+#[derive(Debug, Clone, Deserialize, utoipa::ToSchema)]
+struct BatchItem {
+    key: String,
+    #[serde(with = "serde_with::As::<serde_with::base64::Base64>")]
+    #[schema(value_type = String, format = Byte)]
+    value: Bytes,
+    metadata: Option<String>,
+    time_to_live_sec: Option<u64>,
+    #[serde(default)]
+    add: bool,
+    #[serde(default)]
+    append: bool,
+    #[serde(default)]
+    prepend: bool,
+    if_generation_match: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+#[schema(as = KVBatchErrorResponse)]
+struct BatchErrorResponse {
+    title: &'static str,
+    errors: Vec<BatchLineError>,
+}
+
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+struct BatchLineError {
+    /// Zero-based line number
+    index: usize,
+    code: &'static str,
+    reason: String,
+}
+
+/// Write many items from a newline-delimited JSON body, as used by
+/// `fastly kv-store-entry create --file`.
+///
+/// The whole batch is applied in a single transaction: when any line is invalid or fails its
+/// precondition, nothing is written and every failing line is reported.
+#[utoipa::path(
+    put,
+    path = "/{store_id}/batch",
+    tag = "KV stores",
+    params(("store_id" = String, Path, description = "Store ID or name")),
+    request_body(
+        content = BatchItem,
+        content_type = "application/x-ndjson",
+        description = "One item per line",
+    ),
+    responses(
+        (status = 204, description = "Every item was written"),
+        (
+            status = 400,
+            description = "Some lines failed, none were written",
+            body = BatchErrorResponse,
+        ),
+    )
+)]
+async fn batch_upsert_kv_items(
+    Path(store_id): Path<String>,
+    State(ctx): State<Context>,
+    body: Bytes,
+) -> Result<axum::response::Response> {
+    use axum::response::IntoResponse;
+
+    use crate::kv::{Write, WriteError, time_to_live_secs};
+
+    let tx = ctx.db.begin_write()?;
+
+    let store_id = resolve_store_id_for_write(&tx, StoreKind::KV, store_id)?;
+
+    let mut errors = Vec::new();
+
+    let stats = {
+        let mut tables = KVTables::open(&tx, &store_id)?;
+
+        let now = Utc::now();
+
+        for (index, line) in body.split(|&byte| byte == b'\n').enumerate() {
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+
+            let item = match serde_json::from_slice::<BatchItem>(line) {
+                Ok(item) => item,
+                Err(err) => {
+                    errors.push(BatchLineError {
+                        index,
+                        code: "invalid",
+                        reason: err.to_string(),
+                    });
+                    continue;
+                }
+            };
+            let Some(mode) = InsertMode::from_flags(item.add, item.append, item.prepend) else {
+                errors.push(BatchLineError {
+                    index,
+                    code: "invalid",
+                    reason: "Only one of `add`, `append` and `prepend` can be set".to_string(),
+                });
+                continue;
+            };
+            if let Err(err) = check_limit(
+                &ctx,
+                limits::check_kv_item(&item.key, &item.value, item.metadata.as_deref()),
+            ) {
+                errors.push(BatchLineError {
+                    index,
+                    code: "limit_exceeded",
+                    reason: err.to_string(),
+                });
+                continue;
+            }
+
+            let time_to_live = match item.time_to_live_sec.map(time_to_live_secs) {
+                Some(None) => {
+                    errors.push(BatchLineError {
+                        index,
+                        code: "invalid",
+                        reason: "`time_to_live_sec` is out of range".to_string(),
+                    });
+                    continue;
+                }
+                time_to_live => time_to_live.flatten(),
+            };
+
+            let write = Write {
+                value: item.value,
+                metadata: item.metadata,
+                time_to_live,
+                mode,
+                if_generation_match: item.if_generation_match,
+            };
+            if matches!(mode, InsertMode::Append | InsertMode::Prepend) {
+                let size = tables.written_value_size(&item.key, &write, now)?;
+                if let Err(err) = check_limit(&ctx, limits::check_kv_value_size(size)) {
+                    errors.push(BatchLineError {
+                        index,
+                        code: "limit_exceeded",
+                        reason: err.to_string(),
+                    });
+                    continue;
+                }
+            }
+            match tables.write_item(item.key, write, now) {
+                Ok(_generation) => {}
+                Err(WriteError::Storage(e)) => return Err(e.into()),
+                Err(e) => errors.push(BatchLineError {
+                    index,
+                    code: "precondition_failed",
+                    reason: e.to_string(),
+                }),
+            }
+        }
+
+        tables.stats()
+    };
+
+    if !errors.is_empty() {
+        tx.abort()?;
+
+        let response = BatchErrorResponse {
+            title: "Batch contains invalid items",
+            errors,
+        };
+        return Ok((StatusCode::BAD_REQUEST, Json(response)).into_response());
+    }
+
+    stats.commit(&tx, &store_id)?;
+
+    tx.commit()?;
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/{store_id}/keys/{key}",
+    tag = "KV stores",
+    params(("store_id" = String, Path, description = "Store ID or name"), ("key" = String, Path)),
+    responses(
+        (status = 200, description = "Item deleted"),
+    )
+)]
 async fn delete_kv_item(
     Path((store_id, key)): Path<(String, String)>,
     State(ctx): State<Context>,
 ) -> Result<()> {
     let tx = ctx.db.begin_write()?;
 
-    {
-        let definition = TableDefinition::new(&store_id);
+    let store_id = resolve_store_id_for_write(&tx, StoreKind::KV, store_id)?;
 
-        let mut table = tx.open_table(definition)?;
+    let stats = {
+        let mut tables = KVTables::open(&tx, &store_id)?;
 
-        table.remove(&key)?;
-    }
+        tables.remove_item(key)?;
+
+        tables.stats()
+    };
+
+    stats.commit(&tx, &store_id)?;
 
     tx.commit()?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use http::Method;
+
+    use crate::api::testing::{self, send};
+
+    async fn put(app: &axum::Router, uri: &str, headers: &[(&str, &str)], value: &str) -> u16 {
+        send(app, Method::PUT, uri, headers, value.to_string())
+            .await
+            .status
+            .as_u16()
+    }
+
+    /// Value and generation of an item.
+    async fn get(app: &axum::Router, uri: &str) -> (String, String) {
+        let response = send(app, Method::GET, uri, &[], "").await;
+        assert_eq!(response.status, 200);
+
+        (
+            String::from_utf8(response.body.to_vec()).unwrap(),
+            response.headers["generation"].to_str().unwrap().to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn insert_modes() {
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "kv", "store").await;
+        let uri = format!("/resources/stores/kv/{store_id}/keys/key");
+
+        assert_eq!(put(&app, &format!("{uri}?add=true"), &[], "b").await, 200);
+        assert_eq!(put(&app, &format!("{uri}?add=true"), &[], "x").await, 412);
+        assert_eq!(
+            put(&app, &format!("{uri}?append=true"), &[], "c").await,
+            200
+        );
+        assert_eq!(
+            put(&app, &format!("{uri}?prepend=true"), &[], "a").await,
+            200
+        );
+        assert_eq!(get(&app, &uri).await.0, "abc");
+
+        assert_eq!(
+            put(&app, &format!("{uri}?add=true&append=true"), &[], "x").await,
+            400
+        );
+        assert_eq!(put(&app, &uri, &[], "new").await, 200);
+        assert_eq!(get(&app, &uri).await.0, "new");
+    }
+
+    #[tokio::test]
+    async fn appending_to_a_missing_item_creates_it() {
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "kv", "store").await;
+        let uri = format!("/resources/stores/kv/{store_id}/keys/key");
+
+        assert_eq!(
+            put(&app, &format!("{uri}?append=true"), &[], "a").await,
+            200
+        );
+        assert_eq!(get(&app, &uri).await, ("a".to_string(), "1".to_string()));
+    }
+
+    #[tokio::test]
+    async fn generation_preconditions() {
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "kv", "store").await;
+        let uri = format!("/resources/stores/kv/{store_id}/keys/key");
+
+        // Nothing to match yet.
+        assert_eq!(
+            put(&app, &uri, &[("if-generation-match", "1")], "a").await,
+            412
+        );
+
+        assert_eq!(put(&app, &uri, &[], "a").await, 200);
+        let (_, generation) = get(&app, &uri).await;
+        assert_eq!(generation, "1");
+
+        assert_eq!(
+            put(&app, &uri, &[("if-generation-match", "1")], "b").await,
+            200
+        );
+        // The generation moved on, so a writer still holding the old one fails.
+        assert_eq!(
+            put(&app, &uri, &[("if-generation-match", "1")], "c").await,
+            412
+        );
+        assert_eq!(get(&app, &uri).await, ("b".to_string(), "2".to_string()));
+
+        assert_eq!(
+            put(&app, &uri, &[("if-generation-match", "nope")], "c").await,
+            400
+        );
+    }
+
+    #[tokio::test]
+    async fn deleted_items_match_no_generation() {
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "kv", "store").await;
+        let uri = format!("/resources/stores/kv/{store_id}/keys/key");
+
+        assert_eq!(put(&app, &uri, &[], "a").await, 200);
+        assert_eq!(put(&app, &uri, &[], "b").await, 200);
+        assert_eq!(get(&app, &uri).await.1, "2");
+
+        assert_eq!(send(&app, Method::DELETE, &uri, &[], "").await.status, 200);
+        assert_eq!(send(&app, Method::GET, &uri, &[], "").await.status, 404);
+        assert_eq!(
+            put(&app, &uri, &[("if-generation-match", "2")], "c").await,
+            412
+        );
+    }
+
+    #[tokio::test]
+    async fn time_to_live_out_of_range_is_rejected() {
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "kv", "store").await;
+        let too_long = (i64::MAX as u64 / 1000 + 1).to_string();
+
+        let response = send(
+            &app,
+            Method::PUT,
+            &format!("/resources/stores/kv/{store_id}/keys/key"),
+            &[("time_to_live_sec", &too_long)],
+            "value",
+        )
+        .await;
+        assert_eq!(response.status, 400);
+
+        let line = format!(r#"{{"key":"key","value":"dmFsdWU=","time_to_live_sec":{too_long}}}"#);
+        let response = send(
+            &app,
+            Method::PUT,
+            &format!("/resources/stores/kv/{store_id}/batch"),
+            &[("content-type", "application/x-ndjson")],
+            line,
+        )
+        .await;
+        assert_eq!(response.status, 400);
+        assert_eq!(response.json()["errors"][0]["code"], "invalid");
+    }
+
+    #[tokio::test]
+    async fn appends_are_limited_to_the_value_size() {
+        let app = testing::app(crate::api::Context {
+            strict_limits: true,
+            ..testing::context()
+        });
+        let store_id = testing::create_store(&app, "kv", "store").await;
+        let uri = format!("/resources/stores/kv/{store_id}/keys/key");
+        let half = "a".repeat(13 * 1024 * 1024);
+
+        assert_eq!(put(&app, &uri, &[], &half).await, 200);
+        assert_eq!(
+            put(&app, &format!("{uri}?append=true"), &[], &half).await,
+            400
+        );
+        assert_eq!(
+            put(&app, &format!("{uri}?prepend=true"), &[], &half).await,
+            400
+        );
+        assert_eq!(
+            put(&app, &format!("{uri}?append=true"), &[], "b").await,
+            200
+        );
+
+        let response = batch(
+            &app,
+            &store_id,
+            &[r#"{"key":"key","value":"YWFh","append":true}"#; 2],
+        )
+        .await;
+        assert_eq!(response.status, 204);
+        assert_eq!(get(&app, &uri).await.0.len(), half.len() + 7);
+    }
+
+    async fn batch(app: &axum::Router, store_id: &str, lines: &[&str]) -> testing::Response {
+        send(
+            app,
+            Method::PUT,
+            &format!("/resources/stores/kv/{store_id}/batch"),
+            &[("content-type", "application/x-ndjson")],
+            lines.join("\n"),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn batch_writes_every_line() {
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "kv", "store").await;
+        let uri = format!("/resources/stores/kv/{store_id}/keys");
+        assert_eq!(put(&app, &format!("{uri}/log"), &[], "a").await, 200);
+
+        let response = batch(
+            &app,
+            &store_id,
+            &[
+                r#"{"key":"one","value":"MQ==","metadata":"meta"}"#,
+                "",
+                r#"{"key":"log","value":"Yg==","append":true}"#,
+                r#"{"key":"two","value":"Mg==","add":true,"time_to_live_sec":60}"#,
+                "",
+            ],
+        )
+        .await;
+        assert_eq!(response.status, 204, "{:?}", response.body);
+
+        assert_eq!(get(&app, &format!("{uri}/one")).await.0, "1");
+        assert_eq!(
+            get(&app, &format!("{uri}/log")).await,
+            ("ab".into(), "2".into())
+        );
+        assert_eq!(get(&app, &format!("{uri}/two")).await.0, "2");
+
+        let response = send(&app, Method::GET, &format!("{uri}/one"), &[], "").await;
+        assert_eq!(response.headers["metadata"], "meta");
+    }
+
+    #[tokio::test]
+    async fn batch_with_failing_lines_writes_nothing() {
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "kv", "store").await;
+        let uri = format!("/resources/stores/kv/{store_id}/keys");
+        assert_eq!(put(&app, &format!("{uri}/taken"), &[], "a").await, 200);
+
+        let response = batch(
+            &app,
+            &store_id,
+            &[
+                r#"{"key":"fine","value":"MQ=="}"#,
+                r#"{"key":"broken","value":"not base64"}"#,
+                r#"{"key":"taken","value":"MQ==","add":true}"#,
+                r#"{"key":"both","value":"MQ==","add":true,"append":true}"#,
+                r#"{"key":"taken","value":"MQ==","if_generation_match":5}"#,
+            ],
+        )
+        .await;
+        assert_eq!(response.status, 400);
+
+        let errors: Vec<_> = response.json()["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|error| {
+                (
+                    error["index"].as_u64().unwrap(),
+                    error["code"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                (1, "invalid".to_string()),
+                (2, "precondition_failed".to_string()),
+                (3, "invalid".to_string()),
+                (4, "precondition_failed".to_string()),
+            ]
+        );
+
+        let response = send(&app, Method::GET, &format!("{uri}/fine"), &[], "").await;
+        assert_eq!(response.status, 404);
+        assert_eq!(
+            get(&app, &format!("{uri}/taken")).await,
+            ("a".into(), "1".into())
+        );
+    }
+
+    /// Keys of every page of a listing, following cursors.
+    async fn list_pages(app: &axum::Router, store_id: &str, query: &str) -> Vec<Vec<String>> {
+        let mut pages = Vec::new();
+        let mut cursor = None::<String>;
+
+        loop {
+            let mut uri = format!("/resources/stores/kv/{store_id}/keys?{query}");
+            if let Some(cursor) = &cursor {
+                let cursor = cursor
+                    .replace('+', "%2B")
+                    .replace('/', "%2F")
+                    .replace('=', "%3D");
+                uri.push_str(&format!("&cursor={cursor}"));
+            }
+            let body = send(app, Method::GET, &uri, &[], "").await.json();
+
+            pages.push(
+                body["data"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|key| key.as_str().unwrap().to_string())
+                    .collect(),
+            );
+            match body["meta"]["next_cursor"].as_str() {
+                Some(next) => cursor = Some(next.to_string()),
+                None => return pages,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn listing_pages_with_prefix() {
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "kv", "store").await;
+        for key in ["b/2", "a/1", "b/1", "c", "b/3", "a/2"] {
+            let key = key.replace('/', "%2F");
+            let uri = format!("/resources/stores/kv/{store_id}/keys/{key}");
+            assert_eq!(put(&app, &uri, &[], "v").await, 200);
+        }
+
+        assert_eq!(
+            list_pages(&app, &store_id, "limit=4").await,
+            [vec!["a/1", "a/2", "b/1", "b/2"], vec!["b/3", "c"]],
+        );
+        assert_eq!(
+            list_pages(&app, &store_id, "limit=2&prefix=b%2F").await,
+            [vec!["b/1", "b/2"], vec!["b/3"]],
+        );
+        // A full last page has no cursor.
+        assert_eq!(
+            list_pages(&app, &store_id, "limit=2&prefix=a%2F").await,
+            [vec!["a/1", "a/2"]],
+        );
+        assert_eq!(
+            list_pages(&app, &store_id, "prefix=d").await,
+            [Vec::<String>::new()]
+        );
+
+        let body = send(
+            &app,
+            Method::GET,
+            &format!("/resources/stores/kv/{store_id}/keys?limit=5000&consistency=eventual"),
+            &[],
+            "",
+        )
+        .await
+        .json();
+        assert_eq!(body["meta"]["limit"], 1000);
+        assert_eq!(body["meta"]["consistency"], "eventual");
+    }
+
+    #[tokio::test]
+    async fn listing_resumes_after_changes() {
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "kv", "store").await;
+        for key in ["a", "b", "c", "d"] {
+            let uri = format!("/resources/stores/kv/{store_id}/keys/{key}");
+            assert_eq!(put(&app, &uri, &[], "v").await, 200);
+        }
+
+        let list = |query: String| {
+            let app = app.clone();
+            let store_id = store_id.clone();
+            async move {
+                send(
+                    &app,
+                    Method::GET,
+                    &format!("/resources/stores/kv/{store_id}/keys?{query}"),
+                    &[],
+                    "",
+                )
+                .await
+            }
+        };
+
+        let first = list("limit=2".to_string()).await.json();
+        assert_eq!(first["data"], serde_json::json!(["a", "b"]));
+        let cursor = first["meta"]["next_cursor"].as_str().unwrap().to_string();
+
+        // The cursor names the last key, so deleting it doesn't lose the position.
+        let uri = format!("/resources/stores/kv/{store_id}/keys/b");
+        assert_eq!(send(&app, Method::DELETE, &uri, &[], "").await.status, 200);
+
+        let second = list(format!("limit=2&cursor={cursor}")).await.json();
+        assert_eq!(second["data"], serde_json::json!(["c", "d"]));
+
+        assert_eq!(list("cursor=%21%21".to_string()).await.status, 400);
+    }
+}
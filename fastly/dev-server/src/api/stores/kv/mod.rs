@@ -1,16 +1,21 @@
-use axum::extract::{Json, Path, State};
+use axum::extract::{Json, Path, Query, State};
 use chrono::{DateTime, Utc};
 use http::StatusCode;
 use redb::{ReadableDatabase, ReadableTable};
 use serde::{Deserialize, Serialize};
 
-use crate::api::{Context, Result, Router, error::Error};
-use crate::tables::{KVStoreMetadata, KVStoreTable, METADATA_TABLE};
+use super::{
+    ListStoresQuery, StoreInfo, StoreKind, ensure_unique_name, find_deleted_store_id,
+    find_store_id, store_info,
+};
+use crate::api::openapi::documented_routes;
+use crate::api::{Context, Result, error::Error};
+use crate::tables::{KVStoreMetadata, METADATA_TABLE, StoreStats};
 use crate::util::JsonRecord;
 
 mod keys;
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
 struct KVStore {
     id: String,
     name: String,
@@ -19,21 +24,35 @@ struct KVStore {
     deleted_at: Option<DateTime<Utc>>,
 }
 
-pub fn router() -> Router {
-    use axum::routing;
-
-    Router::new()
-        .route("/", routing::get(list_kv_stores).post(create_kv_store))
-        .route("/{id}", routing::get(get_kv_store).delete(delete_kv_store))
-        .merge(keys::router())
+documented_routes! {
+    list_kv_stores,
+    create_kv_store,
+    get_kv_store,
+    update_kv_store,
+    delete_kv_store,
+    get_kv_store_info,
+    undelete_kv_store;
+    merge(keys)
 }
 
-#[derive(Debug, Clone, Default, Serialize)]
+#[derive(Debug, Clone, Default, Serialize, utoipa::ToSchema)]
 struct KVStoreListResponse {
     data: Vec<KVStore>,
 }
 
-async fn list_kv_stores(State(ctx): State<Context>) -> Result<Json<KVStoreListResponse>> {
+#[utoipa::path(
+    get,
+    path = "",
+    tag = "KV stores",
+    params(ListStoresQuery),
+    responses(
+        (status = 200, body = KVStoreListResponse),
+    )
+)]
+async fn list_kv_stores(
+    State(ctx): State<Context>,
+    Query(query): Query<ListStoresQuery>,
+) -> Result<Json<KVStoreListResponse>> {
     let tx = ctx.db.begin_read()?;
 
     let metadata_table = match tx.open_table(METADATA_TABLE) {
@@ -51,23 +70,33 @@ async fn list_kv_stores(State(ctx): State<Context>) -> Result<Json<KVStoreListRe
     let entries = metadata
         .kv_stores
         .iter()
+        .filter(|(_, store_meta)| query.include_deleted || store_meta.deleted_at.is_none())
         .map(|(id, store_meta)| KVStore {
             id: id.clone(),
             name: store_meta.name.clone(),
             created_at: store_meta.created_at,
             updated_at: store_meta.updated_at,
-            deleted_at: None,
+            deleted_at: store_meta.deleted_at,
         })
         .collect::<Vec<KVStore>>();
 
     Ok(Json(KVStoreListResponse { data: entries }))
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, utoipa::ToSchema)]
 pub struct CreateKVStoreRequest {
     pub name: String,
 }
 
+#[utoipa::path(
+    post,
+    path = "",
+    tag = "KV stores",
+    request_body = CreateKVStoreRequest,
+    responses(
+        (status = 200, body = KVStore),
+    )
+)]
 async fn create_kv_store(
     State(ctx): State<Context>,
     Json(payload): Json<CreateKVStoreRequest>,
@@ -81,13 +110,17 @@ async fn create_kv_store(
             .map(|record| record.value().0.clone())
             .unwrap_or_default();
 
+        ensure_unique_name(&metadata, StoreKind::KV, &payload.name)?;
+
         let now = Utc::now();
         let id = ulid::Ulid::new().to_string();
 
         let store_meta = KVStoreMetadata {
             name: payload.name.clone(),
+            stats: Some(StoreStats::default()),
             created_at: now,
             updated_at: now,
+            deleted_at: None,
         };
         metadata.kv_stores.insert(id.clone(), store_meta);
 
@@ -107,6 +140,15 @@ async fn create_kv_store(
     Ok(Json(store))
 }
 
+#[utoipa::path(
+    get,
+    path = "/{id}",
+    tag = "KV stores",
+    params(("id" = String, Path, description = "Store ID or name")),
+    responses(
+        (status = 200, body = KVStore),
+    )
+)]
 async fn get_kv_store(
     State(ctx): State<Context>,
     axum::extract::Path(id): axum::extract::Path<String>,
@@ -131,7 +173,13 @@ async fn get_kv_store(
     };
     let metadata = &metadata_record.value().0;
 
-    let store_meta = match metadata.kv_stores.get(&id) {
+    let id = find_store_id(metadata, StoreKind::KV, &id).unwrap_or(id);
+
+    let store_meta = match metadata
+        .kv_stores
+        .get(&id)
+        .filter(|meta| meta.deleted_at.is_none())
+    {
         Some(meta) => meta,
         None => {
             return Err(Error::builder()
@@ -150,6 +198,149 @@ async fn get_kv_store(
     }))
 }
 
+#[derive(Debug, Clone, Deserialize, utoipa::ToSchema)]
+pub struct UpdateKVStoreRequest {
+    pub name: String,
+}
+
+#[utoipa::path(
+    put,
+    path = "/{id}",
+    tag = "KV stores",
+    params(("id" = String, Path, description = "Store ID or name")),
+    request_body = UpdateKVStoreRequest,
+    responses(
+        (status = 200, body = KVStore),
+    )
+)]
+async fn update_kv_store(
+    State(ctx): State<Context>,
+    Path(id): Path<String>,
+    Json(payload): Json<UpdateKVStoreRequest>,
+) -> Result<Json<KVStore>> {
+    let tx = ctx.db.begin_write()?;
+
+    let store = {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let mut metadata = metadata_table
+            .get(&())?
+            .map(|record| record.value().0.clone())
+            .unwrap_or_default();
+
+        let Some(id) = find_store_id(&metadata, StoreKind::KV, &id) else {
+            return Err(StoreKind::KV.not_found());
+        };
+
+        if payload.name != metadata.kv_stores[&id].name {
+            ensure_unique_name(&metadata, StoreKind::KV, &payload.name)?;
+        }
+
+        let now = Utc::now();
+
+        let store_meta = metadata
+            .kv_stores
+            .get_mut(&id)
+            .expect("Store was just found");
+        store_meta.name = payload.name;
+        store_meta.updated_at = now;
+
+        let store = KVStore {
+            id,
+            name: store_meta.name.clone(),
+            created_at: store_meta.created_at,
+            updated_at: store_meta.updated_at,
+            deleted_at: None,
+        };
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+
+        store
+    };
+
+    tx.commit()?;
+
+    Ok(Json(store))
+}
+
+#[utoipa::path(
+    get,
+    path = "/{id}/info",
+    tag = "KV stores",
+    params(("id" = String, Path, description = "Store ID or name")),
+    responses(
+        (status = 200, body = StoreInfo),
+    )
+)]
+async fn get_kv_store_info(
+    State(ctx): State<Context>,
+    Path(id): Path<String>,
+) -> Result<Json<StoreInfo>> {
+    store_info(&ctx, StoreKind::KV, id).map(Json)
+}
+
+/// Restore a soft deleted store, unless another store took its name in the meantime.
+#[utoipa::path(
+    post,
+    path = "/{id}/undelete",
+    tag = "KV stores",
+    params(("id" = String, Path, description = "Store ID or name")),
+    responses(
+        (status = 200, body = KVStore),
+    )
+)]
+async fn undelete_kv_store(
+    State(ctx): State<Context>,
+    Path(id): Path<String>,
+) -> Result<Json<KVStore>> {
+    let tx = ctx.db.begin_write()?;
+
+    let store = {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let mut metadata = metadata_table
+            .get(&())?
+            .map(|record| record.value().0.clone())
+            .unwrap_or_default();
+
+        let Some(id) = find_deleted_store_id(&metadata, StoreKind::KV, &id) else {
+            return Err(StoreKind::KV.not_found());
+        };
+
+        let name = metadata.kv_stores[&id].name.clone();
+        ensure_unique_name(&metadata, StoreKind::KV, &name)?;
+
+        let store_meta = metadata
+            .kv_stores
+            .get_mut(&id)
+            .expect("Store was just found");
+        store_meta.deleted_at = None;
+
+        let store = KVStore {
+            id,
+            name: store_meta.name.clone(),
+            created_at: store_meta.created_at,
+            updated_at: store_meta.updated_at,
+            deleted_at: None,
+        };
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+
+        store
+    };
+
+    tx.commit()?;
+
+    Ok(Json(store))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/{id}",
+    tag = "KV stores",
+    params(("id" = String, Path, description = "Store ID or name")),
+    responses(
+        (status = 204, description = "Store deleted"),
+    )
+)]
 async fn delete_kv_store(State(ctx): State<Context>, Path(id): Path<String>) -> Result<StatusCode> {
     let tx = ctx.db.begin_write()?;
 
@@ -160,19 +351,105 @@ async fn delete_kv_store(State(ctx): State<Context>, Path(id): Path<String>) ->
             .map(|record| record.value().0.clone())
             .unwrap_or_default();
 
-        if metadata.kv_stores.remove(&id).is_none() {
+        let store_meta = find_store_id(&metadata, StoreKind::KV, &id)
+            .and_then(|id| metadata.kv_stores.get_mut(&id));
+        let Some(store_meta) = store_meta else {
             return Err(Error::builder()
                 .not_found()
                 .message("KV store not found")
                 .build());
-        }
+        };
+
+        // The table is kept until the store is purged, so the store can be restored.
+        store_meta.deleted_at = Some(Utc::now());
 
         metadata_table.insert(&(), &JsonRecord(metadata))?;
     }
 
-    tx.delete_table(KVStoreTable::new(&id))?;
-
     tx.commit()?;
 
     Ok(StatusCode::NO_CONTENT)
 }
+
+#[cfg(test)]
+mod tests {
+    use http::Method;
+
+    use crate::api::testing::{self, send};
+
+    #[tokio::test]
+    async fn info_counts_items() {
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "kv", "store").await;
+        let info = async || {
+            let uri = format!("/resources/stores/kv/{store_id}/info");
+            send(&app, Method::GET, &uri, &[], "").await.json()
+        };
+
+        assert_eq!(info().await["item_count"], 0);
+
+        for (key, value) in [("a", "value"), ("b", "value"), ("a", "longer value")] {
+            let uri = format!("/resources/stores/kv/{store_id}/keys/{key}");
+            let response = send(&app, Method::PUT, &uri, &[], value).await;
+            assert_eq!(response.status, 200);
+        }
+        let uri = format!("/resources/stores/kv/{store_id}/keys/b");
+        assert_eq!(send(&app, Method::DELETE, &uri, &[], "").await.status, 200);
+
+        let info = info().await;
+        assert_eq!(info["item_count"], 1);
+        assert_eq!(info["total_size"], 13);
+    }
+
+    #[tokio::test]
+    async fn renaming_leaves_resource_links_alone() {
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "kv", "store").await;
+        let form = [("content-type", "application/x-www-form-urlencoded")];
+
+        let response = send(&app, Method::POST, "/service", &form, "name=app").await;
+        let service_id = response.json()["id"].as_str().unwrap().to_string();
+        let uri = format!("/service/{service_id}/version/1/resource");
+        let response = send(
+            &app,
+            Method::POST,
+            &uri,
+            &form,
+            format!("resource_id={store_id}"),
+        )
+        .await;
+        assert_eq!(response.status, 200, "{:?}", response.body);
+        assert_eq!(response.json()["name"], "store");
+
+        let response = send(
+            &app,
+            Method::PUT,
+            &format!("/resources/stores/kv/{store_id}"),
+            &[],
+            serde_json::json!({ "name": "renamed" }).to_string(),
+        )
+        .await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.json()["name"], "renamed");
+
+        let links = send(&app, Method::GET, &uri, &[], "").await.json();
+        assert_eq!(links[0]["name"], "store");
+    }
+
+    #[tokio::test]
+    async fn undeleting_a_store_whose_name_was_taken_conflicts() {
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "kv", "store").await;
+        let uri = format!("/resources/stores/kv/{store_id}");
+
+        assert_eq!(send(&app, Method::DELETE, &uri, &[], "").await.status, 204);
+        testing::create_store(&app, "kv", "store").await;
+
+        let undelete = format!("{uri}/undelete");
+        let response = send(&app, Method::POST, &undelete, &[], "").await;
+        assert_eq!(response.status, 409);
+
+        let response = send(&app, Method::GET, &uri, &[], "").await;
+        assert_eq!(response.status, 404);
+    }
+}
@@ -0,0 +1,230 @@
+//! Store limits enforced by Fastly in production.
+
+/// Maximum length of a config store item key, in characters
+const CONFIG_KEY_MAX_LEN: usize = 256;
+/// Maximum length of a config store item value, in characters
+const CONFIG_VALUE_MAX_LEN: usize = 8000;
+/// Maximum number of items in a config store
+pub const CONFIG_MAX_ITEMS: u64 = 500;
+
+/// Maximum length of a KV store key, in bytes
+const KV_KEY_MAX_LEN: usize = 1024;
+/// Maximum size of a KV store value, in bytes
+const KV_VALUE_MAX_SIZE: usize = 25 * 1024 * 1024;
+/// Maximum size of KV store item metadata, in bytes
+const KV_METADATA_MAX_SIZE: usize = 2000;
+const KV_KEY_FORBIDDEN_CHARS: &[char] = &['\r', '\n', '#', ';', '?', '^', '|', '[', ']', '*'];
+const KV_KEY_FORBIDDEN_PREFIX: &str = ".well-known/acme-challenge/";
+
+/// Maximum length of a secret name, in characters
+const SECRET_NAME_MAX_LEN: usize = 255;
+/// Maximum size of a secret, in bytes
+const SECRET_MAX_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LimitError {
+    #[error("Key must not be empty")]
+    EmptyKey,
+    #[error("Key length {len} exceeds the limit of {max}")]
+    KeyTooLong { len: usize, max: usize },
+    #[error("Key contains the invalid character {0:?}")]
+    InvalidKeyChar(char),
+    #[error("Key `{0}` is not allowed")]
+    ReservedKey(String),
+    #[error("Value is {len} characters long, longer than the limit of {max}")]
+    ValueTooLong { len: usize, max: usize },
+    #[error("Value is {size} bytes, larger than the limit of {max}")]
+    ValueTooLarge { size: usize, max: usize },
+    #[error("Metadata is {size} bytes, larger than the limit of {max}")]
+    MetadataTooLarge { size: usize, max: usize },
+    #[error("Store already holds the maximum of {0} items")]
+    TooManyItems(u64),
+}
+
+/// Config store keys and secret names are limited to letters, digits, `-`, `_` and `.`.
+fn check_name_chars(key: &str) -> Result<(), LimitError> {
+    match key
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+    {
+        Some(c) => Err(LimitError::InvalidKeyChar(c)),
+        None => Ok(()),
+    }
+}
+
+pub fn check_config_item(key: &str, value: &str) -> Result<(), LimitError> {
+    if key.is_empty() {
+        return Err(LimitError::EmptyKey);
+    }
+    let len = key.chars().count();
+    if len > CONFIG_KEY_MAX_LEN {
+        return Err(LimitError::KeyTooLong {
+            len,
+            max: CONFIG_KEY_MAX_LEN,
+        });
+    }
+    check_name_chars(key)?;
+
+    let len = value.chars().count();
+    if len > CONFIG_VALUE_MAX_LEN {
+        return Err(LimitError::ValueTooLong {
+            len,
+            max: CONFIG_VALUE_MAX_LEN,
+        });
+    }
+
+    Ok(())
+}
+
+/// Check that a store can take one more item.
+pub fn check_item_count(count: u64, max: u64) -> Result<(), LimitError> {
+    if count >= max {
+        return Err(LimitError::TooManyItems(max));
+    }
+
+    Ok(())
+}
+
+pub fn check_kv_item(key: &str, value: &[u8], metadata: Option<&str>) -> Result<(), LimitError> {
+    if key.is_empty() {
+        return Err(LimitError::EmptyKey);
+    }
+    if key.len() > KV_KEY_MAX_LEN {
+        return Err(LimitError::KeyTooLong {
+            len: key.len(),
+            max: KV_KEY_MAX_LEN,
+        });
+    }
+    if key == "." || key == ".." || key.starts_with(KV_KEY_FORBIDDEN_PREFIX) {
+        return Err(LimitError::ReservedKey(key.to_string()));
+    }
+    if let Some(c) = key.chars().find(|c| KV_KEY_FORBIDDEN_CHARS.contains(c)) {
+        return Err(LimitError::InvalidKeyChar(c));
+    }
+
+    check_kv_value_size(value.len())?;
+    if let Some(metadata) = metadata
+        && metadata.len() > KV_METADATA_MAX_SIZE
+    {
+        return Err(LimitError::MetadataTooLarge {
+            size: metadata.len(),
+            max: KV_METADATA_MAX_SIZE,
+        });
+    }
+
+    Ok(())
+}
+
+/// Check the size of a KV store value, such as the value an append or prepend results in.
+pub fn check_kv_value_size(size: usize) -> Result<(), LimitError> {
+    if size > KV_VALUE_MAX_SIZE {
+        return Err(LimitError::ValueTooLarge {
+            size,
+            max: KV_VALUE_MAX_SIZE,
+        });
+    }
+
+    Ok(())
+}
+
+pub fn check_secret(name: &str, secret: &[u8]) -> Result<(), LimitError> {
+    if name.is_empty() {
+        return Err(LimitError::EmptyKey);
+    }
+    let len = name.chars().count();
+    if len > SECRET_NAME_MAX_LEN {
+        return Err(LimitError::KeyTooLong {
+            len,
+            max: SECRET_NAME_MAX_LEN,
+        });
+    }
+    check_name_chars(name)?;
+
+    if secret.len() > SECRET_MAX_SIZE {
+        return Err(LimitError::ValueTooLarge {
+            size: secret.len(),
+            max: SECRET_MAX_SIZE,
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_items() {
+        assert!(check_config_item("feature.flag-1_a", "on").is_ok());
+        assert!(matches!(
+            check_config_item("", "on"),
+            Err(LimitError::EmptyKey)
+        ));
+        assert!(matches!(
+            check_config_item("a/b", "on"),
+            Err(LimitError::InvalidKeyChar('/'))
+        ));
+        assert!(check_config_item(&"k".repeat(CONFIG_KEY_MAX_LEN), "").is_ok());
+        assert!(matches!(
+            check_config_item(&"k".repeat(CONFIG_KEY_MAX_LEN + 1), ""),
+            Err(LimitError::KeyTooLong { .. })
+        ));
+        // Values are limited in characters, not bytes.
+        assert!(check_config_item("key", &"é".repeat(CONFIG_VALUE_MAX_LEN)).is_ok());
+        assert!(matches!(
+            check_config_item("key", &"é".repeat(CONFIG_VALUE_MAX_LEN + 1)),
+            Err(LimitError::ValueTooLong { .. })
+        ));
+    }
+
+    #[test]
+    fn kv_items() {
+        assert!(check_kv_item("user/123:profile", b"value", Some("meta")).is_ok());
+        for key in [".", "..", ".well-known/acme-challenge/token"] {
+            assert!(matches!(
+                check_kv_item(key, b"", None),
+                Err(LimitError::ReservedKey(_))
+            ));
+        }
+        assert!(matches!(
+            check_kv_item("a#b", b"", None),
+            Err(LimitError::InvalidKeyChar('#'))
+        ));
+        assert!(matches!(
+            check_kv_item(&"k".repeat(KV_KEY_MAX_LEN + 1), b"", None),
+            Err(LimitError::KeyTooLong { .. })
+        ));
+        assert!(matches!(
+            check_kv_item("key", b"", Some(&"m".repeat(KV_METADATA_MAX_SIZE + 1))),
+            Err(LimitError::MetadataTooLarge { .. })
+        ));
+        assert!(check_kv_value_size(KV_VALUE_MAX_SIZE).is_ok());
+        assert!(matches!(
+            check_kv_value_size(KV_VALUE_MAX_SIZE + 1),
+            Err(LimitError::ValueTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn secrets() {
+        assert!(check_secret("api_key", b"secret").is_ok());
+        assert!(matches!(
+            check_secret("api key", b"secret"),
+            Err(LimitError::InvalidKeyChar(' '))
+        ));
+        assert!(matches!(
+            check_secret("api_key", &vec![0; SECRET_MAX_SIZE + 1]),
+            Err(LimitError::ValueTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn item_count() {
+        assert!(check_item_count(CONFIG_MAX_ITEMS - 1, CONFIG_MAX_ITEMS).is_ok());
+        assert!(matches!(
+            check_item_count(CONFIG_MAX_ITEMS, CONFIG_MAX_ITEMS),
+            Err(LimitError::TooManyItems(CONFIG_MAX_ITEMS))
+        ));
+    }
+}
@@ -1,7 +1,13 @@
-use super::Router;
+use chrono::{DateTime, Utc};
+use redb::{ReadableDatabase, ReadableTable};
+use serde::{Deserialize, Serialize};
+
+use super::{Context, Result, Router, error::Error};
+use crate::tables::{METADATA_TABLE, Metadata};
 
 mod config;
 mod kv;
+mod limits;
 mod secret;
 
 pub fn router() -> Router {
@@ -10,3 +16,208 @@ pub fn router() -> Router {
         .nest("/kv", kv::router())
         .nest("/secret", secret::router())
 }
+
+#[derive(utoipa::OpenApi)]
+#[openapi(nest(
+    (path = "/config", api = config::ApiDoc),
+    (path = "/kv", api = kv::ApiDoc),
+    (path = "/secret", api = secret::ApiDoc),
+))]
+pub struct ApiDoc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StoreKind {
+    Config,
+    KV,
+    Secret,
+}
+
+impl StoreKind {
+    fn not_found(self) -> Error {
+        let message = match self {
+            StoreKind::Config => "Config store not found",
+            StoreKind::KV => "KV store not found",
+            StoreKind::Secret => "Secret store not found",
+        };
+
+        Error::builder().not_found().message(message).build()
+    }
+
+    /// IDs and names of every store of this kind that isn't soft deleted.
+    fn stores(self, metadata: &Metadata) -> Vec<(&str, &str)> {
+        self.all_stores(metadata)
+            .into_iter()
+            .filter(|(_, _, deleted_at)| deleted_at.is_none())
+            .map(|(id, name, _)| (id, name))
+            .collect()
+    }
+
+    /// IDs, names and deletion times of every store of this kind, including soft deleted ones.
+    fn all_stores(self, metadata: &Metadata) -> Vec<(&str, &str, Option<DateTime<Utc>>)> {
+        match self {
+            StoreKind::Config => metadata
+                .config_stores
+                .iter()
+                .map(|(id, meta)| (id.as_str(), meta.name.as_str(), meta.deleted_at))
+                .collect(),
+            StoreKind::KV => metadata
+                .kv_stores
+                .iter()
+                .map(|(id, meta)| (id.as_str(), meta.name.as_str(), meta.deleted_at))
+                .collect(),
+            StoreKind::Secret => metadata
+                .secret_stores
+                .iter()
+                .map(|(id, meta)| (id.as_str(), meta.name.as_str(), meta.deleted_at))
+                .collect(),
+        }
+    }
+}
+
+/// Find the ID of a store from a path parameter holding either its ID or its name, like the
+/// Fastly CLI's `--store-id`.
+fn find_store_id(metadata: &Metadata, kind: StoreKind, id_or_name: &str) -> Option<String> {
+    let stores = kind.stores(metadata);
+
+    stores
+        .iter()
+        .find(|(id, _)| *id == id_or_name)
+        .or_else(|| stores.iter().find(|(_, name)| *name == id_or_name))
+        .map(|(id, _)| id.to_string())
+}
+
+/// Find the ID of a soft deleted store from its ID or name, preferring the latest deleted of
+/// the stores with that name.
+fn find_deleted_store_id(metadata: &Metadata, kind: StoreKind, id_or_name: &str) -> Option<String> {
+    let deleted = kind
+        .all_stores(metadata)
+        .into_iter()
+        .filter_map(|(id, name, deleted_at)| Some((id, name, deleted_at?)))
+        .collect::<Vec<_>>();
+
+    deleted
+        .iter()
+        .find(|(id, _, _)| *id == id_or_name)
+        .or_else(|| {
+            deleted
+                .iter()
+                .filter(|(_, name, _)| *name == id_or_name)
+                .max_by_key(|(_, _, deleted_at)| *deleted_at)
+        })
+        .map(|(id, _, _)| id.to_string())
+}
+
+#[derive(Debug, Clone, Default, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListStoresQuery {
+    /// Also list soft deleted stores
+    #[serde(default)]
+    include_deleted: bool,
+}
+
+/// Resolve a store path parameter to the ID of an existing store, failing with a 404 otherwise.
+fn resolve_store_id(ctx: &Context, kind: StoreKind, id_or_name: String) -> Result<String> {
+    let tx = ctx.db.begin_read()?;
+
+    let metadata_table = match tx.open_table(METADATA_TABLE) {
+        Ok(table) => table,
+        Err(redb::TableError::TableDoesNotExist(_)) => return Err(kind.not_found()),
+        Err(e) => return Err(e.into()),
+    };
+    let Some(metadata_record) = metadata_table.get(&())? else {
+        return Err(kind.not_found());
+    };
+    let metadata = &metadata_record.value().0;
+
+    find_store_id(metadata, kind, &id_or_name).ok_or_else(|| kind.not_found())
+}
+
+/// Like [`resolve_store_id`], but checked in the transaction about to write to the store, so
+/// writes can't recreate the table of a store deleted in the meantime.
+fn resolve_store_id_for_write(
+    tx: &redb::WriteTransaction,
+    kind: StoreKind,
+    id_or_name: String,
+) -> Result<String> {
+    let metadata_table = tx.open_table(METADATA_TABLE)?;
+    let Some(metadata_record) = metadata_table.get(&())? else {
+        return Err(kind.not_found());
+    };
+    let metadata = &metadata_record.value().0;
+
+    find_store_id(metadata, kind, &id_or_name).ok_or_else(|| kind.not_found())
+}
+
+/// Item statistics of a store, as returned by Fastly's config store `/info` endpoint.
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+struct StoreInfo {
+    item_count: u64,
+    /// Total size of the keys and values, in bytes
+    total_size: u64,
+}
+
+fn store_info(ctx: &Context, kind: StoreKind, id_or_name: String) -> Result<StoreInfo> {
+    let tx = ctx.db.begin_read()?;
+
+    let metadata_table = match tx.open_table(METADATA_TABLE) {
+        Ok(table) => table,
+        Err(redb::TableError::TableDoesNotExist(_)) => return Err(kind.not_found()),
+        Err(e) => return Err(e.into()),
+    };
+    let Some(metadata_record) = metadata_table.get(&())? else {
+        return Err(kind.not_found());
+    };
+    let metadata = metadata_record.value().0;
+
+    let id = find_store_id(&metadata, kind, &id_or_name).ok_or_else(|| kind.not_found())?;
+    let mut stats = metadata.store_stats(&id).ok_or_else(|| kind.not_found())?;
+    if kind == StoreKind::KV {
+        stats = crate::kv::live_stats(&tx, &id, stats, chrono::Utc::now())?;
+    }
+
+    Ok(StoreInfo {
+        item_count: stats.item_count,
+        total_size: stats.total_size,
+    })
+}
+
+/// Fail with a 409 when a store of this kind already has the name.
+fn ensure_unique_name(metadata: &Metadata, kind: StoreKind, name: &str) -> Result<()> {
+    if kind
+        .stores(metadata)
+        .iter()
+        .any(|(_, store_name)| *store_name == name)
+    {
+        return Err(Error::builder()
+            .conflict()
+            .message("A store with this name already exists")
+            .build());
+    }
+
+    Ok(())
+}
+
+/// Apply the outcome of a limit check: kept as an error with `--strict-limits`, otherwise only
+/// logged so writes that would fail on Fastly still go through locally.
+fn check_limit(
+    ctx: &Context,
+    result: std::result::Result<(), limits::LimitError>,
+) -> std::result::Result<(), limits::LimitError> {
+    match result {
+        Err(err) if !ctx.strict_limits => {
+            tracing::warn!("Write exceeds Fastly store limits: {err}");
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Like [`check_limit`], failing with a 400.
+fn enforce_limit(ctx: &Context, result: std::result::Result<(), limits::LimitError>) -> Result<()> {
+    check_limit(ctx, result).map_err(|err| {
+        Error::builder()
+            .bad_request()
+            .message(err.to_string())
+            .build()
+    })
+}
@@ -0,0 +1,166 @@
+use axum::extract::{Json, State};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::api::openapi::documented_routes;
+use crate::api::{Context, Result};
+
+/// How long clients are told they can use a client key
+const CLIENT_KEY_LIFETIME: chrono::TimeDelta = chrono::TimeDelta::hours(1);
+
+documented_routes! {
+    create_client_key,
+    get_signing_key,
+}
+
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+struct ClientKey {
+    /// X25519 key to seal secrets with
+    #[serde(with = "serde_with::As::<serde_with::base64::Base64>")]
+    #[schema(value_type = String, format = Byte)]
+    public_key: Vec<u8>,
+    #[serde(with = "serde_with::As::<serde_with::base64::Base64>")]
+    #[schema(value_type = String, format = Byte)]
+    signature: Vec<u8>,
+    expires_at: DateTime<Utc>,
+}
+
+/// Get a key to seal secrets with, as done by `fastly secret-store-entry create`.
+///
+/// Fastly creates a new key on each call; the dev-server hands out the same key, generated at
+/// startup.
+#[utoipa::path(
+    post,
+    path = "/client-key",
+    tag = "Secret stores",
+    responses(
+        (status = 200, body = ClientKey),
+    )
+)]
+async fn create_client_key(State(ctx): State<Context>) -> Result<Json<ClientKey>> {
+    Ok(Json(ClientKey {
+        public_key: ctx.client_keys.client_key().as_bytes().to_vec(),
+        signature: ctx.client_keys.client_key_signature().to_vec(),
+        expires_at: Utc::now() + CLIENT_KEY_LIFETIME,
+    }))
+}
+
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+struct SigningKey {
+    #[serde(with = "serde_with::As::<serde_with::base64::Base64>")]
+    #[schema(value_type = String, format = Byte)]
+    signing_key: Vec<u8>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/signing-key",
+    tag = "Secret stores",
+    responses(
+        (status = 200, body = SigningKey),
+    )
+)]
+async fn get_signing_key(State(ctx): State<Context>) -> Result<Json<SigningKey>> {
+    Ok(Json(SigningKey {
+        signing_key: ctx.client_keys.signing_key().to_vec(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use http::Method;
+    use redb::ReadableDatabase;
+
+    use crate::api::testing::{self, send};
+    use crate::tables::SecretStoreTable;
+
+    #[tokio::test]
+    async fn sealed_secrets_round_trip() {
+        use crypto_box::PublicKey;
+        use crypto_box::aead::OsRng;
+        use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+        let ctx = testing::context();
+        let app = testing::app(ctx.clone());
+        let store_id = testing::create_store(&app, "secret", "store").await;
+
+        let response = send(
+            &app,
+            Method::POST,
+            "/resources/stores/secret/client-key",
+            &[],
+            "",
+        )
+        .await;
+        assert_eq!(response.status, 200);
+        let client_key = response.json();
+        let public_key = STANDARD
+            .decode(client_key["public_key"].as_str().unwrap())
+            .unwrap();
+        let signature = STANDARD
+            .decode(client_key["signature"].as_str().unwrap())
+            .unwrap();
+
+        // Checked like the CLI does before sealing anything.
+        let response = send(
+            &app,
+            Method::GET,
+            "/resources/stores/secret/signing-key",
+            &[],
+            "",
+        )
+        .await;
+        let signing_key = STANDARD
+            .decode(response.json()["signing_key"].as_str().unwrap())
+            .unwrap();
+        let signing_key = VerifyingKey::from_bytes(&signing_key.try_into().unwrap()).unwrap();
+        let signature = Signature::from_bytes(&signature.try_into().unwrap());
+        signing_key.verify(&public_key, &signature).unwrap();
+
+        let public_key = PublicKey::from_slice(&public_key).unwrap();
+        let sealed = public_key.seal(&mut OsRng, b"hunter2").unwrap();
+        let body = serde_json::json!({
+            "name": "password",
+            "secret": STANDARD.encode(sealed),
+            "client_key": STANDARD.encode(public_key.as_bytes()),
+        });
+        let uri = format!("/resources/stores/secret/{store_id}/secrets");
+        let response = send(&app, Method::POST, &uri, &[], body.to_string()).await;
+        assert_eq!(response.status, 200, "{:?}", response.body);
+
+        let tx = ctx.db.begin_read().unwrap();
+        let table = tx.open_table(SecretStoreTable::new(&store_id)).unwrap();
+        let item = table
+            .get("password".to_string())
+            .unwrap()
+            .unwrap()
+            .value()
+            .0;
+        let secret = ctx
+            .master_key
+            .open(&item.secret, item.envelope.as_ref().unwrap())
+            .unwrap();
+        assert_eq!(secret, b"hunter2");
+    }
+
+    #[tokio::test]
+    async fn secrets_sealed_with_another_key_are_rejected() {
+        use crypto_box::SecretKey;
+        use crypto_box::aead::OsRng;
+
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "secret", "store").await;
+
+        let other_key = SecretKey::generate(&mut OsRng).public_key();
+        let body = serde_json::json!({
+            "name": "password",
+            "secret": STANDARD.encode(other_key.seal(&mut OsRng, b"hunter2").unwrap()),
+            "client_key": STANDARD.encode(other_key.as_bytes()),
+        });
+        let uri = format!("/resources/stores/secret/{store_id}/secrets");
+        let response = send(&app, Method::POST, &uri, &[], body.to_string()).await;
+        assert_eq!(response.status, 400);
+    }
+}
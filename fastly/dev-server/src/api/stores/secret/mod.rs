@@ -1,42 +1,59 @@
-use axum::extract::{Json, Path, State};
+use axum::extract::{Json, Path, Query, State};
 use chrono::{DateTime, Utc};
 use redb::{ReadableDatabase, ReadableTable};
 use serde::{Deserialize, Serialize};
 
-use crate::api::{Context, Result, Router, error::Error};
-use crate::tables::{METADATA_TABLE, SecretStoreMetadata, SecretStoreTable};
+use super::{
+    ListStoresQuery, StoreInfo, StoreKind, ensure_unique_name, find_deleted_store_id,
+    find_store_id, store_info,
+};
+use crate::api::openapi::documented_routes;
+use crate::api::{Context, Result, error::Error};
+use crate::tables::{METADATA_TABLE, SecretStoreMetadata, StoreStats};
 use crate::util::JsonRecord;
 
+mod keys;
 mod secrets;
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
 struct SecretStore {
     id: String,
     name: String,
     created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<DateTime<Utc>>,
 }
 
-pub fn router() -> Router {
-    use axum::routing;
-
-    Router::new()
-        .route(
-            "/",
-            routing::get(list_secret_stores).post(create_secret_store),
-        )
-        .route(
-            "/{id}",
-            routing::get(get_secret_store).delete(delete_secret_store),
-        )
-        .merge(secrets::router())
+documented_routes! {
+    list_secret_stores,
+    create_secret_store,
+    get_secret_store,
+    update_secret_store,
+    delete_secret_store,
+    get_secret_store_info,
+    undelete_secret_store;
+    merge(keys, secrets)
 }
 
-#[derive(Debug, Clone, Default, Serialize)]
+#[derive(Debug, Clone, Default, Serialize, utoipa::ToSchema)]
 struct SecretStoreList {
     data: Vec<SecretStore>,
 }
 
-async fn list_secret_stores(State(ctx): State<Context>) -> Result<Json<SecretStoreList>> {
+#[utoipa::path(
+    get,
+    path = "",
+    tag = "Secret stores",
+    params(ListStoresQuery),
+    responses(
+        (status = 200, body = SecretStoreList),
+    )
+)]
+async fn list_secret_stores(
+    State(ctx): State<Context>,
+    Query(query): Query<ListStoresQuery>,
+) -> Result<Json<SecretStoreList>> {
     let tx = ctx.db.begin_read()?;
 
     let metadata_table = match tx.open_table(METADATA_TABLE) {
@@ -54,21 +71,33 @@ async fn list_secret_stores(State(ctx): State<Context>) -> Result<Json<SecretSto
     let entries = metadata
         .secret_stores
         .iter()
+        .filter(|(_, store_meta)| query.include_deleted || store_meta.deleted_at.is_none())
         .map(|(id, store_meta)| SecretStore {
             id: id.clone(),
             name: store_meta.name.clone(),
             created_at: store_meta.created_at,
+            updated_at: store_meta.updated_at.unwrap_or(store_meta.created_at),
+            deleted_at: store_meta.deleted_at,
         })
         .collect::<Vec<SecretStore>>();
 
     Ok(Json(SecretStoreList { data: entries }))
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, utoipa::ToSchema)]
 pub struct CreateSecretStoreRequest {
     pub name: String,
 }
 
+#[utoipa::path(
+    post,
+    path = "",
+    tag = "Secret stores",
+    request_body = CreateSecretStoreRequest,
+    responses(
+        (status = 200, body = SecretStore),
+    )
+)]
 async fn create_secret_store(
     State(ctx): State<Context>,
     Json(payload): Json<CreateSecretStoreRequest>,
@@ -82,12 +111,17 @@ async fn create_secret_store(
             .map(|record| record.value().0.clone())
             .unwrap_or_default();
 
+        ensure_unique_name(&metadata, StoreKind::Secret, &payload.name)?;
+
         let now = Utc::now();
         let id = ulid::Ulid::new().to_string();
 
         let store_meta = SecretStoreMetadata {
             name: payload.name.clone(),
+            stats: Some(StoreStats::default()),
             created_at: now,
+            updated_at: Some(now),
+            deleted_at: None,
         };
         metadata.secret_stores.insert(id.clone(), store_meta);
 
@@ -97,6 +131,8 @@ async fn create_secret_store(
             id,
             name: payload.name,
             created_at: now,
+            updated_at: now,
+            deleted_at: None,
         }
     };
 
@@ -105,6 +141,15 @@ async fn create_secret_store(
     Ok(Json(store))
 }
 
+#[utoipa::path(
+    get,
+    path = "/{id}",
+    tag = "Secret stores",
+    params(("id" = String, Path, description = "Store ID or name")),
+    responses(
+        (status = 200, body = SecretStore),
+    )
+)]
 async fn get_secret_store(
     State(ctx): State<Context>,
     axum::extract::Path(id): axum::extract::Path<String>,
@@ -129,7 +174,13 @@ async fn get_secret_store(
     };
     let metadata = &metadata_record.value().0;
 
-    let store_meta = match metadata.secret_stores.get(&id) {
+    let id = find_store_id(metadata, StoreKind::Secret, &id).unwrap_or(id);
+
+    let store_meta = match metadata
+        .secret_stores
+        .get(&id)
+        .filter(|meta| meta.deleted_at.is_none())
+    {
         Some(meta) => meta,
         None => {
             return Err(Error::builder()
@@ -143,9 +194,152 @@ async fn get_secret_store(
         id: id.clone(),
         name: store_meta.name.clone(),
         created_at: store_meta.created_at,
+        updated_at: store_meta.updated_at.unwrap_or(store_meta.created_at),
+        deleted_at: None,
     }))
 }
 
+#[derive(Debug, Clone, Deserialize, utoipa::ToSchema)]
+pub struct UpdateSecretStoreRequest {
+    pub name: String,
+}
+
+#[utoipa::path(
+    put,
+    path = "/{id}",
+    tag = "Secret stores",
+    params(("id" = String, Path, description = "Store ID or name")),
+    request_body = UpdateSecretStoreRequest,
+    responses(
+        (status = 200, body = SecretStore),
+    )
+)]
+async fn update_secret_store(
+    State(ctx): State<Context>,
+    Path(id): Path<String>,
+    Json(payload): Json<UpdateSecretStoreRequest>,
+) -> Result<Json<SecretStore>> {
+    let tx = ctx.db.begin_write()?;
+
+    let store = {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let mut metadata = metadata_table
+            .get(&())?
+            .map(|record| record.value().0.clone())
+            .unwrap_or_default();
+
+        let Some(id) = find_store_id(&metadata, StoreKind::Secret, &id) else {
+            return Err(StoreKind::Secret.not_found());
+        };
+
+        if payload.name != metadata.secret_stores[&id].name {
+            ensure_unique_name(&metadata, StoreKind::Secret, &payload.name)?;
+        }
+
+        let store_meta = metadata
+            .secret_stores
+            .get_mut(&id)
+            .expect("Store was just found");
+        store_meta.name = payload.name;
+        store_meta.updated_at = Some(Utc::now());
+
+        let store = SecretStore {
+            id,
+            name: store_meta.name.clone(),
+            created_at: store_meta.created_at,
+            updated_at: store_meta.updated_at.unwrap_or(store_meta.created_at),
+            deleted_at: None,
+        };
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+
+        store
+    };
+
+    tx.commit()?;
+
+    Ok(Json(store))
+}
+
+#[utoipa::path(
+    get,
+    path = "/{id}/info",
+    tag = "Secret stores",
+    params(("id" = String, Path, description = "Store ID or name")),
+    responses(
+        (status = 200, body = StoreInfo),
+    )
+)]
+async fn get_secret_store_info(
+    State(ctx): State<Context>,
+    Path(id): Path<String>,
+) -> Result<Json<StoreInfo>> {
+    store_info(&ctx, StoreKind::Secret, id).map(Json)
+}
+
+/// Restore a soft deleted store, unless another store took its name in the meantime.
+#[utoipa::path(
+    post,
+    path = "/{id}/undelete",
+    tag = "Secret stores",
+    params(("id" = String, Path, description = "Store ID or name")),
+    responses(
+        (status = 200, body = SecretStore),
+    )
+)]
+async fn undelete_secret_store(
+    State(ctx): State<Context>,
+    Path(id): Path<String>,
+) -> Result<Json<SecretStore>> {
+    let tx = ctx.db.begin_write()?;
+
+    let store = {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let mut metadata = metadata_table
+            .get(&())?
+            .map(|record| record.value().0.clone())
+            .unwrap_or_default();
+
+        let Some(id) = find_deleted_store_id(&metadata, StoreKind::Secret, &id) else {
+            return Err(StoreKind::Secret.not_found());
+        };
+
+        let name = metadata.secret_stores[&id].name.clone();
+        ensure_unique_name(&metadata, StoreKind::Secret, &name)?;
+
+        let store_meta = metadata
+            .secret_stores
+            .get_mut(&id)
+            .expect("Store was just found");
+        store_meta.deleted_at = None;
+
+        let store = SecretStore {
+            id,
+            name: store_meta.name.clone(),
+            created_at: store_meta.created_at,
+            updated_at: store_meta.updated_at.unwrap_or(store_meta.created_at),
+            deleted_at: None,
+        };
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+
+        store
+    };
+
+    tx.commit()?;
+
+    Ok(Json(store))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/{id}",
+    tag = "Secret stores",
+    params(("id" = String, Path, description = "Store ID or name")),
+    responses(
+        (status = 200, description = "Store deleted"),
+    )
+)]
 async fn delete_secret_store(State(ctx): State<Context>, Path(id): Path<String>) -> Result<()> {
     let tx = ctx.db.begin_write()?;
 
@@ -156,19 +350,48 @@ async fn delete_secret_store(State(ctx): State<Context>, Path(id): Path<String>)
             .map(|record| record.value().0.clone())
             .unwrap_or_default();
 
-        if metadata.secret_stores.remove(&id).is_none() {
+        let store_meta = find_store_id(&metadata, StoreKind::Secret, &id)
+            .and_then(|id| metadata.secret_stores.get_mut(&id));
+        let Some(store_meta) = store_meta else {
             return Err(Error::builder()
                 .not_found()
                 .message("Secret store not found")
                 .build());
-        }
+        };
+
+        // The table is kept until the store is purged, so the store can be restored.
+        store_meta.deleted_at = Some(Utc::now());
 
         metadata_table.insert(&(), &JsonRecord(metadata))?;
     }
 
-    tx.delete_table(SecretStoreTable::new(&id))?;
-
     tx.commit()?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use http::Method;
+
+    use crate::api::testing::{self, send};
+
+    #[tokio::test]
+    async fn renaming_updates_updated_at() {
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "secret", "store").await;
+        let uri = format!("/resources/stores/secret/{store_id}");
+
+        let created = send(&app, Method::GET, &uri, &[], "").await.json();
+        assert_eq!(created["updated_at"], created["created_at"]);
+
+        let body = serde_json::json!({ "name": "renamed" }).to_string();
+        let renamed = send(&app, Method::PUT, &uri, &[], body).await.json();
+        assert_eq!(renamed["name"], "renamed");
+        assert_eq!(renamed["created_at"], created["created_at"]);
+        let updated_at = |store: &serde_json::Value| {
+            chrono::DateTime::parse_from_rfc3339(store["updated_at"].as_str().unwrap()).unwrap()
+        };
+        assert!(updated_at(&renamed) > updated_at(&created));
+    }
+}
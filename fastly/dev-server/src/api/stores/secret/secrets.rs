@@ -4,43 +4,60 @@ use chrono::{DateTime, Utc};
 use redb::{ReadableDatabase, ReadableTable};
 use serde::{Deserialize, Serialize};
 
-use crate::api::{Context, Result, Router, error::Error};
-use crate::tables::{SecretStoreItemMetadata, SecretStoreTable as TableDefinition};
+use crate::api::openapi::documented_routes;
+use crate::api::stores::limits;
+use crate::api::stores::{StoreKind, enforce_limit, resolve_store_id, resolve_store_id_for_write};
+use crate::api::{Context, Result, error::Error};
+use crate::tables::{SecretStoreItemMetadata, SecretStoreTable as TableDefinition, StatsDelta};
 use crate::util::JsonRecord;
 
-pub fn router() -> Router {
-    use axum::routing;
+/// Type of every secret store entry
+const SECRET_TYPE: &str = "secret";
 
-    Router::new()
-        .route(
-            "/{store_id}/secrets",
-            routing::get(list_secrets)
-                .post(create_secret)
-                .put(recreate_secret),
-        )
-        .route(
-            "/{store_id}/secrets/{secret_name}",
-            routing::get(get_secret).delete(delete_secret),
-        )
+documented_routes! {
+    list_secrets,
+    create_secret,
+    create_or_recreate_secret,
+    recreate_secret,
+    get_secret,
+    delete_secret,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
 struct Secret {
     name: String,
     #[serde(with = "serde_with::As::<serde_with::base64::Base64>")]
+    #[schema(value_type = String, format = Byte)]
     digest: Bytes,
     created_at: DateTime<Utc>,
+    /// Kind of the entry, always `secret`
+    #[serde(rename = "type")]
+    secret_type: &'static str,
+    /// Whether a write replaced an existing secret, only set in responses to writes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recreated: Option<bool>,
 }
 
-#[derive(Debug, Clone, Default, Serialize)]
+#[derive(Debug, Clone, Default, Serialize, utoipa::ToSchema)]
 struct SecretListResponse {
     data: Vec<Secret>,
 }
 
+#[utoipa::path(
+    get,
+    path = "/{store_id}/secrets",
+    tag = "Secret stores",
+    params(("store_id" = String, Path, description = "Store ID or name")),
+    responses(
+        (status = 200, body = SecretListResponse),
+    )
+)]
 async fn list_secrets(
     Path(store_id): Path<String>,
     State(ctx): State<Context>,
 ) -> Result<Json<SecretListResponse>> {
+    let store_id = resolve_store_id(&ctx, StoreKind::Secret, store_id)?;
+
     let tx = ctx.db.begin_read()?;
 
     let definition = TableDefinition::new(&store_id);
@@ -62,8 +79,10 @@ async fn list_secrets(
 
             Secret {
                 name,
-                digest: compute_digest(&item.secret),
+                digest: digest(&ctx, &store_id, &item),
                 created_at: item.created_at,
+                secret_type: SECRET_TYPE,
+                recreated: None,
             }
         })
         .collect::<Vec<Secret>>();
@@ -71,102 +90,227 @@ async fn list_secrets(
     Ok(Json(SecretListResponse { data: entries }))
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, utoipa::ToSchema)]
 struct CreateSecretRequest {
     name: String,
+    /// The secret, or its base64 encoded sealed box when `client_key` is set
     secret: String,
+    /// Client key the secret was sealed with, as done by the Fastly CLI
+    #[serde(default, with = "serde_with::As::<Option<serde_with::base64::Base64>>")]
+    #[schema(value_type = Option<String>, format = Byte)]
+    client_key: Option<Vec<u8>>,
+}
+
+impl CreateSecretRequest {
+    /// Plaintext of the secret, unsealing it if needed.
+    fn secret(&self, ctx: &Context) -> Result<Bytes> {
+        use base64::Engine;
+        use base64::engine::general_purpose::STANDARD;
+
+        let Some(client_key) = &self.client_key else {
+            return Ok(Bytes::from(self.secret.as_bytes().to_vec()));
+        };
+
+        let sealed = STANDARD.decode(&self.secret).map_err(|_| {
+            Error::builder()
+                .bad_request()
+                .message("Sealed secret must be base64 encoded")
+                .build()
+        })?;
+        let secret = ctx.client_keys.unseal(client_key, &sealed).map_err(|err| {
+            Error::builder()
+                .bad_request()
+                .message(err.to_string())
+                .build()
+        })?;
+
+        Ok(Bytes::from(secret))
+    }
 }
 
+/// How a write treats an existing secret with the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "kebab-case")]
+enum WriteMethod {
+    /// Fail if the secret exists
+    Create,
+    /// Fail unless the secret exists
+    Recreate,
+    CreateOrRecreate,
+}
+
+#[utoipa::path(
+    post,
+    path = "/{store_id}/secrets",
+    tag = "Secret stores",
+    params(("store_id" = String, Path, description = "Store ID or name")),
+    request_body = CreateSecretRequest,
+    responses(
+        (status = 200, body = Secret),
+    )
+)]
 async fn create_secret(
     Path(store_id): Path<String>,
     State(ctx): State<Context>,
     Json(payload): Json<CreateSecretRequest>,
 ) -> Result<Json<Secret>> {
-    let tx = ctx.db.begin_write()?;
-
-    let secret = {
-        let definition = TableDefinition::new(&store_id);
-
-        let mut table = tx.open_table(definition)?;
-
-        // Check if secret already exists
-        if table.get(&payload.name)?.is_some() {
-            return Err(Error::builder()
-                .conflict()
-                .message("Secret with this name already exists. Use PUT to recreate it.")
-                .build());
-        }
-
-        let now = Utc::now();
-
-        let meta = SecretStoreItemMetadata {
-            name: payload.name.clone(),
-            secret: Bytes::from(payload.secret.as_bytes().to_vec()),
-            created_at: now,
-        };
-
-        table.insert(&payload.name, &JsonRecord(meta.clone()))?;
-
-        Secret {
-            name: payload.name,
-            digest: compute_digest(&meta.secret),
-            created_at: meta.created_at,
-        }
-    };
+    write_secret(&ctx, store_id, payload, WriteMethod::Create).map(Json)
+}
 
-    tx.commit()?;
+#[utoipa::path(
+    put,
+    path = "/{store_id}/secrets",
+    tag = "Secret stores",
+    params(("store_id" = String, Path, description = "Store ID or name")),
+    request_body = CreateSecretRequest,
+    responses(
+        (status = 200, body = Secret),
+    )
+)]
+async fn create_or_recreate_secret(
+    Path(store_id): Path<String>,
+    State(ctx): State<Context>,
+    Json(payload): Json<CreateSecretRequest>,
+) -> Result<Json<Secret>> {
+    write_secret(&ctx, store_id, payload, WriteMethod::CreateOrRecreate).map(Json)
+}
 
-    Ok(Json(secret))
+#[derive(Debug, Clone, Deserialize, utoipa::ToSchema)]
+struct RecreateSecretRequest {
+    #[serde(flatten)]
+    secret: CreateSecretRequest,
+    /// Only `recreate` and `create-or-recreate` are accepted
+    #[serde(default)]
+    method: Option<WriteMethod>,
 }
 
+#[utoipa::path(
+    patch,
+    path = "/{store_id}/secrets",
+    tag = "Secret stores",
+    params(("store_id" = String, Path, description = "Store ID or name")),
+    request_body = RecreateSecretRequest,
+    responses(
+        (status = 200, body = Secret),
+    )
+)]
 async fn recreate_secret(
     Path(store_id): Path<String>,
     State(ctx): State<Context>,
-    Json(payload): Json<CreateSecretRequest>,
+    Json(payload): Json<RecreateSecretRequest>,
 ) -> Result<Json<Secret>> {
+    let method = payload.method.unwrap_or(WriteMethod::Recreate);
+    if method == WriteMethod::Create {
+        return Err(Error::builder()
+            .bad_request()
+            .message("Method must be `recreate` or `create-or-recreate`")
+            .build());
+    }
+
+    write_secret(&ctx, store_id, payload.secret, method).map(Json)
+}
+
+fn write_secret(
+    ctx: &Context,
+    store_id: String,
+    payload: CreateSecretRequest,
+    method: WriteMethod,
+) -> Result<Secret> {
+    let secret = payload.secret(ctx)?;
+    enforce_limit(ctx, limits::check_secret(&payload.name, &secret))?;
+
     let tx = ctx.db.begin_write()?;
 
-    let secret = {
+    let store_id = resolve_store_id_for_write(&tx, StoreKind::Secret, store_id)?;
+
+    let (secret, stats) = {
         let definition = TableDefinition::new(&store_id);
 
         let mut table = tx.open_table(definition)?;
 
+        let exists = table.get(&payload.name)?.is_some();
+        match method {
+            WriteMethod::Create if exists => {
+                return Err(Error::builder()
+                    .conflict()
+                    .message("Secret with this name already exists. Use PUT to recreate it.")
+                    .build());
+            }
+            WriteMethod::Recreate if !exists => {
+                return Err(Error::builder()
+                    .not_found()
+                    .message("Secret not found. Use PUT to create it.")
+                    .build());
+            }
+            _ => {}
+        }
+
         let now = Utc::now();
 
+        let (ciphertext, envelope) = ctx.master_key.seal(&secret);
         let meta = SecretStoreItemMetadata {
             name: payload.name.clone(),
-            secret: Bytes::from(payload.secret.as_bytes().to_vec()),
+            secret: ciphertext,
+            envelope: Some(envelope),
+            digest: Some(ctx.master_key.secret_digest(&store_id, &secret)),
+            digest_key_id: Some(ctx.master_key.id().to_string()),
             created_at: now,
         };
 
-        table.insert(&payload.name, &JsonRecord(meta.clone()))?;
+        let previous = table
+            .insert(&payload.name, &JsonRecord(meta.clone()))?
+            .map(|record| record.value().0.size());
+
+        let mut stats = StatsDelta::default();
+        stats.write(previous, meta.size());
 
-        Secret {
+        let secret = Secret {
             name: payload.name,
-            digest: compute_digest(&meta.secret),
+            digest: digest(ctx, &store_id, &meta),
             created_at: meta.created_at,
-        }
+            secret_type: SECRET_TYPE,
+            recreated: Some(previous.is_some()),
+        };
+
+        (secret, stats)
     };
 
+    stats.commit(&tx, &store_id)?;
+
     tx.commit()?;
 
-    Ok(Json(secret))
+    Ok(secret)
 }
 
+#[utoipa::path(
+    get,
+    path = "/{store_id}/secrets/{secret_name}",
+    tag = "Secret stores",
+    params(
+        ("store_id" = String, Path, description = "Store ID or name"),
+        ("secret_name" = String, Path),
+    ),
+    responses(
+        (status = 200, body = Secret),
+    )
+)]
 async fn get_secret(
     Path((store_id, secret_name)): Path<(String, String)>,
     State(ctx): State<Context>,
 ) -> Result<Json<Secret>> {
+    let store_id = resolve_store_id(&ctx, StoreKind::Secret, store_id)?;
+
     let tx = ctx.db.begin_read()?;
 
     let definition = TableDefinition::new(&store_id);
 
     let table = match tx.open_table(definition) {
         Ok(table) => table,
+        // The store exists but has never been written to.
         Err(redb::TableError::TableDoesNotExist(_)) => {
             return Err(Error::builder()
                 .not_found()
-                .message("Secret store not found")
+                .message("Secret not found")
                 .build());
         }
         Err(e) => return Err(e.into()),
@@ -185,46 +329,101 @@ async fn get_secret(
 
     let secret = Secret {
         name: secret_name,
-        digest: compute_digest(&item.secret),
+        digest: digest(&ctx, &store_id, &item),
         created_at: item.created_at,
+        secret_type: SECRET_TYPE,
+        recreated: None,
     };
 
     Ok(Json(secret))
 }
 
+#[utoipa::path(
+    delete,
+    path = "/{store_id}/secrets/{secret_name}",
+    tag = "Secret stores",
+    params(
+        ("store_id" = String, Path, description = "Store ID or name"),
+        ("secret_name" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Secret deleted"),
+    )
+)]
 async fn delete_secret(
     Path((store_id, secret_name)): Path<(String, String)>,
     State(ctx): State<Context>,
 ) -> Result<()> {
     let tx = ctx.db.begin_write()?;
 
-    {
+    let store_id = resolve_store_id_for_write(&tx, StoreKind::Secret, store_id)?;
+
+    let stats = {
         let definition = TableDefinition::new(&store_id);
 
         let mut table = tx.open_table(definition)?;
 
-        if table.remove(&secret_name)?.is_none() {
+        let Some(record) = table.remove(&secret_name)? else {
             return Err(Error::builder()
                 .not_found()
                 .message("Secret not found")
                 .build());
-        }
-    }
+        };
+
+        let mut stats = StatsDelta::default();
+        stats.remove(record.value().0.size());
+
+        stats
+    };
+
+    stats.commit(&tx, &store_id)?;
 
     tx.commit()?;
 
     Ok(())
 }
 
-/// Compute a digest (opaque identifier) of the plaintext secret value
-/// This allows determining if a secret value has changed without exposing the plaintext
-fn compute_digest(secret: &Bytes) -> Bytes {
-    use sha2::{Digest, Sha256};
+/// Digest (opaque identifier) of the plaintext secret value, which allows determining if a
+/// secret value has changed without exposing the plaintext.
+fn digest(ctx: &Context, store_id: &str, item: &SecretStoreItemMetadata) -> Bytes {
+    match &item.digest {
+        Some(digest) => digest.clone(),
+        // Secrets stored before encryption at rest are in plaintext.
+        None => ctx.master_key.secret_digest(store_id, &item.secret),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{Method, StatusCode};
 
-    let mut hasher = Sha256::new();
-    hasher.update(secret);
-    let result = hasher.finalize();
+    use crate::api::testing::{self, send};
 
-    // Return as bytes
-    Bytes::from(result.to_vec())
+    #[tokio::test]
+    async fn digests_only_change_with_the_value_and_the_store() {
+        let app = testing::app(testing::context());
+        let store_id = testing::create_store(&app, "secret", "store").await;
+        let other_id = testing::create_store(&app, "secret", "other").await;
+
+        let put = async |store_id: &str, secret: &str| {
+            let body = serde_json::json!({ "name": "a", "secret": secret }).to_string();
+            let uri = format!("/resources/stores/secret/{store_id}/secrets");
+            let response = send(&app, Method::PUT, &uri, &[], body).await;
+            assert_eq!(response.status, StatusCode::OK, "{:?}", response.body);
+            response.json()
+        };
+
+        let created = put(&store_id, "value").await;
+        assert_eq!(created["type"], "secret");
+        let recreated = put(&store_id, "value").await;
+        assert_eq!(recreated["recreated"], true);
+        assert_eq!(recreated["digest"], created["digest"]);
+
+        assert_ne!(put(&store_id, "changed").await["digest"], created["digest"]);
+        assert_ne!(put(&other_id, "value").await["digest"], created["digest"]);
+
+        let uri = format!("/resources/stores/secret/{store_id}/secrets/a");
+        let secret = send(&app, Method::GET, &uri, &[], "").await.json();
+        assert_eq!(secret["type"], "secret");
+    }
 }
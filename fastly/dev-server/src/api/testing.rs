@@ -0,0 +1,157 @@
+//! Helpers for exercising the API router in tests.
+
+use std::sync::Arc;
+
+use axum::body::Body;
+use bytes::Bytes;
+use http::{HeaderMap, Method, Request, StatusCode};
+use tower::ServiceExt;
+
+use redb::Database;
+
+use super::Context;
+use crate::crypto::{ClientKeys, MasterKey};
+
+/// Context over an empty in-memory database, with every option off.
+pub(in crate::api) fn context() -> Context {
+    Context {
+        db: crate::context::memory_db(),
+        strict_limits: false,
+        require_auth: false,
+        rate_limiter: None,
+        client_keys: Arc::new(ClientKeys::generate()),
+        master_key: Arc::new(MasterKey::generate()),
+    }
+}
+
+/// The API router, as served.
+pub(in crate::api) fn app(ctx: Context) -> axum::Router {
+    super::router(&ctx).with_state(ctx)
+}
+
+/// The API router over a database, for checking what other parts of the server see of it.
+pub fn app_with_db(db: Arc<Database>) -> axum::Router {
+    app(Context { db, ..context() })
+}
+
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl Response {
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).expect("Response body is not JSON")
+    }
+}
+
+/// Send a request, with a JSON content type unless another is given.
+pub async fn send(
+    app: &axum::Router,
+    method: Method,
+    uri: &str,
+    headers: &[(&str, &str)],
+    body: impl Into<Body>,
+) -> Response {
+    let mut request = Request::builder().method(method).uri(uri);
+    if !headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+    {
+        request = request.header("content-type", "application/json");
+    }
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+
+    let response = app
+        .clone()
+        .oneshot(request.body(body.into()).unwrap())
+        .await
+        .unwrap();
+
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+
+    Response {
+        status,
+        headers,
+        body,
+    }
+}
+
+/// Create a store of a kind (`config`, `kv` or `secret`), returning its ID.
+pub async fn create_store(app: &axum::Router, kind: &str, name: &str) -> String {
+    let response = match kind {
+        "config" => {
+            send(
+                app,
+                Method::POST,
+                "/resources/stores/config",
+                &[("content-type", "application/x-www-form-urlencoded")],
+                format!("name={name}"),
+            )
+            .await
+        }
+        _ => {
+            send(
+                app,
+                Method::POST,
+                &format!("/resources/stores/{kind}"),
+                &[],
+                serde_json::json!({ "name": name }).to_string(),
+            )
+            .await
+        }
+    };
+    assert_eq!(response.status, StatusCode::OK, "{:?}", response.body);
+
+    response.json()["id"].as_str().unwrap().to_string()
+}
+
+/// Create a service, returning its ID. It starts with an editable version 1.
+pub async fn create_service(app: &axum::Router, name: &str) -> String {
+    let response = send(
+        app,
+        Method::POST,
+        "/service",
+        &[("content-type", "application/x-www-form-urlencoded")],
+        format!("name={name}"),
+    )
+    .await;
+    assert_eq!(response.status, StatusCode::OK, "{:?}", response.body);
+
+    response.json()["id"].as_str().unwrap().to_string()
+}
+
+/// Upload a package archive to a service version.
+pub async fn upload_package(
+    app: &axum::Router,
+    service_id: &str,
+    version: u32,
+    archive: &[u8],
+) -> Response {
+    const BOUNDARY: &str = "package-boundary";
+
+    let mut body = format!(
+        "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"package\"; \
+         filename=\"package.tar.gz\"\r\nContent-Type: application/gzip\r\n\r\n"
+    )
+    .into_bytes();
+    body.extend_from_slice(archive);
+    body.extend_from_slice(format!("\r\n--{BOUNDARY}--\r\n").as_bytes());
+
+    let content_type = format!("multipart/form-data; boundary={BOUNDARY}");
+    send(
+        app,
+        Method::PUT,
+        &format!("/service/{service_id}/version/{version}/package"),
+        &[("content-type", &content_type)],
+        body,
+    )
+    .await
+}
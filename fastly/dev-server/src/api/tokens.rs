@@ -0,0 +1,311 @@
+use axum::extract::{Extension, Form, Json, Path, Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use chrono::{DateTime, Utc};
+use redb::{ReadableDatabase, ReadableTable};
+use serde::Serialize;
+
+use crate::api::{Context, Result, Router, error::Error};
+use crate::tables::{TOKENS_TABLE, TokenMetadata, TokenScope};
+use crate::tokens::{create_token, delete_token, find_token};
+
+pub fn router() -> Router {
+    use axum::routing;
+
+    Router::new()
+        .route("/", routing::get(list_tokens).post(create))
+        .route("/self", routing::get(get_self).delete(delete_self))
+        .route("/{token_id}", routing::delete(delete))
+}
+
+/// Token the request was authenticated with.
+#[derive(Debug, Clone)]
+pub struct Authenticated(TokenMetadata);
+
+impl Authenticated {
+    pub fn id(&self) -> &str {
+        &self.0.id
+    }
+}
+
+/// Check the `Fastly-Key` header of every request when tokens are required.
+///
+/// A valid token is passed to handlers even when tokens aren't required, so `/tokens/self`
+/// works either way.
+pub async fn authenticate(
+    State(ctx): State<Context>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response> {
+    let key = req
+        .headers()
+        .get("fastly-key")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    let token = match key {
+        Some(key) => find_token(&ctx.db.begin_read()?, &key)?,
+        None => None,
+    }
+    .filter(|token| !token.is_expired(Utc::now()));
+
+    // The API description is public, so it can be browsed before getting a token.
+    let path = req.uri().path();
+    let public = path == "/openapi.json" || path == "/docs" || path.starts_with("/docs/");
+
+    if ctx.require_auth && !public {
+        let Some(token) = &token else {
+            return Err(Error::builder()
+                .unauthorized()
+                .message("Provided credentials are missing or invalid")
+                .build());
+        };
+
+        // Any token can look itself up or revoke itself.
+        if path != "/tokens/self" {
+            token.authorize(req.method(), path).map_err(|err| {
+                Error::builder()
+                    .forbidden()
+                    .message(err.to_string())
+                    .build()
+            })?;
+        }
+    }
+
+    if let Some(token) = token {
+        req.extensions_mut().insert(Authenticated(token));
+    }
+
+    Ok(next.run(req).await)
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Token {
+    id: String,
+    name: String,
+    scope: String,
+    services: Vec<String>,
+    /// Only returned when the token is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    access_token: Option<String>,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl Token {
+    fn new(meta: TokenMetadata) -> Self {
+        Token {
+            scope: meta.scope(),
+            id: meta.id,
+            name: meta.name,
+            services: meta.services,
+            access_token: None,
+            created_at: meta.created_at,
+            expires_at: meta.expires_at,
+        }
+    }
+}
+
+async fn list_tokens(State(ctx): State<Context>) -> Result<Json<Vec<Token>>> {
+    let tx = ctx.db.begin_read()?;
+
+    let table = match tx.open_table(TOKENS_TABLE) {
+        Ok(table) => table,
+        Err(redb::TableError::TableDoesNotExist(_)) => return Ok(Json(vec![])),
+        Err(e) => return Err(e.into()),
+    };
+
+    let tokens = table
+        .iter()?
+        .filter_map(|entry| entry.ok())
+        .map(|(_, record)| Token::new(record.value().0))
+        .collect();
+
+    Ok(Json(tokens))
+}
+
+/// Create a token from Fastly's form fields: `name`, `scope`, `services[]` and `expires_at`.
+///
+/// Fastly also requires the account's username and password, which are ignored here.
+async fn create(
+    State(ctx): State<Context>,
+    Form(fields): Form<Vec<(String, String)>>,
+) -> Result<Json<Token>> {
+    let mut name = None;
+    let mut scopes = vec![TokenScope::Global];
+    let mut services = Vec::new();
+    let mut expires_at = None;
+
+    for (field, value) in fields {
+        match field.as_str() {
+            "name" => name = Some(value),
+            "scope" => {
+                scopes = TokenScope::parse_list(&value)
+                    .map_err(|err| Error::builder().bad_request().message(err).build())?;
+            }
+            "services[]" | "services" => services.push(value),
+            "expires_at" => {
+                let timestamp = DateTime::parse_from_rfc3339(&value).map_err(|_| {
+                    Error::builder()
+                        .bad_request()
+                        .message("Invalid expires_at timestamp")
+                        .build()
+                })?;
+                expires_at = Some(timestamp.to_utc());
+            }
+            _ => {}
+        }
+    }
+
+    let meta = TokenMetadata {
+        id: ulid::Ulid::new().to_string(),
+        name: name.unwrap_or_default(),
+        scopes,
+        services,
+        created_at: Utc::now(),
+        expires_at,
+    };
+
+    let tx = ctx.db.begin_write()?;
+    let access_token = create_token(&tx, &meta)?;
+    tx.commit()?;
+
+    Ok(Json(Token {
+        access_token: Some(access_token),
+        ..Token::new(meta)
+    }))
+}
+
+async fn get_self(token: Option<Extension<Authenticated>>) -> Result<Json<Token>> {
+    let Some(Extension(Authenticated(meta))) = token else {
+        return Err(Error::builder()
+            .unauthorized()
+            .message("Provided credentials are missing or invalid")
+            .build());
+    };
+
+    Ok(Json(Token::new(meta)))
+}
+
+async fn delete_self(
+    State(ctx): State<Context>,
+    token: Option<Extension<Authenticated>>,
+) -> Result<()> {
+    let Some(Extension(Authenticated(meta))) = token else {
+        return Err(Error::builder()
+            .unauthorized()
+            .message("Provided credentials are missing or invalid")
+            .build());
+    };
+
+    let tx = ctx.db.begin_write()?;
+    delete_token(&tx, &meta.id)?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+async fn delete(State(ctx): State<Context>, Path(token_id): Path<String>) -> Result<()> {
+    let tx = ctx.db.begin_write()?;
+
+    if !delete_token(&tx, &token_id)? {
+        return Err(Error::builder()
+            .not_found()
+            .message("Token not found")
+            .build());
+    }
+
+    tx.commit()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use http::{Method, StatusCode};
+
+    use crate::api::{Context, testing};
+    use crate::tables::{TokenMetadata, TokenScope};
+
+    /// Store a token, returning its ID and the token itself.
+    fn token(ctx: &Context, services: &[&str]) -> (String, String) {
+        let meta = TokenMetadata {
+            id: ulid::Ulid::new().to_string(),
+            name: String::new(),
+            scopes: vec![TokenScope::Global],
+            services: services.iter().map(|id| id.to_string()).collect(),
+            created_at: Utc::now(),
+            expires_at: None,
+        };
+
+        let tx = ctx.db.begin_write().unwrap();
+        let access_token = crate::tokens::create_token(&tx, &meta).unwrap();
+        tx.commit().unwrap();
+
+        (meta.id, access_token)
+    }
+
+    #[tokio::test]
+    async fn only_unrestricted_tokens_manage_tokens_and_stores() {
+        let ctx = Context {
+            require_auth: true,
+            ..testing::context()
+        };
+        let (global_id, global) = token(&ctx, &[]);
+        let (service_id, service) = token(&ctx, &["service"]);
+        let app = testing::app(ctx);
+
+        let send = async |method: Method, uri: &str, key: &str| {
+            let form = [("content-type", "application/x-www-form-urlencoded")];
+            let headers = [form[0], ("fastly-key", key)];
+            let headers = if key.is_empty() {
+                &form[..]
+            } else {
+                &headers[..]
+            };
+            testing::send(&app, method, uri, headers, "name=new")
+                .await
+                .status
+        };
+
+        assert_eq!(
+            send(Method::GET, "/resources/stores/kv", "").await,
+            StatusCode::UNAUTHORIZED
+        );
+
+        let uri = format!("/tokens/{global_id}");
+        assert_eq!(
+            send(Method::POST, "/tokens", &service).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            send(Method::GET, "/tokens", &service).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            send(Method::DELETE, &uri, &service).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            send(Method::GET, "/resources/stores/kv", &service).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            send(Method::GET, "/tokens/self", &service).await,
+            StatusCode::OK
+        );
+
+        assert_eq!(
+            send(Method::GET, "/resources/stores/kv", &global).await,
+            StatusCode::OK
+        );
+        assert_eq!(send(Method::POST, "/tokens", &global).await, StatusCode::OK);
+        let uri = format!("/tokens/{service_id}");
+        assert_eq!(send(Method::DELETE, &uri, &global).await, StatusCode::OK);
+        assert_eq!(
+            send(Method::GET, "/tokens/self", &service).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
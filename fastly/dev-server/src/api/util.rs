@@ -1,8 +1,11 @@
 use headers::{Header, HeaderName, HeaderValue};
 
 static GENERATION: HeaderName = HeaderName::from_static("generation");
+static IF_GENERATION_MATCH: HeaderName = HeaderName::from_static("if-generation-match");
+static METADATA: HeaderName = HeaderName::from_static("metadata");
+static TIME_TO_LIVE_SEC: HeaderName = HeaderName::from_static("time_to_live_sec");
 
-pub struct Generation(pub u16);
+pub struct Generation(pub u64);
 
 impl Header for Generation {
     fn name() -> &'static HeaderName {
@@ -20,7 +23,7 @@ impl Header for Generation {
             .map_err(|_| headers::Error::invalid())?;
 
         let generation = value
-            .parse::<u16>()
+            .parse::<u64>()
             .map_err(|_| headers::Error::invalid())?;
 
         Ok(Generation(generation))
@@ -31,3 +34,83 @@ impl Header for Generation {
         values.extend(std::iter::once(value));
     }
 }
+
+/// Precondition on the current generation of a KV store item
+pub struct IfGenerationMatch(pub u64);
+
+impl Header for IfGenerationMatch {
+    fn name() -> &'static HeaderName {
+        &IF_GENERATION_MATCH
+    }
+
+    fn decode<'i, I>(values: &mut I) -> Result<Self, headers::Error>
+    where
+        I: Iterator<Item = &'i HeaderValue>,
+    {
+        let Generation(generation) = Generation::decode(values)?;
+        Ok(IfGenerationMatch(generation))
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        Generation(self.0).encode(values)
+    }
+}
+
+/// User metadata attached to a KV store item
+pub struct ItemMetadata(pub String);
+
+impl Header for ItemMetadata {
+    fn name() -> &'static HeaderName {
+        &METADATA
+    }
+
+    fn decode<'i, I>(values: &mut I) -> Result<Self, headers::Error>
+    where
+        I: Iterator<Item = &'i HeaderValue>,
+    {
+        let value = values
+            .next()
+            .ok_or_else(headers::Error::invalid)?
+            .to_str()
+            .map_err(|_| headers::Error::invalid())?;
+
+        Ok(ItemMetadata(value.to_string()))
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        if let Ok(value) = HeaderValue::from_str(&self.0) {
+            values.extend(std::iter::once(value));
+        }
+    }
+}
+
+/// Time to live of a KV store item, in seconds
+pub struct TimeToLiveSec(pub u64);
+
+impl Header for TimeToLiveSec {
+    fn name() -> &'static HeaderName {
+        &TIME_TO_LIVE_SEC
+    }
+
+    fn decode<'i, I>(values: &mut I) -> Result<Self, headers::Error>
+    where
+        I: Iterator<Item = &'i HeaderValue>,
+    {
+        let value = values
+            .next()
+            .ok_or_else(headers::Error::invalid)?
+            .to_str()
+            .map_err(|_| headers::Error::invalid())?;
+
+        let ttl = value
+            .parse::<u64>()
+            .map_err(|_| headers::Error::invalid())?;
+
+        Ok(TimeToLiveSec(ttl))
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        let value = HeaderValue::from_str(&self.0.to_string()).unwrap();
+        values.extend(std::iter::once(value));
+    }
+}
@@ -0,0 +1,42 @@
+use chrono::{DateTime, Utc};
+use miette::IntoDiagnostic;
+use redb::Database;
+
+use crate::tables::{TokenMetadata, TokenScope};
+
+#[derive(Debug, clap::Parser)]
+pub struct Options {
+    /// Name of the token
+    #[clap(long, default_value = "dev-server")]
+    pub name: String,
+    /// Space separated scopes: `global`, `global:read`, `purge_select` or `purge_all`
+    #[clap(long, default_value = "global", value_parser = TokenScope::parse_list)]
+    // Spelled out so clap parses one list instead of a repeated option
+    pub scope: std::vec::Vec<TokenScope>,
+    /// Restrict the token to this service, can be repeated
+    #[clap(long = "service", value_name = "ID")]
+    pub services: Vec<String>,
+    /// Time the token expires at, in RFC 3339 format
+    #[clap(long)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+pub fn run(opts: Options, db: &Database) -> miette::Result<()> {
+    let meta = TokenMetadata {
+        id: ulid::Ulid::new().to_string(),
+        name: opts.name,
+        scopes: opts.scope,
+        services: opts.services,
+        created_at: Utc::now(),
+        expires_at: opts.expires_at,
+    };
+
+    let tx = db.begin_write().into_diagnostic()?;
+    let token = crate::tokens::create_token(&tx, &meta).into_diagnostic()?;
+    tx.commit().into_diagnostic()?;
+
+    tracing::info!("Created token {} ({})", meta.id, meta.scope());
+    println!("{token}");
+
+    Ok(())
+}
@@ -2,16 +2,41 @@ use std::sync::Arc;
 
 use redb::Database;
 
+use crate::context::MasterKeySource;
+use crate::crypto::MasterKey;
+
+mod create_token;
+mod rotate_key;
 mod run;
 
+pub use run::ServiceBackend;
+
 #[derive(Debug, clap::Parser)]
 pub enum Command {
     /// Run the Fastly dev server
     Run(run::Options),
+    /// Re-encrypt every stored secret with a new key
+    RotateKey(rotate_key::Options),
+    /// Create an API token and print it
+    CreateToken(create_token::Options),
 }
 
-pub async fn run(cmd: Command, db: Arc<Database>) -> miette::Result<()> {
+/// Run a command, loading the key secrets are encrypted with (creating it if missing) only for
+/// the commands using it.
+pub async fn run(
+    cmd: Command,
+    db: Arc<Database>,
+    load_master_key: impl FnOnce() -> miette::Result<(MasterKey, MasterKeySource)>,
+) -> miette::Result<()> {
     match cmd {
-        Command::Run(opts) => run::run(opts, db).await,
+        Command::Run(opts) => {
+            let (master_key, _) = load_master_key()?;
+            run::run(opts, db, Arc::new(master_key)).await
+        }
+        Command::RotateKey(opts) => {
+            let (master_key, key_source) = load_master_key()?;
+            rotate_key::run(opts, &db, &master_key, &key_source)
+        }
+        Command::CreateToken(opts) => create_token::run(opts, &db),
     }
 }
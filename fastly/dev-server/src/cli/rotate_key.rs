@@ -0,0 +1,54 @@
+use miette::IntoDiagnostic;
+use redb::Database;
+
+use crate::context::MasterKeySource;
+use crate::crypto::MasterKey;
+
+#[derive(Debug, clap::Parser)]
+pub struct Options {
+    /// Base64 encoded key to switch to [default: a newly generated key]
+    #[clap(long, env = "FASTLY_DEV_SERVER_NEW_SECRET_KEY", hide_env_values = true)]
+    pub new_key: Option<String>,
+}
+
+pub fn run(
+    opts: Options,
+    db: &Database,
+    current: &MasterKey,
+    source: &MasterKeySource,
+) -> miette::Result<()> {
+    use crate::crypto::reencrypt_secrets;
+
+    let new = match opts.new_key {
+        Some(encoded) => MasterKey::from_base64(&encoded).into_diagnostic()?,
+        None => MasterKey::generate(),
+    };
+
+    match source {
+        MasterKeySource::File(path) => {
+            // The new key is saved before secrets are encrypted with it, so it can't be lost.
+            let pending = path.with_extension("key.new");
+            crate::context::write_key_file(&pending, &new)?;
+
+            let updated = reencrypt_secrets(db, current, &new)?;
+            std::fs::rename(&pending, path).into_diagnostic()?;
+
+            tracing::info!(
+                "Re-encrypted {updated} secrets with key {}, saved to {}",
+                new.id(),
+                path.display()
+            );
+        }
+        MasterKeySource::Env => {
+            let updated = reencrypt_secrets(db, current, &new)?;
+
+            tracing::info!(
+                "Re-encrypted {updated} secrets with key {}; set FASTLY_DEV_SERVER_SECRET_KEY to it",
+                new.id()
+            );
+            println!("{}", new.to_base64());
+        }
+    }
+
+    Ok(())
+}
@@ -5,10 +5,14 @@ use std::sync::Arc;
 use miette::IntoDiagnostic;
 use redb::Database;
 
+use crate::crypto::MasterKey;
+
 #[derive(Debug, clap::Parser)]
 pub struct Options {
-    /// Path to the Wasm file to run
-    pub file: PathBuf,
+    /// Path to the Wasm file or package archive to run, instead of the active version of
+    /// `--service-id`
+    #[clap(required_unless_present = "service_id")]
+    pub file: Option<PathBuf>,
 
     /// Address to bind the HTTP server to
     #[clap(
@@ -24,32 +28,134 @@ pub struct Options {
         env = "FASTLY_DEV_SERVER_API_ADDR"
     )]
     pub api_addr: SocketAddr,
+
+    /// Run as this service, only exposing the stores linked to it
+    #[clap(long, env = "FASTLY_DEV_SERVER_SERVICE_ID")]
+    pub service_id: Option<String>,
+    /// Service version whose resource links are used [default: active version]
+    #[clap(
+        long,
+        requires = "service_id",
+        env = "FASTLY_DEV_SERVER_SERVICE_VERSION"
+    )]
+    pub service_version: Option<u32>,
+
+    /// Adapt core Wasm modules into components
+    #[clap(long, env = "FASTLY_DEV_SERVER_ADAPT")]
+    pub adapt: bool,
+
+    /// Reject store writes exceeding Fastly's limits instead of only logging a warning
+    #[clap(long, env = "FASTLY_DEV_SERVER_STRICT_LIMITS")]
+    pub strict_limits: bool,
+
+    /// Reject API requests without a valid `Fastly-Key` token, see `create-token`
+    #[clap(long, env = "FASTLY_DEV_SERVER_REQUIRE_AUTH")]
+    pub require_auth: bool,
+
+    /// Limit API writes to this many per hour and token, like Fastly (which allows 1000)
+    #[clap(long, value_name = "WRITES", env = "FASTLY_DEV_SERVER_RATE_LIMIT")]
+    pub rate_limit: Option<u32>,
+
+    /// Bind a guest backend to another local Wasm module or package (`NAME=FILE`)
+    #[clap(long = "service-backend", value_name = "NAME=FILE", value_parser = parse_service_backend)]
+    pub service_backends: Vec<ServiceBackend>,
 }
 
-pub async fn run(opts: Options, db: Arc<Database>) -> miette::Result<()> {
+#[derive(Debug, Clone)]
+pub struct ServiceBackend {
+    pub name: String,
+    pub file: PathBuf,
+}
+
+fn parse_service_backend(value: &str) -> Result<ServiceBackend, String> {
+    let Some((name, file)) = value.split_once('=') else {
+        return Err(format!("expected `NAME=FILE`, got `{value}`"));
+    };
+    if name.is_empty() {
+        return Err("backend name must not be empty".to_string());
+    }
+
+    Ok(ServiceBackend {
+        name: name.to_string(),
+        file: PathBuf::from(file),
+    })
+}
+
+pub async fn run(
+    opts: Options,
+    db: Arc<Database>,
+    master_key: Arc<MasterKey>,
+) -> miette::Result<()> {
     use std::time::Duration;
 
     use tokio_graceful_shutdown::{SubsystemBuilder, SubsystemHandle, Toplevel};
 
+    let updated = crate::crypto::reencrypt_secrets(&db, &master_key, &master_key)?;
+    if updated > 0 {
+        tracing::info!("Encrypted or recomputed the digest of {updated} secrets");
+    }
+
     Toplevel::new(async move |s: &mut SubsystemHandle| {
         let api_subsys = SubsystemBuilder::new("api", {
             let db = db.clone();
             let listen_addr = opts.api_addr;
+            let strict_limits = opts.strict_limits;
+            let require_auth = opts.require_auth;
+            let rate_limit = opts.rate_limit;
+            let master_key = master_key.clone();
 
-            async move |subsys: &mut SubsystemHandle| crate::api::run(subsys, db, listen_addr).await
+            async move |subsys: &mut SubsystemHandle| {
+                crate::api::run(
+                    subsys,
+                    db,
+                    listen_addr,
+                    strict_limits,
+                    require_auth,
+                    rate_limit,
+                    master_key,
+                )
+                .await
+            }
         });
         s.start(api_subsys);
 
         let compute_subsys = SubsystemBuilder::new("compute", {
             let db = db.clone();
-            let module_path = opts.file.to_path_buf();
+            let module_path = opts.file.clone();
             let listen_addr = opts.http_addr;
+            let service_backends = opts.service_backends.clone();
+            let scope = opts
+                .service_id
+                .clone()
+                .map(|service_id| crate::compute::ServiceScope {
+                    service_id,
+                    version: opts.service_version,
+                });
+            let adapt = opts.adapt;
+            let master_key = master_key.clone();
 
             async move |subsys: &mut SubsystemHandle| {
-                crate::compute::run(subsys, db, module_path, listen_addr).await
+                crate::compute::run(
+                    subsys,
+                    db,
+                    module_path,
+                    listen_addr,
+                    service_backends,
+                    scope,
+                    adapt,
+                    master_key,
+                )
+                .await
             }
         });
         s.start(compute_subsys);
+
+        let sweeper_subsys = SubsystemBuilder::new("sweeper", {
+            let db = db.clone();
+
+            async move |subsys: &mut SubsystemHandle| crate::sweeper::run(subsys, db).await
+        });
+        s.start(sweeper_subsys);
     })
     .catch_signals()
     .handle_shutdown_requests(Duration::from_millis(1000))
@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task;
+
+use axum::body::Body as AxumBody;
+use http::{HeaderMap, Request, Response};
+use tower::{Layer, Service};
+use viceroy_lib::config::{Backend, Backends};
+
+use super::util::CDN_LOOP;
+
+/// Path prefix of the requests a guest sends to a chained service, naming the request it was
+/// handling.
+const HOP_PATH_PREFIX: &str = "/.fastly-dev-server/hop/";
+
+/// Guest backends bound to chained services.
+///
+/// Viceroy sends backend requests over HTTP, so chained services are served on loopback
+/// listeners of this process. Backend requests don't carry the request their guest was
+/// handling, so each request gets its own backends, whose URIs name a [`Hop`] the chained
+/// service takes the `CDN-Loop` and trace context of the guest's request from.
+#[derive(Clone, Default)]
+pub struct Chain {
+    backends: Backends,
+    services: Vec<(String, SocketAddr)>,
+    hops: Hops,
+}
+
+impl Chain {
+    pub fn add_service(&mut self, name: String, addr: SocketAddr) {
+        self.backends
+            .insert(name.clone(), Arc::new(local_backend(addr, "/")));
+        self.services.push((name, addr));
+    }
+
+    /// Record the request being handled in the current span, returning the guest backends to
+    /// handle it with. The request's hop lasts as long as the returned guard.
+    pub fn enter(&self, req: &Request<AxumBody>) -> (Backends, HopGuard) {
+        let hop = Hop {
+            cdn_loop: req.headers().get_all(&CDN_LOOP).iter().cloned().collect(),
+            trace_context: crate::util::trace_context_headers(),
+        };
+        let guard = self.hops.insert(hop);
+
+        let mut backends = self.backends.clone();
+        for (name, addr) in &self.services {
+            let path = format!("{HOP_PATH_PREFIX}{}/", guard.id);
+            backends.insert(name.clone(), Arc::new(local_backend(*addr, &path)));
+        }
+
+        (backends, guard)
+    }
+
+    /// Strip the hop from the path of a request sent by a guest, and give it the `CDN-Loop`
+    /// and trace context of the request the guest was handling.
+    ///
+    /// Like on Fastly, `CDN-Loop` is set by the platform whatever the guest sends, while a
+    /// trace context sent by the guest is kept.
+    pub fn follow(&self, mut req: Request<AxumBody>) -> Request<AxumBody> {
+        let Some((id, path)) = parse_hop_path(req.uri()) else {
+            return req;
+        };
+
+        let mut parts = req.uri().clone().into_parts();
+        parts.path_and_query = Some(path);
+        *req.uri_mut() = http::Uri::from_parts(parts).expect("Path was taken from a valid URI");
+
+        let Some(hop) = self.hops.get(id) else {
+            return req;
+        };
+
+        let headers = req.headers_mut();
+        headers.remove(&CDN_LOOP);
+        for value in hop.cdn_loop {
+            headers.append(CDN_LOOP.clone(), value);
+        }
+        for (name, value) in &hop.trace_context {
+            if !headers.contains_key(name) {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+
+        req
+    }
+}
+
+/// Follows the hops of requests to the listener of a chained service, or rejects requests
+/// passing for one sent by a guest on the public listener, so clients can't pick the `CDN-Loop`
+/// and trace context of another request.
+#[derive(Clone)]
+pub struct HopLayer {
+    chain: Option<Chain>,
+}
+
+impl HopLayer {
+    pub fn chained(chain: Chain) -> Self {
+        Self { chain: Some(chain) }
+    }
+
+    pub fn public() -> Self {
+        Self { chain: None }
+    }
+}
+
+impl<S> Layer<S> for HopLayer {
+    type Service = HopService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        HopService {
+            inner,
+            chain: self.chain.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct HopService<S> {
+    inner: S,
+    chain: Option<Chain>,
+}
+
+impl<S> Service<Request<AxumBody>> for HopService<S>
+where
+    S: Service<Request<AxumBody>, Response = Response<AxumBody>>,
+{
+    type Response = Response<AxumBody>;
+    type Error = S::Error;
+    type Future = HopFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<AxumBody>) -> Self::Future {
+        let req = match &self.chain {
+            Some(chain) => chain.follow(req),
+            None if req.uri().path().starts_with(HOP_PATH_PREFIX) => {
+                tracing::warn!("Rejected a request to a hop path on the public listener");
+                return HopFuture::Rejected;
+            }
+            None => req,
+        };
+
+        HopFuture::Inner {
+            inner: self.inner.call(req),
+        }
+    }
+}
+
+#[pin_project::pin_project(project = HopFutureProj)]
+pub enum HopFuture<Fut> {
+    Inner {
+        #[pin]
+        inner: Fut,
+    },
+    Rejected,
+}
+
+impl<Fut, E> Future for HopFuture<Fut>
+where
+    Fut: Future<Output = Result<Response<AxumBody>, E>>,
+{
+    type Output = Result<Response<AxumBody>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Self::Output> {
+        match self.project() {
+            HopFutureProj::Inner { inner } => inner.poll(cx),
+            HopFutureProj::Rejected => {
+                let resp = Response::builder()
+                    .status(http::StatusCode::NOT_FOUND)
+                    .body(AxumBody::from("Not found"))
+                    .unwrap();
+
+                task::Poll::Ready(Ok(resp))
+            }
+        }
+    }
+}
+
+/// A request handled by a guest, as seen by the services it calls.
+#[derive(Clone)]
+struct Hop {
+    cdn_loop: Vec<http::HeaderValue>,
+    trace_context: HeaderMap,
+}
+
+#[derive(Clone, Default)]
+struct Hops {
+    hops: Arc<Mutex<HashMap<u64, Hop>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Hops {
+    fn insert(&self, hop: Hop) -> HopGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.hops.lock().unwrap().insert(id, hop);
+
+        HopGuard {
+            hops: self.clone(),
+            id,
+        }
+    }
+
+    fn get(&self, id: u64) -> Option<Hop> {
+        self.hops.lock().unwrap().get(&id).cloned()
+    }
+}
+
+/// Keeps a request's hop until the guest is done with the request, including streaming its
+/// response body.
+pub struct HopGuard {
+    hops: Hops,
+    id: u64,
+}
+
+impl Drop for HopGuard {
+    fn drop(&mut self) {
+        self.hops.hops.lock().unwrap().remove(&self.id);
+    }
+}
+
+fn parse_hop_path(uri: &http::Uri) -> Option<(u64, http::uri::PathAndQuery)> {
+    let rest = uri.path().strip_prefix(HOP_PATH_PREFIX)?;
+    let (id, path) = match rest.split_once('/') {
+        Some((id, path)) => (id, path),
+        None => (rest, ""),
+    };
+    let id = id.parse().ok()?;
+
+    let path = match uri.query() {
+        Some(query) => format!("/{path}?{query}"),
+        None => format!("/{path}"),
+    };
+
+    Some((id, path.parse().ok()?))
+}
+
+fn local_backend(addr: SocketAddr, path: &str) -> Backend {
+    let uri = format!("http://{addr}{path}")
+        .parse()
+        .expect("Invalid local backend URI");
+
+    Backend {
+        uri,
+        override_host: None,
+        cert_host: None,
+        use_sni: false,
+        grpc: false,
+        client_cert: None,
+        ca_certs: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> Request<AxumBody> {
+        let mut builder = Request::builder().uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(AxumBody::empty()).unwrap()
+    }
+
+    fn chain() -> Chain {
+        let mut chain = Chain::default();
+        chain.add_service("origin".to_string(), "127.0.0.1:9000".parse().unwrap());
+        chain
+    }
+
+    #[test]
+    fn backends_name_the_hop() {
+        let chain = chain();
+
+        let (backends, guard) = chain.enter(&request("/", &[]));
+
+        assert_eq!(
+            backends["origin"].uri.to_string(),
+            format!("http://127.0.0.1:9000/.fastly-dev-server/hop/{}/", guard.id),
+        );
+    }
+
+    #[test]
+    fn follow_sets_cdn_loop_and_keeps_guest_trace_context() {
+        let chain = chain();
+        let (_, guard) = chain.enter(&request("/", &[("cdn-loop", "Fastly")]));
+
+        let req = request(
+            &format!("/.fastly-dev-server/hop/{}/a/b?c=d", guard.id),
+            &[("cdn-loop", "Other"), ("traceparent", "from-guest")],
+        );
+        let req = chain.follow(req);
+
+        assert_eq!(req.uri(), "/a/b?c=d");
+        let cdn_loop: Vec<_> = req.headers().get_all("cdn-loop").iter().collect();
+        assert_eq!(cdn_loop, ["Fastly"]);
+        assert_eq!(req.headers()["traceparent"], "from-guest");
+    }
+
+    #[test]
+    fn follow_after_the_hop_ended_only_strips_the_path() {
+        let chain = chain();
+        let (_, guard) = chain.enter(&request("/", &[("cdn-loop", "Fastly")]));
+        let id = guard.id;
+        drop(guard);
+
+        let req = chain.follow(request(
+            &format!("/.fastly-dev-server/hop/{id}"),
+            &[("cdn-loop", "Other")],
+        ));
+
+        assert_eq!(req.uri(), "/");
+        assert_eq!(req.headers()["cdn-loop"], "Other");
+    }
+
+    async fn call_through(layer: HopLayer, req: Request<AxumBody>) -> Response<AxumBody> {
+        use tower::ServiceExt;
+
+        let echo = tower::service_fn(async |req: Request<AxumBody>| {
+            let cdn_loop = req.headers().get("cdn-loop").cloned();
+            let mut resp = Response::new(AxumBody::from(req.uri().to_string()));
+            if let Some(cdn_loop) = cdn_loop {
+                resp.headers_mut().insert("cdn-loop", cdn_loop);
+            }
+            Ok::<_, std::convert::Infallible>(resp)
+        });
+
+        layer.layer(echo).oneshot(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn hops_are_only_followed_on_chained_listeners() {
+        let chain = chain();
+        let (_, guard) = chain.enter(&request("/", &[("cdn-loop", "Fastly")]));
+        let hop_uri = format!("/.fastly-dev-server/hop/{}/a", guard.id);
+
+        let resp = call_through(
+            HopLayer::chained(chain.clone()),
+            request(&hop_uri, &[("cdn-loop", "Other")]),
+        )
+        .await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        assert_eq!(resp.headers()["cdn-loop"], "Fastly");
+
+        let resp = call_through(
+            HopLayer::public(),
+            request(&hop_uri, &[("cdn-loop", "Other")]),
+        )
+        .await;
+        assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+
+        let resp = call_through(HopLayer::public(), request("/a", &[])).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+    }
+
+    #[test]
+    fn other_requests_are_left_alone() {
+        let req = chain().follow(request("/.fastly-dev-server/hop/x/y", &[]));
+
+        assert_eq!(req.uri(), "/.fastly-dev-server/hop/x/y");
+    }
+}
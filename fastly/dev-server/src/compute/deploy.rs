@@ -0,0 +1,176 @@
+use std::sync::Arc;
+
+use super::Template;
+use super::stores::open_table;
+use crate::package::Package;
+use crate::tables::{METADATA_TABLE, PACKAGES_TABLE};
+use miette::IntoDiagnostic;
+use redb::{Database, ReadableDatabase};
+
+/// Guest module deployed through the services API, reloaded whenever another package becomes
+/// active.
+pub struct Deployment {
+    service_id: String,
+    adapt: bool,
+    /// Package ID and compiled guest of the last loaded version
+    current: tokio::sync::Mutex<Option<(String, Arc<Template>)>>,
+}
+
+impl Deployment {
+    pub fn new(service_id: String, adapt: bool) -> Self {
+        Self {
+            service_id,
+            adapt,
+            current: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// Get the compiled guest of the active version, if there is one.
+    ///
+    /// The module of a version is only compiled once, on a blocking thread, by the first
+    /// connection after the version is activated; later connections share it.
+    pub async fn template(&self, db: &Arc<Database>) -> miette::Result<Option<Arc<Template>>> {
+        let Some((version, package_id)) = self.active_package(db)? else {
+            return Ok(None);
+        };
+
+        // Held while compiling, so connections arriving meanwhile wait for the same module.
+        let mut current = self.current.lock().await;
+        if let Some((current_id, template)) = current.as_ref()
+            && *current_id == package_id
+        {
+            return Ok(Some(template.clone()));
+        }
+
+        let template = tokio::task::spawn_blocking({
+            let db = db.clone();
+            let service_id = self.service_id.clone();
+            let adapt = self.adapt;
+
+            move || load_version(&db, &service_id, version, adapt)
+        })
+        .await
+        .into_diagnostic()??;
+        let template = Arc::new(template);
+
+        *current = Some((package_id, template.clone()));
+
+        Ok(Some(template))
+    }
+
+    /// Number and package ID of the active version.
+    fn active_package(&self, db: &Database) -> miette::Result<Option<(u32, String)>> {
+        let tx = db.begin_read().into_diagnostic()?;
+
+        let Some(metadata_table) = open_table(&tx, METADATA_TABLE).into_diagnostic()? else {
+            return Ok(None);
+        };
+        let Some(metadata_record) = metadata_table.get(&()).into_diagnostic()? else {
+            return Ok(None);
+        };
+        let metadata = &metadata_record.value().0;
+
+        Ok(metadata
+            .services
+            .get(&self.service_id)
+            .and_then(|service_meta| service_meta.active_version())
+            .and_then(|version_meta| {
+                let package_meta = version_meta.package.as_ref()?;
+                Some((version_meta.number, package_meta.id.clone()))
+            }))
+    }
+}
+
+fn load_version(
+    db: &Database,
+    service_id: &str,
+    version: u32,
+    adapt: bool,
+) -> miette::Result<Template> {
+    let package = read_package(db, service_id, version)?;
+
+    let module_path = super::unpack_module(&package)?;
+
+    let template = super::build_template(&module_path, adapt)?;
+    tracing::info!(
+        "Loaded version {version} of service {service_id} ({})",
+        package.manifest.name,
+    );
+
+    Ok(template)
+}
+
+/// Read the package uploaded to a service version.
+fn read_package(db: &Database, service_id: &str, version: u32) -> miette::Result<Package> {
+    let tx = db.begin_read().into_diagnostic()?;
+
+    let Some(packages_table) = open_table(&tx, PACKAGES_TABLE).into_diagnostic()? else {
+        miette::bail!("Package of version {version} not found");
+    };
+    let Some(archive) = packages_table
+        .get((service_id.to_string(), version))
+        .into_diagnostic()?
+    else {
+        miette::bail!("Package of version {version} not found");
+    };
+
+    Package::from_archive(archive.value()).into_diagnostic()
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Method;
+
+    use super::*;
+    use crate::api::testing::{self, send};
+    use crate::package::archive;
+
+    const FIRST: &[u8] = b"\0asm\x01\0\0\0";
+    /// Another empty module, with a custom section named `a`
+    const SECOND: &[u8] = b"\0asm\x01\0\0\0\0\x02\x01a";
+
+    fn package(module: &[u8]) -> Vec<u8> {
+        archive(&[
+            ("app/fastly.toml", b"manifest_version = 3\nname = \"app\"\n"),
+            ("app/bin/main.wasm", module),
+        ])
+    }
+
+    #[tokio::test]
+    async fn uploaded_packages_are_run_once_active() {
+        let db = crate::context::memory_db();
+        let app = testing::app_with_db(db.clone());
+        let service_id = testing::create_service(&app, "app").await;
+        let deployment = Deployment::new(service_id.clone(), false);
+        let activate = async |version| {
+            let uri = format!("/service/{service_id}/version/{version}/activate");
+            send(&app, Method::PUT, &uri, &[], "").await
+        };
+
+        let response = testing::upload_package(&app, &service_id, 1, &package(FIRST)).await;
+        assert_eq!(response.status, 200, "{:?}", response.body);
+        let first_id = response.json()["id"].as_str().unwrap().to_string();
+        assert_eq!(deployment.active_package(&db).unwrap(), None);
+
+        assert_eq!(activate(1).await.status, 200);
+        assert_eq!(deployment.active_package(&db).unwrap(), Some((1, first_id)));
+        let package_run = read_package(&db, &service_id, 1).unwrap();
+        assert_eq!(package_run.module.as_ref(), FIRST);
+
+        // A new package only runs once its version is activated
+        let uri = format!("/service/{service_id}/version/1/clone");
+        assert_eq!(send(&app, Method::PUT, &uri, &[], "").await.status, 200);
+        let response = testing::upload_package(&app, &service_id, 2, &package(SECOND)).await;
+        assert_eq!(response.status, 200, "{:?}", response.body);
+        let second_id = response.json()["id"].as_str().unwrap().to_string();
+        assert_eq!(deployment.active_package(&db).unwrap().unwrap().0, 1);
+
+        assert_eq!(activate(2).await.status, 200);
+        assert_eq!(
+            deployment.active_package(&db).unwrap(),
+            Some((2, second_id))
+        );
+        let package_run = read_package(&db, &service_id, 2).unwrap();
+        assert_eq!(package_run.module.as_ref(), SECOND);
+    }
+}
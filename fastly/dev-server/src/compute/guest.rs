@@ -0,0 +1,501 @@
+use std::fmt;
+
+use wasmparser::{Parser, Payload};
+
+use super::stores::StoreKinds;
+
+// The imports below mirror what Viceroy's linkers define (`linking.rs` for core modules,
+// `component.rs` and its `adapter-service` world for components), without experimental modules.
+
+/// Core module import namespaces provided by Viceroy.
+const CORE_IMPORT_MODULES: &[&str] = &[
+    "wasi_snapshot_preview1",
+    "fastly_abi",
+    "fastly_acl",
+    "fastly_async_io",
+    "fastly_backend",
+    "fastly_cache",
+    "fastly_compute_runtime",
+    "fastly_config_store",
+    "fastly_device_detection",
+    "fastly_dictionary",
+    "fastly_erl",
+    "fastly_geo",
+    "fastly_http_body",
+    "fastly_http_cache",
+    "fastly_http_downstream",
+    "fastly_http_req",
+    "fastly_http_resp",
+    "fastly_image_optimizer",
+    "fastly_kv_store",
+    "fastly_log",
+    "fastly_object_store",
+    "fastly_purge",
+    "fastly_secret_store",
+    "fastly_shielding",
+    "fastly_uap",
+];
+
+/// Module of the legacy hostcall names, of which Viceroy only defines [`LEGACY_IMPORTS`].
+const LEGACY_IMPORT_MODULE: &str = "env";
+const LEGACY_IMPORTS: &[&str] = &[
+    "xqd_body_append",
+    "xqd_body_close",
+    "xqd_body_close_downstream",
+    "xqd_body_new",
+    "xqd_body_read",
+    "xqd_body_write",
+    "xqd_init",
+    "xqd_log_endpoint_get",
+    "xqd_log_write",
+    "xqd_pending_req_poll",
+    "xqd_pending_req_select",
+    "xqd_pending_req_wait",
+    "xqd_req_body_downstream_get",
+    "xqd_req_cache_override_set",
+    "xqd_req_downstream_client_ip_addr",
+    "xqd_req_downstream_client_request_id",
+    "xqd_req_downstream_tls_cipher_openssl_name",
+    "xqd_req_downstream_tls_client_hello",
+    "xqd_req_downstream_tls_protocol",
+    "xqd_req_header_append",
+    "xqd_req_header_insert",
+    "xqd_req_header_names_get",
+    "xqd_req_header_remove",
+    "xqd_req_header_value_get",
+    "xqd_req_header_values_get",
+    "xqd_req_header_values_set",
+    "xqd_req_method_get",
+    "xqd_req_method_set",
+    "xqd_req_new",
+    "xqd_req_original_header_count",
+    "xqd_req_original_header_names_get",
+    "xqd_req_send",
+    "xqd_req_send_async",
+    "xqd_req_send_async_streaming",
+    "xqd_req_uri_get",
+    "xqd_req_uri_set",
+    "xqd_req_version_get",
+    "xqd_req_version_set",
+    "xqd_resp_header_append",
+    "xqd_resp_header_insert",
+    "xqd_resp_header_names_get",
+    "xqd_resp_header_remove",
+    "xqd_resp_header_value_get",
+    "xqd_resp_header_values_get",
+    "xqd_resp_header_values_set",
+    "xqd_resp_new",
+    "xqd_resp_status_get",
+    "xqd_resp_status_set",
+    "xqd_resp_version_get",
+    "xqd_resp_version_set",
+];
+
+/// Component interfaces provided by Viceroy, by package and the version they're defined at.
+/// Imports of other patch versions (or minor versions, before 1.0) link to them as well.
+const COMPONENT_INTERFACES: &[(&str, Option<&str>, &[&str])] = &[
+    (
+        "fastly:compute",
+        Some("0.1.0"),
+        &[
+            "acl",
+            "async-io",
+            "backend",
+            "cache",
+            "compute-runtime",
+            "config-store",
+            "device-detection",
+            "dictionary",
+            "erl",
+            "geo",
+            "http-body",
+            "http-cache",
+            "http-downstream",
+            "http-req",
+            "http-resp",
+            "http-types",
+            "image-optimizer",
+            "kv-store",
+            "log",
+            "purge",
+            "secret-store",
+            "security",
+            "shielding",
+            "types",
+        ],
+    ),
+    ("fastly:adapter", None, &["adapter-http-req"]),
+    (
+        "wasi:cli",
+        Some("0.2.6"),
+        &["environment", "exit", "stderr", "stdin", "stdout"],
+    ),
+    (
+        "wasi:clocks",
+        Some("0.2.6"),
+        &["monotonic-clock", "wall-clock"],
+    ),
+    ("wasi:io", Some("0.2.6"), &["error", "poll", "streams"]),
+    (
+        "wasi:random",
+        Some("0.2.6"),
+        &["insecure", "insecure-seed", "random"],
+    ),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestKind {
+    CoreModule,
+    Component,
+}
+
+impl fmt::Display for GuestKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GuestKind::CoreModule => f.write_str("core module"),
+            GuestKind::Component => f.write_str("component"),
+        }
+    }
+}
+
+/// What the dev-server needs to know about a guest before handing it to Viceroy.
+#[derive(Debug, Clone)]
+pub struct Guest {
+    pub kind: GuestKind,
+    /// Imports that Viceroy would fail to link, as `module::name` or interface names
+    pub unsupported_imports: Vec<String>,
+    /// Kinds of stores the guest imports hostcalls for
+    pub stores: StoreKinds,
+}
+
+impl Guest {
+    /// Inspect a binary Wasm guest. Returns `None` for inputs that aren't binary Wasm, such as
+    /// WAT text, which are left to Viceroy.
+    pub fn inspect(bytes: &[u8]) -> Result<Option<Self>, wasmparser::BinaryReaderError> {
+        let kind = if Parser::is_component(bytes) {
+            GuestKind::Component
+        } else if Parser::is_core_wasm(bytes) {
+            GuestKind::CoreModule
+        } else {
+            return Ok(None);
+        };
+
+        let mut unsupported_imports = Vec::new();
+        let mut stores = StoreKinds::default();
+
+        // Only the guest's own imports matter: modules nested in a component import from other
+        // instances of the same component.
+        let mut depth = 0usize;
+        for payload in Parser::new(0).parse_all(bytes) {
+            match payload? {
+                Payload::ModuleSection { .. } | Payload::ComponentSection { .. } => depth += 1,
+                Payload::End(_) => depth = depth.saturating_sub(1),
+                Payload::ImportSection(reader) if depth == 0 => {
+                    for import in reader {
+                        let import = import?;
+                        if !is_supported_core_import(import.module, import.name) {
+                            unsupported_imports.push(format!("{}::{}", import.module, import.name));
+                        }
+                        add_store_kind(&mut stores, import.module);
+                    }
+                }
+                Payload::ComponentImportSection(reader) if depth == 0 => {
+                    for import in reader {
+                        let import = import?;
+                        if !is_supported_component_import(import.name.0) {
+                            unsupported_imports.push(import.name.0.to_string());
+                        }
+                        if let Some(interface) = import.name.0.strip_prefix("fastly:compute/") {
+                            let interface = interface
+                                .split_once('@')
+                                .map_or(interface, |(name, _)| name);
+                            add_store_kind(&mut stores, interface);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        unsupported_imports.sort();
+        unsupported_imports.dedup();
+
+        Ok(Some(Guest {
+            kind,
+            unsupported_imports,
+            stores,
+        }))
+    }
+}
+
+/// Record the kind of store a core import module or `fastly:compute` interface opens.
+fn add_store_kind(stores: &mut StoreKinds, module_or_interface: &str) {
+    match module_or_interface {
+        "fastly_config_store" | "fastly_dictionary" | "config-store" | "dictionary" => {
+            stores.config = true
+        }
+        "fastly_kv_store" | "fastly_object_store" | "kv-store" => stores.kv = true,
+        "fastly_secret_store" | "secret-store" => stores.secret = true,
+        _ => {}
+    }
+}
+
+fn is_supported_core_import(module: &str, name: &str) -> bool {
+    if module == LEGACY_IMPORT_MODULE {
+        return LEGACY_IMPORTS.contains(&name);
+    }
+
+    CORE_IMPORT_MODULES.contains(&module)
+}
+
+/// Whether an import names a provided interface, as `namespace:package/interface@version`.
+fn is_supported_component_import(name: &str) -> bool {
+    let (path, version) = match name.split_once('@') {
+        Some((path, version)) => (path, Some(version)),
+        None => (name, None),
+    };
+    let Some((package, interface)) = path.split_once('/') else {
+        return false;
+    };
+
+    COMPONENT_INTERFACES
+        .iter()
+        .any(|(provided_package, provided_version, interfaces)| {
+            *provided_package == package
+                && interfaces.contains(&interface)
+                && match (version, provided_version) {
+                    (None, None) => true,
+                    (Some(version), Some(provided)) => is_compatible_version(version, provided),
+                    _ => false,
+                }
+        })
+}
+
+/// Whether Wasmtime links an import of `version` to a definition at `provided`: both must
+/// share their major version, or their minor version before 1.0, like semver.
+fn is_compatible_version(version: &str, provided: &str) -> bool {
+    fn parse(version: &str) -> Option<(u64, u64)> {
+        let mut parts = version.split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next()?.parse().ok()?;
+        let _patch: u64 = parts.next()?.split(['-', '+']).next()?.parse().ok()?;
+        Some((major, minor))
+    }
+
+    match (parse(version), parse(provided)) {
+        (Some((0, minor)), Some((0, provided_minor))) => minor == provided_minor,
+        (Some((major, _)), Some((provided_major, _))) => major == provided_major,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a section with its ID and size.
+    fn section(id: u8, contents: &[u8]) -> Vec<u8> {
+        let mut section = vec![id];
+        leb128(&mut section, contents.len());
+        section.extend_from_slice(contents);
+        section
+    }
+
+    fn leb128(out: &mut Vec<u8>, mut value: usize) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte);
+                return;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+
+    fn name(out: &mut Vec<u8>, name: &str) {
+        leb128(out, name.len());
+        out.extend_from_slice(name.as_bytes());
+    }
+
+    /// A core module importing `() -> ()` functions.
+    fn core_module(imports: &[(&str, &str)]) -> Vec<u8> {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        module.extend(section(1, &[0x01, 0x60, 0x00, 0x00]));
+
+        let mut import_section = Vec::new();
+        leb128(&mut import_section, imports.len());
+        for (module_name, field) in imports {
+            name(&mut import_section, module_name);
+            name(&mut import_section, field);
+            import_section.extend([0x00, 0x00]);
+        }
+        module.extend(section(2, &import_section));
+
+        module
+    }
+
+    /// A component importing instances of an empty instance type, holding nested modules.
+    fn component(imports: &[&str], modules: &[Vec<u8>]) -> Vec<u8> {
+        let mut component = b"\0asm\x0d\0\x01\0".to_vec();
+        component.extend(section(7, &[0x01, 0x42, 0x00]));
+
+        let mut import_section = Vec::new();
+        leb128(&mut import_section, imports.len());
+        for import in imports {
+            import_section.push(0x00);
+            name(&mut import_section, import);
+            import_section.extend([0x05, 0x00]);
+        }
+        component.extend(section(10, &import_section));
+
+        for module in modules {
+            component.extend(section(1, module));
+        }
+
+        component
+    }
+
+    fn inspect(bytes: &[u8]) -> Guest {
+        Guest::inspect(bytes).unwrap().unwrap()
+    }
+
+    #[test]
+    fn core_modules_may_import_viceroy_modules() {
+        let guest = inspect(&core_module(&[
+            ("wasi_snapshot_preview1", "fd_write"),
+            ("fastly_http_req", "send"),
+            ("fastly_kv_store", "lookup"),
+            ("env", "xqd_req_new"),
+        ]));
+
+        assert_eq!(guest.kind, GuestKind::CoreModule);
+        assert!(guest.unsupported_imports.is_empty());
+        assert_eq!(
+            guest.stores,
+            StoreKinds {
+                kv: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn components_may_import_viceroy_interfaces() {
+        let guest = inspect(&component(
+            &[
+                "fastly:compute/http-req@0.1.0",
+                "fastly:compute/kv-store@0.1.0",
+                "fastly:adapter/adapter-http-req",
+                "wasi:io/streams@0.2.6",
+                // Older patch versions link to the provided ones
+                "wasi:cli/environment@0.2.0",
+            ],
+            &[],
+        ));
+
+        assert_eq!(guest.kind, GuestKind::Component);
+        assert!(guest.unsupported_imports.is_empty());
+        assert_eq!(
+            guest.stores,
+            StoreKinds {
+                kv: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn only_the_stores_a_guest_imports_are_loaded() {
+        let guest = inspect(&core_module(&[
+            ("fastly_dictionary", "open"),
+            ("fastly_secret_store", "open"),
+        ]));
+        assert_eq!(
+            guest.stores,
+            StoreKinds {
+                config: true,
+                kv: false,
+                secret: true,
+            }
+        );
+
+        let guest = inspect(&component(
+            &[
+                "fastly:compute/dictionary@0.1.0",
+                "fastly:compute/secret-store@0.1.0",
+            ],
+            // Nested modules don't reach the host
+            &[core_module(&[("fastly_kv_store", "open")])],
+        ));
+        assert_eq!(
+            guest.stores,
+            StoreKinds {
+                config: true,
+                kv: false,
+                secret: true,
+            }
+        );
+
+        let guest = inspect(&component(&["fastly:compute/http-req@0.1.0"], &[]));
+        assert_eq!(guest.stores, StoreKinds::default());
+    }
+
+    #[test]
+    fn adapted_core_modules_only_need_their_component_imports() {
+        // The adapter and the original module, nested in the component, import from instances
+        // of the component itself rather than from the host.
+        let guest = inspect(&component(
+            &["fastly:compute/http-req@0.1.0", "wasi:cli/stdout@0.2.6"],
+            &[
+                core_module(&[("fastly_http_req", "send")]),
+                core_module(&[("__main_module__", "_start")]),
+            ],
+        ));
+
+        assert_eq!(guest.kind, GuestKind::Component);
+        assert!(guest.unsupported_imports.is_empty());
+    }
+
+    #[test]
+    fn other_imports_are_rejected() {
+        let guest = inspect(&core_module(&[
+            ("fastly_http_req", "send"),
+            ("fastly_unknown", "call"),
+            ("env", "memcpy"),
+            ("wasi_snapshot_preview2", "fd_write"),
+        ]));
+        assert_eq!(
+            guest.unsupported_imports,
+            [
+                "env::memcpy",
+                "fastly_unknown::call",
+                "wasi_snapshot_preview2::fd_write"
+            ]
+        );
+
+        let guest = inspect(&component(
+            &[
+                "fastly:compute/http-req@0.1.0",
+                "fastly:compute/http-incoming@0.1.0",
+                "fastly:compute/http-req@0.2.0",
+                "wasi:filesystem/types@0.2.6",
+                "wasi:io/streams",
+                "wasi:http/types@0.2.6",
+                "my:own/interface",
+            ],
+            &[],
+        ));
+        assert_eq!(
+            guest.unsupported_imports,
+            [
+                "fastly:compute/http-incoming@0.1.0",
+                "fastly:compute/http-req@0.2.0",
+                "my:own/interface",
+                "wasi:filesystem/types@0.2.6",
+                "wasi:http/types@0.2.6",
+                "wasi:io/streams",
+            ]
+        );
+    }
+}
@@ -1,24 +1,159 @@
-use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use miette::IntoDiagnostic;
+use axum::body::Body as AxumBody;
+use miette::{IntoDiagnostic, WrapErr};
 use redb::Database;
+use tokio::net::TcpListener;
 use tokio_graceful_shutdown::SubsystemHandle;
 use viceroy_lib::{ExecuteCtx, ProfilingStrategy};
 
+use crate::cli::ServiceBackend;
+use crate::crypto::MasterKey;
+
+mod chain;
 mod compat;
+mod deploy;
+mod guest;
 mod stores;
 mod util;
 
+pub use stores::ServiceScope;
+
+#[allow(clippy::too_many_arguments)]
 pub async fn run(
     subsys: &mut SubsystemHandle,
     db: Arc<Database>,
-    module_path: PathBuf,
+    module_path: Option<PathBuf>,
     listen_addr: SocketAddr,
+    service_backends: Vec<ServiceBackend>,
+    scope: Option<ServiceScope>,
+    adapt: bool,
+    master_key: Arc<MasterKey>,
 ) -> miette::Result<()> {
-    use axum::serve::IncomingStream;
-    use tokio::net::TcpListener;
+    use tokio_graceful_shutdown::SubsystemBuilder;
+
+    let mut chain = chain::Chain::default();
+    let mut chained_services = Vec::with_capacity(service_backends.len());
+
+    // Chained services are only reachable through loopback listeners owned by this process, so
+    // every guest backend bound to one never leaves the dev-server.
+    for service_backend in service_backends {
+        let module = Module::File(Arc::new(build_template(&service_backend.file, adapt)?));
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .into_diagnostic()?;
+        let local_addr = listener.local_addr().into_diagnostic()?;
+
+        chain.add_service(service_backend.name.clone(), local_addr);
+        chained_services.push((service_backend.name, module, listener, local_addr));
+    }
+
+    for (name, module, listener, local_addr) in chained_services {
+        let db = db.clone();
+        let chain = chain.clone();
+        let master_key = master_key.clone();
+
+        let chained_subsys = SubsystemBuilder::new(
+            format!("compute:{name}"),
+            async move |subsys: &mut SubsystemHandle| {
+                tracing::info!("Service backend `{name}` listening on {local_addr}");
+                serve(
+                    subsys, db, module, chain, true, None, listener, local_addr, master_key,
+                )
+                .await
+            },
+        );
+        subsys.start(chained_subsys);
+    }
+
+    let module = match (module_path, &scope) {
+        (Some(module_path), _) => Module::File(Arc::new(build_template(&module_path, adapt)?)),
+        (None, Some(scope)) => {
+            Module::Deployed(deploy::Deployment::new(scope.service_id.clone(), adapt))
+        }
+        (None, None) => miette::bail!("Either a Wasm file or a service ID is required"),
+    };
+
+    let listener = TcpListener::bind(listen_addr).await.into_diagnostic()?;
+    tracing::info!("Compute server listening on {listen_addr}");
+
+    serve(
+        subsys,
+        db,
+        module,
+        chain,
+        false,
+        scope,
+        listener,
+        listen_addr,
+        master_key,
+    )
+    .await
+}
+
+/// Where the guest module served by a compute server comes from.
+enum Module {
+    File(Arc<Template>),
+    Deployed(deploy::Deployment),
+}
+
+impl Module {
+    async fn template(&self, db: &Arc<Database>) -> miette::Result<Option<Arc<Template>>> {
+        match self {
+            Module::File(template) => Ok(Some(template.clone())),
+            Module::Deployed(deployment) => deployment.template(db).await,
+        }
+    }
+}
+
+/// A compiled guest, instantiated for each request.
+struct Template {
+    exec_ctx: ExecuteCtx,
+    /// Kinds of stores loaded into each instance
+    stores: stores::StoreKinds,
+}
+
+/// Build the execution context of a Wasm module or component, or of the module of a package
+/// archive. Core modules are adapted into components when `adapt` is set.
+fn build_template(module_path: &Path, adapt: bool) -> miette::Result<Template> {
+    use crate::package::{self, Package};
+
+    let module_path = if package::is_package_file(module_path).into_diagnostic()? {
+        let package = Package::from_file(module_path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Invalid package `{}`", module_path.display()))?;
+        tracing::info!("Loaded package {}", package.manifest.name);
+
+        unpack_module(&package)?
+    } else {
+        module_path.to_path_buf()
+    };
+
+    let bytes = std::fs::read(&module_path).into_diagnostic()?;
+    let guest = guest::Guest::inspect(&bytes)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Invalid Wasm guest `{}`", module_path.display()))?;
+
+    let (component, stores) = match guest {
+        Some(guest) => {
+            if !guest.unsupported_imports.is_empty() {
+                miette::bail!(
+                    help = "The dev-server only provides the Fastly Compute and WASI interfaces",
+                    "Guest `{}` imports unsupported interfaces: {}",
+                    module_path.display(),
+                    guest.unsupported_imports.join(", "),
+                );
+            }
+
+            tracing::info!("Loading {} `{}`", guest.kind, module_path.display());
+            (guest.kind == guest::GuestKind::Component, guest.stores)
+        }
+        // Text guests aren't inspected, so they may open any store.
+        None => (false, stores::StoreKinds::ALL),
+    };
 
     let exec_ctx = ExecuteCtx::build(
         module_path,
@@ -26,28 +161,84 @@ pub async fn run(
         Default::default(),
         None,
         Default::default(),
-        false,
+        component || adapt,
     )
     .into_diagnostic()?
     .finish();
 
-    let make_service = tower::service_fn(move |stream: IncomingStream<TcpListener>| {
-        let exec_ctx = {
-            let builder = exec_ctx.new_instance();
-            let builder = stores::init_stores(&db, builder).expect("Failed to initialize stores");
+    Ok(Template { exec_ctx, stores })
+}
+
+/// Viceroy loads modules from disk, so write the module of a package to a scratch directory.
+///
+/// Modules are named after their own hash rather than anything in the package's manifest, so
+/// packages can't write outside the directory or replace each other's module, and packages
+/// sharing a module share its file.
+fn unpack_module(package: &crate::package::Package) -> miette::Result<PathBuf> {
+    let modules_dir = std::env::temp_dir().join("fastly-dev-server");
+    std::fs::create_dir_all(&modules_dir).into_diagnostic()?;
+
+    let module_path = modules_dir.join(format!("{}.wasm", package.module_hash));
+    if !module_path.exists() {
+        // Written aside first, so a module is never loaded while partially written.
+        let partial_path = modules_dir.join(format!(
+            "{}.wasm.{}",
+            package.module_hash,
+            ulid::Ulid::new()
+        ));
+        std::fs::write(&partial_path, &package.module).into_diagnostic()?;
+        std::fs::rename(&partial_path, &module_path).into_diagnostic()?;
+    }
+
+    Ok(module_path)
+}
+
+/// Serve a guest on a listener, either the public one or that of a chained service, which
+/// guests reach through the hops of their backends.
+#[allow(clippy::too_many_arguments)]
+async fn serve(
+    subsys: &mut SubsystemHandle,
+    db: Arc<Database>,
+    module: Module,
+    chain: chain::Chain,
+    chained: bool,
+    scope: Option<ServiceScope>,
+    listener: TcpListener,
+    listen_addr: SocketAddr,
+    master_key: Arc<MasterKey>,
+) -> miette::Result<()> {
+    use axum::serve::IncomingStream;
 
-            Arc::new(builder.finish())
-        };
+    let module = Arc::new(module);
+    let hop_layer = if chained {
+        chain::HopLayer::chained(chain.clone())
+    } else {
+        chain::HopLayer::public()
+    };
 
+    let make_service = tower::service_fn(move |stream: IncomingStream<TcpListener>| {
+        let module = module.clone();
+        let db = db.clone();
+        let chain = chain.clone();
+        let scope = scope.clone();
+        let master_key = master_key.clone();
+        let hop_layer = hop_layer.clone();
         let local_addr = listen_addr;
         let remote_addr = *stream.remote_addr();
 
         async move {
             use axum::error_handling::HandleErrorLayer;
+            use axum::response::IntoResponse;
+            use tower::util::Either;
             use tower_http::trace::TraceLayer;
 
             use crate::util::OtelTrace;
 
+            let template = module.template(&db).await.unwrap_or_else(|err| {
+                tracing::error!("Failed to load guest module: {err:?}");
+                None
+            });
+
             let trace_layer = TraceLayer::new_for_http()
                 .make_span_with(OtelTrace)
                 .on_response(OtelTrace)
@@ -55,9 +246,70 @@ pub async fn run(
                 .on_eos(())
                 .on_failure(OtelTrace);
 
-            let viceroy_service = util::ViceroyService::new(exec_ctx, local_addr, remote_addr);
+            let guest_service = match template {
+                // Each request gets its own instance, with the stores as they are when it
+                // arrives and guest backends tied to it.
+                Some(template) => {
+                    Either::Left(tower::service_fn(move |req: http::Request<AxumBody>| {
+                        use tower::{Layer, ServiceExt};
+
+                        let (backends, hop) = chain.enter(&req);
+                        let builder = template.exec_ctx.new_instance().with_backends(backends);
+                        let instance = stores::init_stores(
+                            &db,
+                            builder,
+                            scope.as_ref(),
+                            template.stores,
+                            &master_key,
+                        );
+                        let db = db.clone();
+
+                        async move {
+                            let (builder, kv_snapshot) = match instance {
+                                Ok(instance) => instance,
+                                Err(err) => {
+                                    tracing::error!("Failed to initialize stores: {err}");
+                                    let resp = (
+                                        http::StatusCode::INTERNAL_SERVER_ERROR,
+                                        "Failed to initialize stores",
+                                    );
+                                    return Ok::<_, viceroy_lib::error::Error>(
+                                        resp.into_response(),
+                                    );
+                                }
+                            };
+
+                            let viceroy_service = util::ViceroyService::new(
+                                Arc::new(builder.finish()),
+                                local_addr,
+                                remote_addr,
+                            );
+                            // Guests change their own copy of the KV stores, so persist their
+                            // writes once they are done with the request.
+                            let write_back = stores::KVWriteBack::new(db, kv_snapshot);
+
+                            let resp = util::ViceroyCompatLayer
+                                .layer(viceroy_service)
+                                .oneshot(req)
+                                .await?;
+
+                            Ok(resp.map(|body| util::GuardedBody::wrap(body, (hop, write_back))))
+                        }
+                    }))
+                }
+                None => Either::Right(tower::service_fn(async |_req| {
+                    let resp = (
+                        http::StatusCode::SERVICE_UNAVAILABLE,
+                        "No active version with a package for this service",
+                    );
+
+                    Ok(resp.into_response())
+                })),
+            };
 
             let service = tower::ServiceBuilder::new()
+                // Before tracing, so calls from chained services continue their caller's trace.
+                .layer(hop_layer)
                 .layer(HandleErrorLayer::new(async |err| {
                     (
                         http::StatusCode::INTERNAL_SERVER_ERROR,
@@ -65,16 +317,13 @@ pub async fn run(
                     )
                 }))
                 .layer(trace_layer)
-                .layer(util::ViceroyCompatLayer)
-                .service(viceroy_service);
+                .layer(util::CdnLoopLayer)
+                .service(guest_service);
 
             Ok(service)
         }
     });
 
-    let listener = TcpListener::bind(listen_addr).await.into_diagnostic()?;
-    tracing::info!("Compute server listening on {listen_addr}");
-
     let cancel = subsys.create_cancellation_token();
 
     axum::serve(listener, make_service)
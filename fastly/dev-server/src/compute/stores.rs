@@ -1,80 +1,411 @@
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
 use redb::{Database, ReadTransaction, ReadableDatabase, ReadableTable};
 use viceroy_lib::ExecuteCtxBuilder;
+use viceroy_lib::config::{ObjectStores, ObjectValue};
 
-use crate::tables::{
-    ConfigStoreMetadata, ConfigStoreTable, KVStoreMetadata, KVStoreTable, METADATA_TABLE,
-    SecretStoreMetadata, SecretStoreTable,
-};
+use crate::crypto::MasterKey;
+use crate::kv::{KVTables, Write, WriteError};
+use crate::tables::{ConfigStoreTable, KVStoreTable, METADATA_TABLE, Metadata, SecretStoreTable};
+
+/// Service whose resource links decide which stores the guest can see.
+#[derive(Debug, Clone)]
+pub struct ServiceScope {
+    pub service_id: String,
+    /// Version whose links are used, defaulting to the active version
+    pub version: Option<u32>,
+}
+
+/// A store exposed to the guest, with every name it can be opened by.
+struct StoreBinding<'a> {
+    id: &'a str,
+    names: Vec<&'a str>,
+}
+
+/// Kinds of stores handed to a guest instance, from the hostcalls the guest imports.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreKinds {
+    pub config: bool,
+    pub kv: bool,
+    pub secret: bool,
+}
+
+impl StoreKinds {
+    /// Every kind, for guests whose imports aren't known.
+    pub const ALL: Self = StoreKinds {
+        config: true,
+        kv: true,
+        secret: true,
+    };
+}
+
+/// The KV items handed to a guest instance, kept to write the guest's changes back.
+///
+/// Guests read and list the instance's in-memory copy of their KV stores, which is loaded from
+/// the database in key order when the instance is created for a request, so listings match the
+/// management API.
+///
+/// Viceroy has no hook to serve KV hostcalls from the database, so writes made through the API
+/// while a request is handled aren't seen by its guest, and each request of a guest importing
+/// the KV hostcalls copies every live key of the stores it can see.
+#[derive(Clone, Default)]
+pub struct KVSnapshot {
+    object_stores: ObjectStores,
+    stores: Vec<KVStoreSnapshot>,
+}
+
+#[derive(Clone)]
+struct KVStoreSnapshot {
+    id: String,
+    names: Vec<String>,
+    /// Keys with the generation they had in the database when the instance was created
+    keys: HashMap<String, u64>,
+    /// For each name, the keys loaded under it with the generation Viceroy gave them, which
+    /// changes when the guest writes them
+    loaded: Vec<HashMap<String, u64>>,
+}
 
 pub fn init_stores(
     db: &Database,
     builder: ExecuteCtxBuilder,
-) -> Result<ExecuteCtxBuilder, redb::Error> {
-    let tx = db.begin_read().unwrap();
+    scope: Option<&ServiceScope>,
+    kinds: StoreKinds,
+    master_key: &MasterKey,
+) -> Result<(ExecuteCtxBuilder, KVSnapshot), redb::Error> {
+    let tx = db.begin_read()?;
 
     let Some(metadata_table) = open_table(&tx, METADATA_TABLE)? else {
-        return Ok(builder);
+        return Ok((builder, KVSnapshot::default()));
     };
     let Some(metadata_record) = metadata_table.get(&())? else {
-        return Ok(builder);
+        return Ok((builder, KVSnapshot::default()));
     };
     let metadata = &metadata_record.value().0;
 
-    let config_stores: Vec<_> = metadata
-        .config_stores
-        .iter()
-        .map(|(name, meta)| (name.as_str(), meta))
-        .collect();
-    let builder = init_config_stores(builder, &tx, &config_stores)?;
+    let mut builder = builder;
+    let mut kv_snapshot = KVSnapshot::default();
+
+    if kinds.config {
+        let config_stores = bind_stores(
+            metadata,
+            scope,
+            metadata
+                .config_stores
+                .iter()
+                .filter(|(_, meta)| meta.deleted_at.is_none())
+                .map(|(id, meta)| (id.as_str(), meta.name.as_str())),
+        );
+        builder = init_config_stores(builder, &tx, &config_stores)?;
+    }
+
+    if kinds.kv {
+        let kv_stores = bind_stores(
+            metadata,
+            scope,
+            metadata
+                .kv_stores
+                .iter()
+                .filter(|(_, meta)| meta.deleted_at.is_none())
+                .map(|(id, meta)| (id.as_str(), meta.name.as_str())),
+        );
+        (builder, kv_snapshot) = init_kv_stores(builder, &tx, &kv_stores)?;
+    }
+
+    if kinds.secret {
+        let secret_stores = bind_stores(
+            metadata,
+            scope,
+            metadata
+                .secret_stores
+                .iter()
+                .filter(|(_, meta)| meta.deleted_at.is_none())
+                .map(|(id, meta)| (id.as_str(), meta.name.as_str())),
+        );
+        builder = init_secret_stores(builder, &tx, &secret_stores, master_key)?;
+    }
+
+    Ok((builder, kv_snapshot))
+}
+
+/// Writes the changes a guest made to its KV stores back to the database once dropped.
+///
+/// Guests can keep writing keys while streaming their response body, so this is held by the
+/// response body rather than written back once the guest has responded.
+pub struct KVWriteBack {
+    db: Arc<Database>,
+    snapshot: KVSnapshot,
+}
+
+impl KVWriteBack {
+    pub fn new(db: Arc<Database>, snapshot: KVSnapshot) -> Self {
+        Self { db, snapshot }
+    }
+}
+
+impl Drop for KVWriteBack {
+    fn drop(&mut self) {
+        if self.snapshot.stores.is_empty() {
+            return;
+        }
+
+        let db = self.db.clone();
+        let snapshot = std::mem::take(&mut self.snapshot);
+        let write_back = move || {
+            if let Err(err) = write_back_kv_changes(&db, &snapshot, chrono::Utc::now()) {
+                tracing::error!("Failed to write back KV store changes: {err}");
+            }
+        };
+
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(write_back);
+            }
+            Err(_) => write_back(),
+        }
+    }
+}
+
+/// A key a guest wrote or deleted in its copy of a KV store.
+struct GuestChange<'a> {
+    store_id: &'a str,
+    key: String,
+    /// Generation of the key in the database when the instance was created, if it was live
+    generation: Option<u64>,
+    /// The value the guest left, or `None` if it deleted the key
+    value: Option<ObjectValue>,
+}
+
+/// Apply the inserts and deletes a guest made to its KV stores to the database.
+///
+/// Keys written through the API since the instance was created are left alone, as the guest
+/// changed an older generation.
+fn write_back_kv_changes(
+    db: &Database,
+    snapshot: &KVSnapshot,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<(), redb::Error> {
+    let changes = guest_changes(snapshot);
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    let tx = db.begin_write()?;
+
+    for change in changes {
+        let stats = {
+            let mut tables = KVTables::open(&tx, change.store_id)?;
 
-    let kv_stores: Vec<_> = metadata
-        .kv_stores
-        .iter()
-        .map(|(name, meta)| (name.as_str(), meta))
-        .collect();
-    let builder = init_kv_stores(builder, &tx, &kv_stores)?;
+            let current = tables.get_item(change.key.clone())?;
+            let unchanged = match change.generation {
+                Some(generation) => current.is_some_and(|item| item.generation == generation),
+                None => current.is_none_or(|item| item.is_expired(now)),
+            };
+            if !unchanged {
+                tracing::warn!(
+                    "Dropping a guest's change to key `{}` of KV store {}, which was written meanwhile",
+                    change.key,
+                    change.store_id,
+                );
+                continue;
+            }
+
+            match change.value {
+                Some(value) => {
+                    let write = Write {
+                        value: value.body.into(),
+                        metadata: (!value.metadata.is_empty()).then_some(value.metadata),
+                        time_to_live: value.expiration.and_then(|expiration| {
+                            let ttl = expiration
+                                .duration_since(std::time::SystemTime::now())
+                                .unwrap_or_default();
+                            chrono::Duration::from_std(ttl).ok()
+                        }),
+                        ..Default::default()
+                    };
+                    match tables.write_item(change.key, write, now) {
+                        Ok(_) => {}
+                        Err(WriteError::Storage(err)) => return Err(err.into()),
+                        Err(err) => unreachable!("unconditional overwrite failed: {err}"),
+                    }
+                }
+                None => {
+                    tables.remove_item(change.key)?;
+                }
+            }
+
+            tables.stats()
+        };
+
+        stats.commit(&tx, change.store_id)?;
+    }
 
-    let secret_stores: Vec<_> = metadata
-        .secret_stores
-        .iter()
-        .map(|(name, meta)| (name.as_str(), meta))
-        .collect();
-    let builder = init_secret_stores(builder, &tx, &secret_stores)?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Compare every name's copy of the KV stores with what was loaded into it.
+fn guest_changes(snapshot: &KVSnapshot) -> Vec<GuestChange<'_>> {
+    use viceroy_lib::config::{ObjectKey, ObjectStoreKey};
+
+    let mut changes = Vec::new();
+
+    for store in &snapshot.stores {
+        for (name, loaded) in store.names.iter().zip(&store.loaded) {
+            // Viceroy only knows stores something was inserted into, which guests can't open.
+            let Some(keys) = list_object_keys(&snapshot.object_stores, name) else {
+                if !loaded.is_empty() {
+                    tracing::warn!("Failed to list KV store {} as `{name}`", store.id);
+                }
+                continue;
+            };
+
+            for key in &keys {
+                let Ok(object_key) = ObjectKey::new(key) else {
+                    continue;
+                };
+                let Ok(Some(value)) = snapshot
+                    .object_stores
+                    .lookup(ObjectStoreKey::new(name), object_key)
+                else {
+                    continue;
+                };
+                if loaded.get(key) != Some(&value.generation) {
+                    changes.push(GuestChange {
+                        store_id: &store.id,
+                        key: key.clone(),
+                        generation: store.keys.get(key).copied(),
+                        value: Some(value),
+                    });
+                }
+            }
+
+            let listed = keys.into_iter().collect::<HashSet<_>>();
+            for key in loaded.keys().filter(|key| !listed.contains(*key)) {
+                changes.push(GuestChange {
+                    store_id: &store.id,
+                    key: key.clone(),
+                    generation: store.keys.get(key).copied(),
+                    value: None,
+                });
+            }
+        }
+    }
 
-    Ok(builder)
+    changes
+}
+
+/// Every live key of a name's copy of a KV store, through Viceroy's paginated listings.
+fn list_object_keys(object_stores: &ObjectStores, name: &str) -> Option<Vec<String>> {
+    use viceroy_lib::config::ObjectStoreKey;
+
+    #[derive(serde::Deserialize)]
+    struct Page {
+        data: Vec<String>,
+        meta: PageMeta,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct PageMeta {
+        next_cursor: Option<String>,
+    }
+
+    let mut keys = Vec::new();
+    let mut cursor = None;
+
+    loop {
+        let page = object_stores
+            .list(
+                ObjectStoreKey::new(name),
+                cursor,
+                None,
+                crate::kv::MAX_LIST_LIMIT as u32,
+            )
+            .ok()?;
+        let page: Page = serde_json::from_slice(&page).ok()?;
+        keys.extend(page.data);
+
+        match page.meta.next_cursor {
+            Some(next_cursor) => cursor = Some(next_cursor),
+            None => return Some(keys),
+        }
+    }
+}
+
+/// Without a scope, every store is exposed under both its ID and its name. With a scope, only
+/// stores linked to the service version are exposed, under their link names.
+fn bind_stores<'a>(
+    metadata: &'a Metadata,
+    scope: Option<&ServiceScope>,
+    stores: impl Iterator<Item = (&'a str, &'a str)>,
+) -> Vec<StoreBinding<'a>> {
+    let Some(scope) = scope else {
+        return stores
+            .map(|(id, name)| StoreBinding {
+                id,
+                names: vec![id, name],
+            })
+            .collect();
+    };
+
+    let version = scope.version.or_else(|| {
+        metadata
+            .services
+            .get(&scope.service_id)
+            .and_then(|service_meta| service_meta.active_version())
+            .map(|version_meta| version_meta.number)
+    });
+    let Some(version) = version else {
+        return Vec::new();
+    };
+
+    stores
+        .filter_map(|(id, _)| {
+            let names: Vec<&str> = metadata
+                .resource_links
+                .values()
+                .filter(|link| {
+                    link.service_id == scope.service_id
+                        && link.version == version
+                        && link.resource_id == id
+                })
+                .map(|link| link.name.as_str())
+                .collect();
+
+            (!names.is_empty()).then_some(StoreBinding { id, names })
+        })
+        .collect()
 }
 
 fn init_config_stores(
     builder: ExecuteCtxBuilder,
     tx: &ReadTransaction,
-    stores: &[(&str, &ConfigStoreMetadata)],
+    stores: &[StoreBinding],
 ) -> Result<ExecuteCtxBuilder, redb::Error> {
     use viceroy_lib::config::{Dictionaries, Dictionary};
 
     let mut dictionaries = Dictionaries::default();
 
-    for (id, meta) in stores {
-        let table_def = ConfigStoreTable::new(id);
+    for store in stores {
+        let table_def = ConfigStoreTable::new(store.id);
         let Some(table) = open_table(tx, table_def)? else {
             continue;
         };
 
         let data = table
             .iter()?
-            .filter_map(|res| res.ok())
-            .map(|(key, entry)| {
-                let key = key.value().clone();
-                let value = entry.value().0.item_value.clone();
-                (key, value)
+            .map(|entry| {
+                let (key, entry) = entry?;
+                Ok((key.value(), entry.value().0.item_value))
             })
-            .collect();
+            .collect::<Result<_, redb::StorageError>>()?;
         let dictionary = Dictionary::InlineToml {
             contents: Arc::new(data),
         };
-        dictionaries.insert(id.to_string(), dictionary.clone());
-        dictionaries.insert(meta.name.clone(), dictionary);
+        for name in &store.names {
+            dictionaries.insert(name.to_string(), dictionary.clone());
+        }
     }
 
     Ok(builder.with_dictionaries(dictionaries))
@@ -83,88 +414,138 @@ fn init_config_stores(
 fn init_kv_stores(
     builder: ExecuteCtxBuilder,
     tx: &ReadTransaction,
-    stores: &[(&str, &KVStoreMetadata)],
-) -> Result<ExecuteCtxBuilder, redb::Error> {
-    use viceroy_lib::config::{ObjectKey, ObjectStoreKey, ObjectStores};
+    stores: &[StoreBinding],
+) -> Result<(ExecuteCtxBuilder, KVSnapshot), redb::Error> {
+    let snapshot = load_kv_stores(tx, stores)?;
+
+    Ok((
+        builder.with_object_stores(snapshot.object_stores.clone()),
+        snapshot,
+    ))
+}
+
+fn load_kv_stores(
+    tx: &ReadTransaction,
+    stores: &[StoreBinding],
+) -> Result<KVSnapshot, redb::Error> {
+    use viceroy_lib::config::{ObjectKey, ObjectStoreKey};
     use viceroy_lib::wiggle_abi::types::KvInsertMode;
 
     let object_stores = ObjectStores::default();
+    let mut snapshots = Vec::with_capacity(stores.len());
+    let now = chrono::Utc::now();
 
-    for (id, meta) in stores {
-        let table_def = KVStoreTable::new(id);
+    for store in stores {
+        let table_def = KVStoreTable::new(store.id);
         let Some(table) = open_table(tx, table_def)? else {
             continue;
         };
 
-        let entries = table.iter()?.filter_map(|res| res.ok());
-        for entry in entries {
-            let (key, record) = entry;
-            let key = key.value().clone();
-            let value = record.value().0.value.clone();
-
-            let store_key = ObjectStoreKey::new(id.to_string());
-            let store_alias_key = ObjectStoreKey::new(meta.name.clone());
-            let object_key = ObjectKey::new(key).expect("Invalid KV store key");
-
-            object_stores
-                .insert(
-                    store_key,
-                    object_key.clone(),
-                    value.to_vec(),
-                    KvInsertMode::Overwrite,
-                    Some(1),
-                    None,
-                    None,
-                )
-                .expect("Failed to insert into object store");
-            object_stores
-                .insert(
-                    store_alias_key,
-                    object_key.clone(),
-                    value.to_vec(),
-                    KvInsertMode::Overwrite,
-                    Some(1),
-                    None,
-                    None,
-                )
-                .expect("Failed to insert into object store");
+        let mut keys = HashMap::new();
+        let mut loaded = vec![HashMap::new(); store.names.len()];
+
+        for entry in table.iter()? {
+            let (key, record) = entry?;
+            let key = key.value();
+            let item = record.value().0;
+            if item.is_expired(now) {
+                continue;
+            }
+
+            // The API accepts keys Viceroy doesn't, such as `.`, unless limits are enforced.
+            let Ok(object_key) = ObjectKey::new(&key) else {
+                tracing::warn!(
+                    "Skipping key `{key}` of KV store {}, which guests can't look up",
+                    store.id
+                );
+                continue;
+            };
+
+            for (name, loaded) in store.names.iter().zip(&mut loaded) {
+                let object_store_key = ObjectStoreKey::new(name.to_string());
+                let inserted = object_stores
+                    .insert(
+                        object_store_key.clone(),
+                        object_key.clone(),
+                        item.value.to_vec(),
+                        KvInsertMode::Overwrite,
+                        None,
+                        item.metadata.clone(),
+                        item.time_to_live(now),
+                    )
+                    .and_then(|()| object_stores.lookup(object_store_key, object_key.clone()));
+                match inserted {
+                    Ok(Some(value)) => {
+                        loaded.insert(key.clone(), value.generation);
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        tracing::warn!("Skipping key `{key}` of KV store {}: {err:?}", store.id);
+                    }
+                }
+            }
+
+            keys.insert(key, item.generation);
         }
+
+        snapshots.push(KVStoreSnapshot {
+            id: store.id.to_string(),
+            names: store.names.iter().map(|name| name.to_string()).collect(),
+            keys,
+            loaded,
+        });
     }
 
-    Ok(builder.with_object_stores(object_stores))
+    Ok(KVSnapshot {
+        object_stores,
+        stores: snapshots,
+    })
 }
 
+/// Secrets are only ever decrypted here, when handed to the guest.
 fn init_secret_stores(
     builder: ExecuteCtxBuilder,
     tx: &ReadTransaction,
-    stores: &[(&str, &SecretStoreMetadata)],
+    stores: &[StoreBinding],
+    master_key: &MasterKey,
 ) -> Result<ExecuteCtxBuilder, redb::Error> {
     use viceroy_lib::config::{SecretStore, SecretStores};
 
     let mut secret_stores = SecretStores::default();
 
-    for (id, meta) in stores {
-        let table_def = SecretStoreTable::new(id);
+    for store in stores {
+        let table_def = SecretStoreTable::new(store.id);
         let Some(table) = open_table(tx, table_def)? else {
             continue;
         };
 
         let mut secret_store = SecretStore::new();
 
-        for entry in table.iter()?.filter_map(|res| res.ok()) {
-            let (key, record) = entry;
-            let key = key.value().clone();
-            let value = record.value().0.secret.clone();
+        for entry in table.iter()? {
+            let (key, record) = entry?;
+            let key = key.value();
+            let item = record.value().0;
+            let value = match &item.envelope {
+                Some(envelope) => match master_key.open(&item.secret, envelope) {
+                    Ok(secret) => secret.into(),
+                    Err(err) => {
+                        tracing::error!("Failed to decrypt secret `{key}`: {err}");
+                        continue;
+                    }
+                },
+                None => item.secret,
+            };
             secret_store.add_secret(key, value);
         }
-        secret_stores.add_store(id.to_string(), secret_store.clone());
-        secret_stores.add_store(meta.name.clone(), secret_store);
+        for name in &store.names {
+            secret_stores.add_store(name.to_string(), secret_store.clone());
+        }
     }
 
     Ok(builder.with_secret_stores(secret_stores))
 }
 
-fn open_table<K: redb::Key, V: redb::Value>(
+pub(super) fn open_table<K: redb::Key, V: redb::Value>(
     tx: &ReadTransaction,
     table_def: redb::TableDefinition<K, V>,
 ) -> Result<Option<redb::ReadOnlyTable<K, V>>, redb::Error> {
@@ -174,3 +555,266 @@ fn open_table<K: redb::Key, V: redb::Value>(
         Err(err) => Err(err.into()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::tables::{ResourceLinkMetadata, ServiceMetadata, ServiceVersionMetadata};
+
+    /// A service with an active version 1 and a draft version 2, linking stores to each.
+    fn metadata(links: &[(u32, &str, &str)]) -> Metadata {
+        let now = Utc::now();
+        let version = |number, active| ServiceVersionMetadata {
+            number,
+            active,
+            locked: active,
+            comment: String::new(),
+            domains: Vec::new(),
+            package: None,
+            created_at: now,
+            updated_at: now,
+        };
+
+        let mut metadata = Metadata::default();
+        metadata.services.insert(
+            "service".to_string(),
+            ServiceMetadata {
+                name: "app".to_string(),
+                comment: String::new(),
+                versions: vec![version(1, true), version(2, false)],
+                created_at: now,
+                updated_at: now,
+            },
+        );
+        for (i, (version, name, resource_id)) in links.iter().enumerate() {
+            metadata.resource_links.insert(
+                format!("link-{i}"),
+                ResourceLinkMetadata {
+                    service_id: "service".to_string(),
+                    version: *version,
+                    name: name.to_string(),
+                    resource_id: resource_id.to_string(),
+                    created_at: now,
+                    updated_at: now,
+                },
+            );
+        }
+
+        metadata
+    }
+
+    const STORES: [(&str, &str); 3] = [("a-id", "a"), ("b-id", "b"), ("c-id", "c")];
+
+    fn bound<'a>(
+        metadata: &'a Metadata,
+        scope: Option<&ServiceScope>,
+    ) -> Vec<(&'a str, Vec<&'a str>)> {
+        let mut bound: Vec<_> = bind_stores(metadata, scope, STORES.into_iter())
+            .into_iter()
+            .map(|binding| {
+                let mut names = binding.names;
+                names.sort();
+                (binding.id, names)
+            })
+            .collect();
+        bound.sort();
+        bound
+    }
+
+    fn scope(version: Option<u32>) -> ServiceScope {
+        ServiceScope {
+            service_id: "service".to_string(),
+            version,
+        }
+    }
+
+    #[test]
+    fn every_store_is_bound_without_a_scope() {
+        let metadata = metadata(&[(1, "alias", "a-id")]);
+
+        assert_eq!(
+            bound(&metadata, None),
+            [
+                ("a-id", vec!["a", "a-id"]),
+                ("b-id", vec!["b", "b-id"]),
+                ("c-id", vec!["c", "c-id"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_linked_stores_are_bound_under_their_link_names() {
+        let metadata = metadata(&[
+            (1, "alias", "a-id"),
+            (1, "other", "a-id"),
+            (1, "b", "b-id"),
+            (2, "c", "c-id"),
+        ]);
+
+        // The active version by default
+        assert_eq!(
+            bound(&metadata, Some(&scope(None))),
+            [("a-id", vec!["alias", "other"]), ("b-id", vec!["b"])]
+        );
+        assert_eq!(
+            bound(&metadata, Some(&scope(Some(2)))),
+            [("c-id", vec!["c"])]
+        );
+        assert!(bound(&metadata, Some(&scope(Some(3)))).is_empty());
+    }
+
+    #[test]
+    fn nothing_is_bound_without_an_active_version() {
+        let mut metadata = metadata(&[(1, "a", "a-id")]);
+        metadata.services.get_mut("service").unwrap().versions[0].active = false;
+
+        assert!(bound(&metadata, Some(&scope(None))).is_empty());
+        assert!(
+            bound(
+                &metadata,
+                Some(&ServiceScope {
+                    service_id: "missing".to_string(),
+                    version: None,
+                })
+            )
+            .is_empty()
+        );
+    }
+
+    /// Load a KV store with the given keys into a guest's copy, under the name `store`.
+    async fn guest_copy(db: &Arc<Database>, keys: &[&str]) -> (axum::Router, String, KVSnapshot) {
+        let app = crate::api::testing::app_with_db(db.clone());
+        let store_id = crate::api::testing::create_store(&app, "kv", "store").await;
+        for key in keys {
+            put(&app, &store_id, key, "api").await;
+        }
+
+        let tx = db.begin_read().unwrap();
+        let bindings = [StoreBinding {
+            id: &store_id,
+            names: vec!["store"],
+        }];
+        let snapshot = load_kv_stores(&tx, &bindings).unwrap();
+
+        (app, store_id, snapshot)
+    }
+
+    async fn put(app: &axum::Router, store_id: &str, key: &str, value: &str) {
+        let uri = format!("/resources/stores/kv/{store_id}/keys/{key}");
+        let response =
+            crate::api::testing::send(app, http::Method::PUT, &uri, &[], value.to_string()).await;
+        assert_eq!(response.status, 200, "{:?}", response.body);
+    }
+
+    fn guest_insert(snapshot: &KVSnapshot, key: &str, value: &str, metadata: Option<&str>) {
+        use viceroy_lib::config::{ObjectKey, ObjectStoreKey};
+        use viceroy_lib::wiggle_abi::types::KvInsertMode;
+
+        snapshot
+            .object_stores
+            .insert(
+                ObjectStoreKey::new("store"),
+                ObjectKey::new(key).unwrap(),
+                value.as_bytes().to_vec(),
+                KvInsertMode::Overwrite,
+                None,
+                metadata.map(str::to_string),
+                Some(std::time::Duration::from_secs(60)),
+            )
+            .unwrap();
+    }
+
+    fn guest_delete(snapshot: &KVSnapshot, key: &str) {
+        use viceroy_lib::config::{ObjectKey, ObjectStoreKey};
+
+        snapshot
+            .object_stores
+            .delete(ObjectStoreKey::new("store"), ObjectKey::new(key).unwrap())
+            .unwrap();
+    }
+
+    /// Value and metadata of each item of a KV store.
+    fn items(db: &Database, store_id: &str) -> Vec<(String, String, Option<String>)> {
+        let tx = db.begin_read().unwrap();
+        let table = open_table(&tx, KVStoreTable::new(store_id))
+            .unwrap()
+            .unwrap();
+        table
+            .iter()
+            .unwrap()
+            .map(|entry| {
+                let (key, record) = entry.unwrap();
+                let item = record.value().0;
+                (
+                    key.value(),
+                    String::from_utf8(item.value.to_vec()).unwrap(),
+                    item.metadata,
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn guest_inserts_and_deletes_are_written_back() {
+        let db = crate::context::memory_db();
+        let (_app, store_id, snapshot) = guest_copy(&db, &["changed", "deleted", "kept"]).await;
+
+        guest_insert(&snapshot, "changed", "guest", None);
+        guest_delete(&snapshot, "deleted");
+        guest_insert(&snapshot, "new", "guest", Some("meta"));
+        write_back_kv_changes(&db, &snapshot, Utc::now()).unwrap();
+
+        assert_eq!(
+            items(&db, &store_id),
+            [
+                ("changed".to_string(), "guest".to_string(), None),
+                ("kept".to_string(), "api".to_string(), None),
+                (
+                    "new".to_string(),
+                    "guest".to_string(),
+                    Some("meta".to_string())
+                ),
+            ]
+        );
+
+        let tx = db.begin_read().unwrap();
+        let table = open_table(&tx, KVStoreTable::new(&store_id))
+            .unwrap()
+            .unwrap();
+        let new = table.get("new".to_string()).unwrap().unwrap().value().0;
+        assert!(new.expires_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn keys_written_through_the_api_meanwhile_are_kept() {
+        let db = crate::context::memory_db();
+        let (app, store_id, snapshot) = guest_copy(&db, &["changed", "deleted"]).await;
+
+        guest_insert(&snapshot, "changed", "guest", None);
+        guest_delete(&snapshot, "deleted");
+        guest_insert(&snapshot, "new", "guest", None);
+        for key in ["changed", "deleted", "new"] {
+            put(&app, &store_id, key, "meanwhile").await;
+        }
+        write_back_kv_changes(&db, &snapshot, Utc::now()).unwrap();
+
+        assert_eq!(
+            items(&db, &store_id),
+            [
+                ("changed".to_string(), "meanwhile".to_string(), None),
+                ("deleted".to_string(), "meanwhile".to_string(), None),
+                ("new".to_string(), "meanwhile".to_string(), None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn untouched_stores_are_left_alone() {
+        let db = crate::context::memory_db();
+        let (_app, _store_id, snapshot) = guest_copy(&db, &["a", "b"]).await;
+
+        assert!(guest_changes(&snapshot).is_empty());
+    }
+}
@@ -67,6 +67,140 @@ where
     }
 }
 
+pub(super) static CDN_LOOP: http::HeaderName = http::HeaderName::from_static("cdn-loop");
+
+/// Value appended to the `CDN-Loop` header of every request entering a service.
+const CDN_LOOP_TOKEN: &str = "Fastly";
+
+/// Maximum number of `CDN-Loop` hops before a request is rejected as looping.
+const CDN_LOOP_MAX_HOPS: usize = 20;
+
+pub struct CdnLoopLayer;
+
+impl<S> Layer<S> for CdnLoopLayer {
+    type Service = CdnLoop<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CdnLoop { inner }
+    }
+}
+
+/// Records service hops in the `CDN-Loop` header, like the Fastly edge does, and
+/// rejects requests bouncing between chained services.
+#[derive(Clone)]
+pub struct CdnLoop<S> {
+    inner: S,
+}
+
+impl<S> Service<Request<AxumBody>> for CdnLoop<S>
+where
+    S: Service<Request<AxumBody>, Response = Response<AxumBody>>,
+{
+    type Response = Response<AxumBody>;
+    type Error = S::Error;
+    type Future = CdnLoopFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<AxumBody>) -> Self::Future {
+        let hops = req
+            .headers()
+            .get_all(&CDN_LOOP)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter(|entry| {
+                let cdn_id = entry.split(';').next().unwrap_or_default().trim();
+                cdn_id.eq_ignore_ascii_case(CDN_LOOP_TOKEN)
+            })
+            .count();
+
+        if hops >= CDN_LOOP_MAX_HOPS {
+            tracing::warn!(hops, "Request loop detected between services");
+            return CdnLoopFuture::Rejected;
+        }
+
+        req.headers_mut().append(
+            CDN_LOOP.clone(),
+            http::HeaderValue::from_static(CDN_LOOP_TOKEN),
+        );
+
+        CdnLoopFuture::Inner {
+            inner: self.inner.call(req),
+        }
+    }
+}
+
+#[pin_project::pin_project(project = CdnLoopFutureProj)]
+pub enum CdnLoopFuture<Fut> {
+    Inner {
+        #[pin]
+        inner: Fut,
+    },
+    Rejected,
+}
+
+impl<Fut, E> Future for CdnLoopFuture<Fut>
+where
+    Fut: Future<Output = Result<Response<AxumBody>, E>>,
+{
+    type Output = Result<Response<AxumBody>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Self::Output> {
+        match self.project() {
+            CdnLoopFutureProj::Inner { inner } => inner.poll(cx),
+            CdnLoopFutureProj::Rejected => {
+                let resp = Response::builder()
+                    .status(http::StatusCode::SERVICE_UNAVAILABLE)
+                    .body(AxumBody::from("Loop detected"))
+                    .unwrap();
+
+                task::Poll::Ready(Ok(resp))
+            }
+        }
+    }
+}
+
+/// A response body holding on to something until the body is done with, whether it was sent
+/// completely or not.
+#[pin_project::pin_project]
+pub struct GuardedBody<G> {
+    #[pin]
+    inner: AxumBody,
+    _guard: G,
+}
+
+impl<G: Send + 'static> GuardedBody<G> {
+    pub fn wrap(inner: AxumBody, guard: G) -> AxumBody {
+        AxumBody::new(GuardedBody {
+            inner,
+            _guard: guard,
+        })
+    }
+}
+
+impl<G> http_body::Body for GuardedBody<G> {
+    type Data = bytes::Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> task::Poll<Option<Result<http_body::Frame<Self::Data>, Self::Error>>> {
+        self.project().inner.poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
 #[derive(Clone)]
 pub struct ViceroyService {
     exec_ctx: Arc<ExecuteCtx>,
@@ -1,9 +1,13 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use miette::{IntoDiagnostic, Result};
+use miette::{IntoDiagnostic, Result, WrapErr};
 use redb::Database;
 
+use crate::crypto::MasterKey;
+use crate::tables::StoreStats;
+use crate::util::JsonRecord;
+
 pub fn open_db(db_path: &Path) -> Result<Arc<Database>> {
     let mut db = Database::create(db_path).into_diagnostic()?;
 
@@ -11,5 +15,413 @@ pub fn open_db(db_path: &Path) -> Result<Arc<Database>> {
         tracing::info!("Database was compacted");
     }
 
+    let backfilled = backfill_store_stats(&db).into_diagnostic()?;
+    if backfilled > 0 {
+        tracing::info!("Computed item statistics of {backfilled} stores");
+    }
+
+    let indexed = backfill_kv_expiry(&db).into_diagnostic()?;
+    if indexed > 0 {
+        tracing::info!("Indexed the expiry of {indexed} KV store items");
+    }
+
+    let orphan_tables = orphan_tables(&db).into_diagnostic()?;
+    if !orphan_tables.is_empty() {
+        tracing::warn!(
+            "Database has tables without a matching store: {}",
+            orphan_tables.join(", ")
+        );
+    }
+
     Ok(Arc::new(db))
 }
+
+/// Empty in-memory database, for tests.
+#[cfg(test)]
+pub fn memory_db() -> Arc<Database> {
+    let db = Database::builder()
+        .create_with_backend(redb::backends::InMemoryBackend::new())
+        .unwrap();
+
+    Arc::new(db)
+}
+
+/// Where the key secrets are encrypted with at rest comes from.
+#[derive(Debug, Clone)]
+pub enum MasterKeySource {
+    File(PathBuf),
+    Env,
+}
+
+/// Load the key secrets are encrypted with at rest, from `encoded` if set, otherwise from
+/// `key_file`, or [`default_key_file`] if unset, which is created with a new key if missing.
+pub fn load_master_key(
+    encoded: Option<&str>,
+    key_file: Option<&Path>,
+    store_path: &Path,
+) -> Result<(MasterKey, MasterKeySource)> {
+    if let Some(encoded) = encoded {
+        let key = MasterKey::from_base64(encoded).into_diagnostic()?;
+        return Ok((key, MasterKeySource::Env));
+    }
+
+    let key_file = match key_file {
+        Some(key_file) => key_file.to_path_buf(),
+        None => default_key_file(store_path, config_dir())?,
+    };
+
+    let key = match std::fs::read_to_string(&key_file) {
+        Ok(encoded) => MasterKey::from_base64(&encoded)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Invalid secret key file {}", key_file.display()))?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let key = MasterKey::generate();
+            write_key_file(&key_file, &key)?;
+            tracing::info!("Created secret key file {}", key_file.display());
+            key
+        }
+        Err(err) => return Err(err).into_diagnostic(),
+    };
+
+    Ok((key, MasterKeySource::File(key_file)))
+}
+
+/// The key file used without `--secret-key-file`, kept in the user's config directory rather
+/// than next to the store, so copying the store's directory doesn't copy its key along.
+///
+/// Older versions kept it next to the store with a `.key` extension, in which case it's moved.
+fn default_key_file(store_path: &Path, config_dir: Option<PathBuf>) -> Result<PathBuf> {
+    let Some(config_dir) = config_dir else {
+        miette::bail!(
+            "No config directory to keep the secret key in, set `--secret-key-file` or \
+             `FASTLY_DEV_SERVER_SECRET_KEY`"
+        );
+    };
+    let key_file = config_dir.join("fastly-dev-server").join("secret.key");
+
+    let legacy = store_path.with_extension("key");
+    if legacy.is_file() {
+        if key_file.exists() {
+            miette::bail!(
+                "Found secret key files {} and {}, set `--secret-key-file` to the one the store \
+                 was encrypted with",
+                legacy.display(),
+                key_file.display()
+            );
+        }
+
+        let key = std::fs::read_to_string(&legacy).into_diagnostic()?;
+        let key = MasterKey::from_base64(&key)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Invalid secret key file {}", legacy.display()))?;
+        create_key_dir(&key_file)?;
+        write_key_file(&key_file, &key)?;
+        std::fs::remove_file(&legacy).into_diagnostic()?;
+        tracing::warn!(
+            "Moved secret key file {} out of the store's directory to {}",
+            legacy.display(),
+            key_file.display()
+        );
+    }
+
+    create_key_dir(&key_file)?;
+    Ok(key_file)
+}
+
+/// The user's config directory, following the XDG base directory spec outside Windows.
+fn config_dir() -> Option<PathBuf> {
+    let from_env = |name| {
+        std::env::var_os(name)
+            .map(PathBuf::from)
+            .filter(|path| path.is_absolute())
+    };
+
+    if cfg!(windows) {
+        from_env("APPDATA")
+    } else {
+        from_env("XDG_CONFIG_HOME").or_else(|| from_env("HOME").map(|home| home.join(".config")))
+    }
+}
+
+/// Create the directory of a key file, only accessible by its owner.
+fn create_key_dir(key_file: &Path) -> Result<()> {
+    let Some(dir) = key_file.parent() else {
+        return Ok(());
+    };
+
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+
+    builder
+        .create(dir)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to create {}", dir.display()))
+}
+
+/// Write a key to a new file only readable by its owner.
+pub fn write_key_file(path: &Path, key: &MasterKey) -> Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options
+        .open(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to create secret key file {}", path.display()))?;
+    writeln!(file, "{}", key.to_base64()).into_diagnostic()?;
+    file.sync_all().into_diagnostic()
+}
+
+/// Find store tables whose store no longer exists in the metadata, such as tables written to
+/// before writes checked that their store exists.
+fn orphan_tables(db: &Database) -> Result<Vec<String>, redb::Error> {
+    use redb::{ReadableDatabase, TableHandle};
+
+    use crate::tables::METADATA_TABLE;
+
+    let tx = db.begin_read()?;
+
+    let metadata = match tx.open_table(METADATA_TABLE) {
+        Ok(table) => table.get(&())?.map(|record| record.value().0),
+        Err(redb::TableError::TableDoesNotExist(_)) => None,
+        Err(e) => return Err(e.into()),
+    }
+    .unwrap_or_default();
+
+    let orphan_tables = tx
+        .list_tables()?
+        .map(|table| table.name().to_string())
+        // Internal tables are named `__<name>__`, store tables by their store ID.
+        .filter(|name| !name.starts_with("__"))
+        .filter(|name| {
+            !metadata.config_stores.contains_key(name)
+                && !metadata.kv_stores.contains_key(name)
+                && !metadata.secret_stores.contains_key(name)
+        })
+        .collect();
+
+    Ok(orphan_tables)
+}
+
+/// Compute the statistics of stores created before they were kept up to date by writes,
+/// returning how many stores were updated.
+fn backfill_store_stats(db: &Database) -> Result<u64, redb::Error> {
+    use redb::{ReadableTable, TableHandle};
+
+    use crate::tables::{ConfigStoreTable, KVStoreTable, METADATA_TABLE, SecretStoreTable};
+
+    let tx = db.begin_write()?;
+
+    let mut backfilled = 0;
+
+    {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let Some(mut metadata) = metadata_table.get(&())?.map(|record| record.value().0) else {
+            return Ok(0);
+        };
+
+        // Opening the table of a store never written to would create it.
+        let tables = tx
+            .list_tables()?
+            .map(|table| table.name().to_string())
+            .collect::<Vec<_>>();
+        let exists = |id: &String| tables.contains(id);
+
+        for (id, store) in &mut metadata.config_stores {
+            if store.stats.is_none() {
+                store.stats = Some(match exists(id) {
+                    true => {
+                        table_stats(&tx, ConfigStoreTable::new(id), |key, item| item.size(key))?
+                    }
+                    false => StoreStats::default(),
+                });
+                backfilled += 1;
+            }
+        }
+        for (id, store) in &mut metadata.kv_stores {
+            if store.stats.is_none() {
+                store.stats = Some(match exists(id) {
+                    true => table_stats(&tx, KVStoreTable::new(id), |key, item| item.size(key))?,
+                    false => StoreStats::default(),
+                });
+                backfilled += 1;
+            }
+        }
+        for (id, store) in &mut metadata.secret_stores {
+            if store.stats.is_none() {
+                store.stats = Some(match exists(id) {
+                    true => table_stats(&tx, SecretStoreTable::new(id), |_, item| item.size())?,
+                    false => StoreStats::default(),
+                });
+                backfilled += 1;
+            }
+        }
+
+        if backfilled > 0 {
+            metadata_table.insert(&(), &JsonRecord(metadata))?;
+        }
+    }
+
+    tx.commit()?;
+
+    Ok(backfilled)
+}
+
+/// Index the expiry of KV store items written before expiring items were indexed, returning
+/// how many items were indexed.
+///
+/// Writes keep the index up to date once it exists, so this only runs while it's missing.
+fn backfill_kv_expiry(db: &Database) -> Result<u64, redb::Error> {
+    use redb::{ReadableTable, TableHandle};
+
+    use crate::tables::{KV_EXPIRY_TABLE, KVStoreTable, METADATA_TABLE};
+
+    let tx = db.begin_write()?;
+
+    let tables = tx
+        .list_tables()?
+        .map(|table| table.name().to_string())
+        .collect::<Vec<_>>();
+    if tables.iter().any(|name| name == KV_EXPIRY_TABLE.name()) {
+        return Ok(0);
+    }
+
+    let mut indexed = 0;
+
+    {
+        let metadata = tx
+            .open_table(METADATA_TABLE)?
+            .get(&())?
+            .map(|record| record.value().0)
+            .unwrap_or_default();
+
+        let mut expiry = tx.open_table(KV_EXPIRY_TABLE)?;
+
+        for id in metadata.kv_stores.keys() {
+            // Opening the table of a store never written to would create it.
+            if !tables.contains(id) {
+                continue;
+            }
+
+            for entry in tx.open_table(KVStoreTable::new(id))?.iter()? {
+                let (key, record) = entry?;
+                if let Some(expires_at) = record.value().0.expires_at {
+                    expiry.insert((expires_at.timestamp_millis(), id.clone(), key.value()), ())?;
+                    indexed += 1;
+                }
+            }
+        }
+    }
+
+    tx.commit()?;
+
+    Ok(indexed)
+}
+
+fn table_stats<T>(
+    tx: &redb::WriteTransaction,
+    definition: redb::TableDefinition<String, JsonRecord<T>>,
+    size: impl Fn(&str, &T) -> usize,
+) -> Result<StoreStats, redb::Error>
+where
+    T: std::fmt::Debug + serde::Serialize + serde::de::DeserializeOwned,
+{
+    use redb::ReadableTable;
+
+    let mut stats = StoreStats::default();
+
+    for entry in tx.open_table(definition)?.iter()? {
+        let (key, record) = entry?;
+        stats.item_count += 1;
+        stats.total_size += size(&key.value(), &record.value().0) as u64;
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kv_expiry_is_backfilled_once() {
+        use chrono::{Duration, Utc};
+
+        use crate::kv::{KVTables, Write};
+        use crate::tables::{KV_EXPIRY_TABLE, KVStoreMetadata, METADATA_TABLE, Metadata};
+
+        let db = memory_db();
+        let now = Utc::now();
+
+        let tx = db.begin_write().unwrap();
+        {
+            let mut metadata = Metadata::default();
+            metadata.kv_stores.insert(
+                "store".to_string(),
+                KVStoreMetadata {
+                    name: "store".to_string(),
+                    stats: None,
+                    created_at: now,
+                    updated_at: now,
+                    deleted_at: None,
+                },
+            );
+            let mut metadata_table = tx.open_table(METADATA_TABLE).unwrap();
+            metadata_table.insert(&(), &JsonRecord(metadata)).unwrap();
+
+            let mut tables = KVTables::open(&tx, "store").unwrap();
+            for (key, time_to_live) in [("expiring", Some(Duration::minutes(1))), ("kept", None)] {
+                let write = Write {
+                    time_to_live,
+                    ..Write::default()
+                };
+                tables.write_item(key.to_string(), write, now).unwrap();
+            }
+        }
+        tx.commit().unwrap();
+
+        // As written before expiring items were indexed
+        let tx = db.begin_write().unwrap();
+        assert!(tx.delete_table(KV_EXPIRY_TABLE).unwrap());
+        tx.commit().unwrap();
+
+        assert_eq!(backfill_kv_expiry(&db).unwrap(), 1);
+        assert_eq!(backfill_kv_expiry(&db).unwrap(), 0);
+
+        let later = now + Duration::minutes(2);
+        assert_eq!(crate::kv::sweep_expired(&db, later).unwrap(), 1);
+    }
+
+    #[test]
+    fn key_file_defaults_outside_the_store_directory() {
+        let dir = std::env::temp_dir().join(format!("fastly-dev-server-{}", ulid::Ulid::new()));
+        let store_path = dir.join("data").join("store.db");
+        let config_dir = dir.join("config");
+        std::fs::create_dir_all(store_path.parent().unwrap()).unwrap();
+
+        let key_file = default_key_file(&store_path, Some(config_dir.clone())).unwrap();
+        assert_eq!(key_file, config_dir.join("fastly-dev-server/secret.key"));
+        assert!(!key_file.exists());
+
+        // A key file kept next to the store by older versions is moved out of it
+        let key = MasterKey::generate();
+        write_key_file(&store_path.with_extension("key"), &key).unwrap();
+        let moved = default_key_file(&store_path, Some(config_dir.clone())).unwrap();
+        assert_eq!(moved, key_file);
+        assert!(!store_path.with_extension("key").exists());
+        let (loaded, _) = load_master_key(None, Some(&moved), &store_path).unwrap();
+        assert_eq!(loaded.id(), key.id());
+
+        // Unless that would replace another key
+        write_key_file(&store_path.with_extension("key"), &MasterKey::generate()).unwrap();
+        assert!(default_key_file(&store_path, Some(config_dir)).is_err());
+        assert!(default_key_file(&store_path, None).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,554 @@
+use bytes::Bytes;
+use crypto_box::aead::OsRng;
+use crypto_box::{PublicKey, SecretKey};
+use ed25519_dalek::{Signer, SigningKey};
+
+use crate::tables::Envelope;
+
+#[derive(Debug, thiserror::Error)]
+pub enum UnsealError {
+    #[error("Unknown client key")]
+    UnknownClientKey,
+    #[error("Failed to decrypt the secret")]
+    Decrypt,
+}
+
+/// Keys used by the Fastly CLI to encrypt secrets before sending them, like Fastly's secret
+/// store API.
+///
+/// The CLI fetches the client key and its signature, checks the signature against the signing
+/// key and seals secrets with the client key in a libsodium sealed box. Both keys only live as
+/// long as the process, as clients fetch them right before using them.
+pub struct ClientKeys {
+    client_key: SecretKey,
+    signing_key: SigningKey,
+}
+
+impl ClientKeys {
+    pub fn generate() -> Self {
+        use crypto_box::aead::rand_core::RngCore;
+
+        let mut seed = [0; ed25519_dalek::SECRET_KEY_LENGTH];
+        OsRng.fill_bytes(&mut seed);
+
+        ClientKeys {
+            client_key: SecretKey::generate(&mut OsRng),
+            signing_key: SigningKey::from_bytes(&seed),
+        }
+    }
+
+    /// Public X25519 key secrets are sealed with.
+    pub fn client_key(&self) -> PublicKey {
+        self.client_key.public_key()
+    }
+
+    /// Ed25519 signature of the client key.
+    pub fn client_key_signature(&self) -> [u8; ed25519_dalek::SIGNATURE_LENGTH] {
+        self.signing_key
+            .sign(self.client_key().as_bytes())
+            .to_bytes()
+    }
+
+    /// Public Ed25519 key the client key signature is checked with.
+    pub fn signing_key(&self) -> [u8; ed25519_dalek::PUBLIC_KEY_LENGTH] {
+        self.signing_key.verifying_key().to_bytes()
+    }
+
+    /// Open a secret sealed with `client_key`, which must be this process' client key.
+    pub fn unseal(&self, client_key: &[u8], sealed: &[u8]) -> Result<Vec<u8>, UnsealError> {
+        if client_key != self.client_key().as_bytes() {
+            return Err(UnsealError::UnknownClientKey);
+        }
+
+        self.client_key
+            .unseal(sealed)
+            .map_err(|_| UnsealError::Decrypt)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SecretKeyError {
+    #[error("Secret key must be 32 base64 encoded bytes")]
+    InvalidKey,
+    #[error("Secret was encrypted with another key ({0})")]
+    WrongKey(String),
+    #[error("Failed to decrypt the secret")]
+    Decrypt,
+}
+
+/// Key secret store values are encrypted with at rest.
+///
+/// Each secret is encrypted with its own data key, which is encrypted with this key, so rotating
+/// it only re-encrypts data keys.
+pub struct MasterKey {
+    key: crypto_secretbox::Key,
+    id: String,
+}
+
+impl MasterKey {
+    pub fn generate() -> Self {
+        use crypto_secretbox::{KeyInit, XSalsa20Poly1305};
+
+        Self::new(XSalsa20Poly1305::generate_key(&mut OsRng))
+    }
+
+    fn new(key: crypto_secretbox::Key) -> Self {
+        use sha2::{Digest, Sha256};
+
+        let hash = Sha256::digest(key);
+        let id = hash[..8].iter().map(|byte| format!("{byte:02x}")).collect();
+
+        MasterKey { key, id }
+    }
+
+    pub fn from_base64(encoded: &str) -> Result<Self, SecretKeyError> {
+        use base64::Engine;
+        use base64::engine::general_purpose::STANDARD;
+
+        let bytes = STANDARD
+            .decode(encoded.trim())
+            .map_err(|_| SecretKeyError::InvalidKey)?;
+        let key = <[u8; 32]>::try_from(bytes).map_err(|_| SecretKeyError::InvalidKey)?;
+
+        Ok(Self::new(key.into()))
+    }
+
+    pub fn to_base64(&self) -> String {
+        use base64::Engine;
+        use base64::engine::general_purpose::STANDARD;
+
+        STANDARD.encode(self.key)
+    }
+
+    /// Short fingerprint of the key, recorded with the data keys it encrypts.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Encrypt a secret with a new data key, returning the ciphertext and its envelope.
+    pub fn seal(&self, plaintext: &[u8]) -> (Bytes, Envelope) {
+        use crypto_secretbox::aead::Aead;
+        use crypto_secretbox::{AeadCore, KeyInit, XSalsa20Poly1305};
+
+        let data_key = XSalsa20Poly1305::generate_key(&mut OsRng);
+        let nonce = XSalsa20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = XSalsa20Poly1305::new(&data_key)
+            .encrypt(&nonce, plaintext)
+            .expect("Failed to encrypt secret");
+
+        let (data_key, data_key_nonce) = self.wrap(&data_key);
+
+        let envelope = Envelope {
+            key_id: self.id.clone(),
+            data_key,
+            data_key_nonce,
+            nonce: Bytes::copy_from_slice(&nonce),
+        };
+
+        (Bytes::from(ciphertext), envelope)
+    }
+
+    /// Decrypt a secret sealed with this key.
+    pub fn open(&self, ciphertext: &[u8], envelope: &Envelope) -> Result<Vec<u8>, SecretKeyError> {
+        use crypto_secretbox::aead::Aead;
+        use crypto_secretbox::{KeyInit, Nonce, XSalsa20Poly1305};
+
+        let data_key = self.unwrap(envelope)?;
+        let nonce = Nonce::from_exact_iter(envelope.nonce.iter().copied())
+            .ok_or(SecretKeyError::Decrypt)?;
+
+        XSalsa20Poly1305::new(&data_key)
+            .decrypt(&nonce, ciphertext)
+            .map_err(|_| SecretKeyError::Decrypt)
+    }
+
+    /// Move an envelope sealed with `self` to `new`, leaving the secret's ciphertext unchanged.
+    pub fn rewrap(&self, envelope: &Envelope, new: &MasterKey) -> Result<Envelope, SecretKeyError> {
+        let data_key = self.unwrap(envelope)?;
+        let (wrapped, data_key_nonce) = new.wrap(&data_key);
+
+        Ok(Envelope {
+            key_id: new.id.clone(),
+            data_key: wrapped,
+            data_key_nonce,
+            nonce: envelope.nonce.clone(),
+        })
+    }
+
+    /// Digest of a secret, as reported by the API.
+    ///
+    /// Like on Fastly, it's keyed by the store, so the same secret has different digests in
+    /// different stores while its digest only changes with its value. The HMAC key is derived
+    /// from this key, so digests can't be checked against guessed secrets without it.
+    pub fn secret_digest(&self, store_id: &str, secret: &[u8]) -> Bytes {
+        use hmac::{Hmac, Mac};
+        use sha2::Sha256;
+
+        let mut digest_key =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        digest_key.update(b"secret digest");
+        let digest_key = digest_key.finalize().into_bytes();
+
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&digest_key).expect("HMAC accepts keys of any length");
+        mac.update(store_id.as_bytes());
+        mac.update(&[0]);
+        mac.update(secret);
+
+        Bytes::from(mac.finalize().into_bytes().to_vec())
+    }
+
+    fn wrap(&self, data_key: &crypto_secretbox::Key) -> (Bytes, Bytes) {
+        use crypto_secretbox::aead::Aead;
+        use crypto_secretbox::{AeadCore, KeyInit, XSalsa20Poly1305};
+
+        let nonce = XSalsa20Poly1305::generate_nonce(&mut OsRng);
+        let wrapped = XSalsa20Poly1305::new(&self.key)
+            .encrypt(&nonce, data_key.as_slice())
+            .expect("Failed to encrypt data key");
+
+        (Bytes::from(wrapped), Bytes::copy_from_slice(&nonce))
+    }
+
+    fn unwrap(&self, envelope: &Envelope) -> Result<crypto_secretbox::Key, SecretKeyError> {
+        use crypto_secretbox::aead::Aead;
+        use crypto_secretbox::{Key, KeyInit, Nonce, XSalsa20Poly1305};
+
+        if envelope.key_id != self.id {
+            return Err(SecretKeyError::WrongKey(envelope.key_id.clone()));
+        }
+
+        let nonce = Nonce::from_exact_iter(envelope.data_key_nonce.iter().copied())
+            .ok_or(SecretKeyError::Decrypt)?;
+        let data_key = XSalsa20Poly1305::new(&self.key)
+            .decrypt(&nonce, envelope.data_key.as_ref())
+            .map_err(|_| SecretKeyError::Decrypt)?;
+
+        Key::from_exact_iter(data_key).ok_or(SecretKeyError::Decrypt)
+    }
+}
+
+/// Encrypt every stored secret with `new`, returning how many were updated.
+///
+/// Data keys encrypted with `current` are re-encrypted with `new`, and digests are keyed with
+/// `new`. Secrets still stored in plaintext are encrypted; with `new` being `current`, they're
+/// only updated along with digests not keyed with it yet.
+pub fn reencrypt_secrets(
+    db: &redb::Database,
+    current: &MasterKey,
+    new: &MasterKey,
+) -> miette::Result<u64> {
+    use miette::IntoDiagnostic;
+    use redb::ReadableTable;
+
+    use crate::tables::{METADATA_TABLE, SecretStoreTable, StatsDelta};
+    use crate::util::JsonRecord;
+
+    let tx = db.begin_write().into_diagnostic()?;
+
+    let mut updated = 0;
+
+    {
+        let Some(metadata) = tx
+            .open_table(METADATA_TABLE)
+            .into_diagnostic()?
+            .get(&())
+            .into_diagnostic()?
+            .map(|record| record.value().0)
+        else {
+            return Ok(0);
+        };
+
+        let table_names = tx
+            .list_tables()
+            .into_diagnostic()?
+            .map(|table| redb::TableHandle::name(&table).to_string())
+            .collect::<Vec<_>>();
+
+        for store_id in metadata.secret_stores.keys() {
+            if !table_names.contains(store_id) {
+                continue;
+            }
+
+            let mut table = tx
+                .open_table(SecretStoreTable::new(store_id))
+                .into_diagnostic()?;
+
+            let items = table
+                .iter()
+                .into_diagnostic()?
+                .map(|entry| entry.map(|(key, record)| (key.value(), record.value().0)))
+                .collect::<Result<Vec<_>, _>>()
+                .into_diagnostic()?;
+
+            let mut stats = StatsDelta::default();
+
+            for (name, mut item) in items {
+                let size = item.size();
+                let secret_error = |err| miette::miette!("Secret `{name}`: {err}");
+
+                let stale_digest = item.digest_key_id.as_deref() != Some(new.id());
+
+                match item.envelope.take() {
+                    Some(envelope) if envelope.key_id == new.id && !stale_digest => continue,
+                    Some(envelope) => {
+                        let key = if envelope.key_id == new.id {
+                            new
+                        } else {
+                            current
+                        };
+                        if stale_digest {
+                            let secret = key.open(&item.secret, &envelope).map_err(secret_error)?;
+                            item.digest = Some(new.secret_digest(store_id, &secret));
+                        }
+                        item.envelope = Some(match envelope.key_id == new.id {
+                            true => envelope,
+                            false => current.rewrap(&envelope, new).map_err(secret_error)?,
+                        });
+                    }
+                    None => {
+                        let (ciphertext, envelope) = new.seal(&item.secret);
+                        item.digest = Some(new.secret_digest(store_id, &item.secret));
+                        item.secret = ciphertext;
+                        item.envelope = Some(envelope);
+                    }
+                }
+                item.digest_key_id = Some(new.id.clone());
+
+                stats.write(Some(size), item.size());
+                table.insert(&name, &JsonRecord(item)).into_diagnostic()?;
+                updated += 1;
+            }
+
+            drop(table);
+            stats.commit(&tx, store_id).into_diagnostic()?;
+        }
+    }
+
+    tx.commit().into_diagnostic()?;
+
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tables::{
+        METADATA_TABLE, Metadata, SecretStoreItemMetadata, SecretStoreMetadata, SecretStoreTable,
+    };
+    use crate::util::JsonRecord;
+
+    #[test]
+    fn seal_and_open() {
+        let key = MasterKey::generate();
+
+        let (ciphertext, envelope) = key.seal(b"secret");
+        assert_ne!(ciphertext.as_ref(), b"secret");
+        assert_eq!(envelope.key_id, key.id());
+        assert_eq!(key.open(&ciphertext, &envelope).unwrap(), b"secret");
+
+        let mut tampered = ciphertext.to_vec();
+        tampered[0] ^= 1;
+        assert!(matches!(
+            key.open(&tampered, &envelope),
+            Err(SecretKeyError::Decrypt)
+        ));
+
+        assert!(matches!(
+            MasterKey::generate().open(&ciphertext, &envelope),
+            Err(SecretKeyError::WrongKey(id)) if id == key.id()
+        ));
+    }
+
+    #[test]
+    fn keys_round_trip_through_base64() {
+        let key = MasterKey::generate();
+
+        let decoded = MasterKey::from_base64(&format!("{}\n", key.to_base64())).unwrap();
+        assert_eq!(decoded.id(), key.id());
+
+        assert!(matches!(
+            MasterKey::from_base64("c2hvcnQ="),
+            Err(SecretKeyError::InvalidKey)
+        ));
+    }
+
+    #[test]
+    fn rewrap_moves_envelopes_to_the_new_key() {
+        let current = MasterKey::generate();
+        let new = MasterKey::generate();
+        let (ciphertext, envelope) = current.seal(b"secret");
+
+        let rewrapped = current.rewrap(&envelope, &new).unwrap();
+
+        assert_eq!(rewrapped.key_id, new.id());
+        assert_eq!(new.open(&ciphertext, &rewrapped).unwrap(), b"secret");
+        assert!(current.open(&ciphertext, &rewrapped).is_err());
+        assert!(matches!(
+            new.rewrap(&envelope, &current),
+            Err(SecretKeyError::WrongKey(_))
+        ));
+    }
+
+    /// Database with a secret store holding the given items.
+    fn secret_store(items: Vec<SecretStoreItemMetadata>) -> std::sync::Arc<redb::Database> {
+        let db = crate::context::memory_db();
+        let now = chrono::Utc::now();
+
+        let mut metadata = Metadata::default();
+        metadata.secret_stores.insert(
+            "store".to_string(),
+            SecretStoreMetadata {
+                name: "store".to_string(),
+                stats: None,
+                created_at: now,
+                updated_at: None,
+                deleted_at: None,
+            },
+        );
+
+        let tx = db.begin_write().unwrap();
+        tx.open_table(METADATA_TABLE)
+            .unwrap()
+            .insert(&(), &JsonRecord(metadata))
+            .unwrap();
+        {
+            let mut table = tx.open_table(SecretStoreTable::new("store")).unwrap();
+            for item in items {
+                table.insert(&item.name.clone(), &JsonRecord(item)).unwrap();
+            }
+        }
+        tx.commit().unwrap();
+
+        db
+    }
+
+    /// A secret named after its value, sealed with `key` as written by the API, or in plaintext.
+    fn item(name: &str, key: Option<&MasterKey>) -> SecretStoreItemMetadata {
+        let (secret, envelope) = match key {
+            Some(key) => {
+                let (ciphertext, envelope) = key.seal(name.as_bytes());
+                (ciphertext, Some(envelope))
+            }
+            None => (Bytes::copy_from_slice(name.as_bytes()), None),
+        };
+
+        SecretStoreItemMetadata {
+            name: name.to_string(),
+            secret,
+            envelope,
+            digest: key.map(|key| key.secret_digest("store", name.as_bytes())),
+            digest_key_id: key.map(|key| key.id().to_string()),
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    fn read_items(db: &redb::Database) -> Vec<SecretStoreItemMetadata> {
+        use redb::{ReadableDatabase, ReadableTable};
+
+        let tx = db.begin_read().unwrap();
+        let table = tx.open_table(SecretStoreTable::new("store")).unwrap();
+        table
+            .iter()
+            .unwrap()
+            .map(|entry| entry.unwrap().1.value().0)
+            .collect()
+    }
+
+    #[test]
+    fn reencrypting_moves_every_secret_to_the_new_key() {
+        let current = MasterKey::generate();
+        let new = MasterKey::generate();
+        let db = secret_store(vec![item("plain", None), item("sealed", Some(&current))]);
+
+        assert_eq!(reencrypt_secrets(&db, &current, &new).unwrap(), 2);
+
+        for item in read_items(&db) {
+            let envelope = item.envelope.as_ref().unwrap();
+            assert_eq!(envelope.key_id, new.id());
+            assert_eq!(
+                new.open(&item.secret, envelope).unwrap(),
+                item.name.as_bytes()
+            );
+            assert_eq!(
+                item.digest.unwrap(),
+                new.secret_digest("store", item.name.as_bytes())
+            );
+            assert_eq!(item.digest_key_id.as_deref(), Some(new.id()));
+        }
+
+        assert_eq!(reencrypt_secrets(&db, &new, &new).unwrap(), 0);
+    }
+
+    #[test]
+    fn reencrypting_with_the_same_key_only_encrypts_plaintext() {
+        let key = MasterKey::generate();
+        let sealed = item("sealed", Some(&key));
+        let db = secret_store(vec![item("plain", None), sealed.clone()]);
+
+        assert_eq!(reencrypt_secrets(&db, &key, &key).unwrap(), 1);
+
+        let items = read_items(&db);
+        assert_eq!(
+            key.open(&items[0].secret, items[0].envelope.as_ref().unwrap())
+                .unwrap(),
+            b"plain"
+        );
+        assert_eq!(items[1].secret, sealed.secret);
+    }
+
+    #[test]
+    fn digests_not_keyed_with_the_key_are_recomputed_once() {
+        let key = MasterKey::generate();
+        // As written when digests were keyed by the store alone
+        let legacy = SecretStoreItemMetadata {
+            digest: Some(Bytes::from_static(b"digest")),
+            digest_key_id: None,
+            ..item("sealed", Some(&key))
+        };
+        let db = secret_store(vec![legacy.clone()]);
+
+        assert_eq!(reencrypt_secrets(&db, &key, &key).unwrap(), 1);
+        assert_eq!(reencrypt_secrets(&db, &key, &key).unwrap(), 0);
+
+        let items = read_items(&db);
+        assert_eq!(items[0].secret, legacy.secret);
+        assert_eq!(
+            items[0].envelope.as_ref().unwrap().data_key,
+            legacy.envelope.unwrap().data_key
+        );
+        assert_eq!(
+            items[0].digest.as_ref().unwrap(),
+            &key.secret_digest("store", b"sealed")
+        );
+        assert_eq!(items[0].digest_key_id.as_deref(), Some(key.id()));
+    }
+
+    #[test]
+    fn digests_depend_on_the_key_and_the_store() {
+        let key = MasterKey::generate();
+        let digest = key.secret_digest("store", b"secret");
+
+        assert_eq!(digest, key.secret_digest("store", b"secret"));
+        assert_ne!(digest, key.secret_digest("other", b"secret"));
+        assert_ne!(digest, key.secret_digest("store", b"other"));
+        assert_ne!(
+            digest,
+            MasterKey::generate().secret_digest("store", b"secret")
+        );
+    }
+
+    #[test]
+    fn reencrypting_fails_without_changes_on_the_wrong_key() {
+        let other = MasterKey::generate();
+        let db = secret_store(vec![item("plain", None), item("sealed", Some(&other))]);
+
+        let current = MasterKey::generate();
+        let new = MasterKey::generate();
+        assert!(reencrypt_secrets(&db, &current, &new).is_err());
+
+        let items = read_items(&db);
+        assert!(items[0].envelope.is_none());
+        assert_eq!(items[1].envelope.as_ref().unwrap().key_id, other.id());
+    }
+}
@@ -0,0 +1,537 @@
+use std::collections::HashMap;
+use std::ops::Bound;
+
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use redb::ReadableTable;
+
+use crate::tables::{
+    KV_EXPIRY_TABLE, KVStoreItemMetadata, KVStoreTable, METADATA_TABLE, StatsDelta, StoreStats,
+};
+use crate::util::JsonRecord;
+
+/// Page size used when a listing doesn't ask for one, matching Fastly
+pub const DEFAULT_LIST_LIMIT: usize = 100;
+pub const MAX_LIST_LIMIT: usize = 1000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ListKeysError {
+    #[error("Invalid cursor")]
+    InvalidCursor,
+    #[error(transparent)]
+    Storage(#[from] redb::StorageError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WriteError {
+    #[error("Generation does not match")]
+    GenerationMismatch,
+    #[error("KV store item already exists")]
+    AlreadyExists,
+    #[error(transparent)]
+    Storage(#[from] redb::StorageError),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InsertMode {
+    #[default]
+    Overwrite,
+    Add,
+    Append,
+    Prepend,
+}
+
+impl InsertMode {
+    /// Mode selected by Fastly's `add`, `append` and `prepend` flags, of which at most one can
+    /// be set.
+    pub fn from_flags(add: bool, append: bool, prepend: bool) -> Option<Self> {
+        match (add, append, prepend) {
+            (false, false, false) => Some(InsertMode::Overwrite),
+            (true, false, false) => Some(InsertMode::Add),
+            (false, true, false) => Some(InsertMode::Append),
+            (false, false, true) => Some(InsertMode::Prepend),
+            _ => None,
+        }
+    }
+}
+
+/// A write to a KV store item, as accepted by Fastly's KV API.
+#[derive(Debug, Clone, Default)]
+pub struct Write {
+    pub value: Bytes,
+    pub metadata: Option<String>,
+    pub time_to_live: Option<chrono::Duration>,
+    pub mode: InsertMode,
+    pub if_generation_match: Option<u64>,
+}
+
+/// The tables touched by writes to a KV store: the store's own table and the expiry index.
+pub struct KVTables<'txn> {
+    store_id: String,
+    items: redb::Table<'txn, String, JsonRecord<KVStoreItemMetadata>>,
+    expiry: redb::Table<'txn, (i64, String, String), ()>,
+    stats: StatsDelta,
+}
+
+impl<'txn> KVTables<'txn> {
+    pub fn open(
+        tx: &'txn redb::WriteTransaction,
+        store_id: &str,
+    ) -> Result<Self, redb::TableError> {
+        Ok(KVTables {
+            store_id: store_id.to_string(),
+            items: tx.open_table(KVStoreTable::new(store_id))?,
+            expiry: tx.open_table(KV_EXPIRY_TABLE)?,
+            stats: StatsDelta::default(),
+        })
+    }
+
+    /// Apply a write to an item, returning its new generation.
+    ///
+    /// Preconditions are checked against the tables being written, so concurrent writers can't
+    /// both pass them.
+    pub fn write_item(
+        &mut self,
+        key: String,
+        write: Write,
+        now: DateTime<Utc>,
+    ) -> Result<u64, WriteError> {
+        let previous = self.items.get(&key)?.map(|record| record.value().0);
+        let current = previous.as_ref().filter(|item| !item.is_expired(now));
+
+        if let Some(expected) = write.if_generation_match
+            && current.is_none_or(|item| item.generation != expected)
+        {
+            return Err(WriteError::GenerationMismatch);
+        }
+        if write.mode == InsertMode::Add && current.is_some() {
+            return Err(WriteError::AlreadyExists);
+        }
+
+        let value = match (write.mode, current) {
+            (InsertMode::Append, Some(item)) => {
+                [item.value.as_ref(), write.value.as_ref()].concat().into()
+            }
+            (InsertMode::Prepend, Some(item)) => {
+                [write.value.as_ref(), item.value.as_ref()].concat().into()
+            }
+            _ => write.value,
+        };
+
+        // Appending and prepending only touch the value unless metadata or a TTL are given.
+        let (metadata, expires_at) = match (write.mode, current) {
+            (InsertMode::Append | InsertMode::Prepend, Some(item)) => (
+                write.metadata.or_else(|| item.metadata.clone()),
+                match write.time_to_live {
+                    Some(ttl) => now.checked_add_signed(ttl),
+                    None => item.expires_at,
+                },
+            ),
+            _ => (
+                write.metadata,
+                write
+                    .time_to_live
+                    .and_then(|ttl| now.checked_add_signed(ttl)),
+            ),
+        };
+
+        // Generations keep increasing across expiry so stale writers can't match them.
+        let generation = previous.as_ref().map_or(1, |item| item.generation + 1);
+        let created_at = current.map_or(now, |item| item.created_at);
+
+        if let Some(previous) = &previous {
+            self.unindex_expiry(&key, previous)?;
+        }
+
+        let item = KVStoreItemMetadata {
+            value,
+            metadata,
+            generation,
+            expires_at,
+            created_at,
+            updated_at: now,
+        };
+        if let Some(expires_at) = item.expires_at {
+            self.expiry.insert(
+                (
+                    expires_at.timestamp_millis(),
+                    self.store_id.clone(),
+                    key.clone(),
+                ),
+                (),
+            )?;
+        }
+        self.stats.write(
+            previous.as_ref().map(|previous| previous.size(&key)),
+            item.size(&key),
+        );
+        self.items.insert(&key, &JsonRecord(item))?;
+
+        Ok(generation)
+    }
+
+    /// Size of the value an item would have after a write, which appends and prepends add to.
+    pub fn written_value_size(
+        &self,
+        key: &str,
+        write: &Write,
+        now: DateTime<Utc>,
+    ) -> Result<usize, redb::StorageError> {
+        let current_size = match write.mode {
+            InsertMode::Append | InsertMode::Prepend => self
+                .items
+                .get(key.to_string())?
+                .map(|record| record.value().0)
+                .filter(|item| !item.is_expired(now))
+                .map_or(0, |item| item.value.len()),
+            _ => 0,
+        };
+
+        Ok(current_size + write.value.len())
+    }
+
+    /// Delete an item, returning it if it existed.
+    pub fn remove_item(
+        &mut self,
+        key: String,
+    ) -> Result<Option<KVStoreItemMetadata>, redb::StorageError> {
+        let Some(item) = self.items.remove(&key)?.map(|record| record.value().0) else {
+            return Ok(None);
+        };
+        self.unindex_expiry(&key, &item)?;
+        self.stats.remove(item.size(&key));
+
+        Ok(Some(item))
+    }
+
+    /// Change made to the store's statistics so far, to [commit](StatsDelta::commit) once the
+    /// tables are dropped.
+    pub fn stats(&self) -> StatsDelta {
+        self.stats
+    }
+
+    pub fn get_item(&self, key: String) -> Result<Option<KVStoreItemMetadata>, redb::StorageError> {
+        Ok(self.items.get(&key)?.map(|record| record.value().0))
+    }
+
+    fn unindex_expiry(
+        &mut self,
+        key: &str,
+        item: &KVStoreItemMetadata,
+    ) -> Result<(), redb::StorageError> {
+        if let Some(expires_at) = item.expires_at {
+            self.expiry.remove((
+                expires_at.timestamp_millis(),
+                self.store_id.clone(),
+                key.to_string(),
+            ))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Statistics of a KV store without the expired items the sweeper hasn't deleted yet.
+///
+/// Only the items due in the expiry index are looked at, which the sweeper keeps few.
+pub fn live_stats(
+    tx: &redb::ReadTransaction,
+    store_id: &str,
+    stats: StoreStats,
+    now: DateTime<Utc>,
+) -> Result<StoreStats, redb::Error> {
+    let (expiry, items) = match (
+        tx.open_table(KV_EXPIRY_TABLE),
+        tx.open_table(KVStoreTable::new(store_id)),
+    ) {
+        (Ok(expiry), Ok(items)) => (expiry, items),
+        (Err(redb::TableError::TableDoesNotExist(_)), _)
+        | (_, Err(redb::TableError::TableDoesNotExist(_))) => return Ok(stats),
+        (Err(e), _) | (_, Err(e)) => return Err(e.into()),
+    };
+
+    let mut expired = StatsDelta::default();
+
+    let until = (now.timestamp_millis() + 1, String::new(), String::new());
+    for entry in expiry.range(..until)? {
+        let (due, _) = entry?;
+        let (_, due_store_id, key) = due.value();
+        if due_store_id != store_id {
+            continue;
+        }
+
+        if let Some(item) = items.get(&key)?.map(|record| record.value().0)
+            && item.is_expired(now)
+        {
+            expired.remove(item.size(&key));
+        }
+    }
+
+    let mut stats = stats;
+    stats.apply(expired);
+
+    Ok(stats)
+}
+
+/// Delete the KV store items whose TTL has passed, returning how many were deleted.
+///
+/// Expired items are already hidden from readers; this reclaims their space by walking the
+/// expiry index rather than every store.
+pub fn sweep_expired(db: &redb::Database, now: DateTime<Utc>) -> Result<u64, redb::Error> {
+    let tx = db.begin_write()?;
+
+    let mut reaped = 0;
+    let mut stats = HashMap::<String, StatsDelta>::new();
+
+    {
+        let mut expiry = tx.open_table(KV_EXPIRY_TABLE)?;
+
+        let until = (now.timestamp_millis() + 1, String::new(), String::new());
+        let due = expiry
+            .range(..until)?
+            .map(|entry| entry.map(|(key, _)| key.value()))
+            .collect::<Result<Vec<_>, _>>()?;
+        if due.is_empty() {
+            return Ok(0);
+        }
+
+        let metadata = tx
+            .open_table(METADATA_TABLE)?
+            .get(&())?
+            .map(|record| record.value().0)
+            .unwrap_or_default();
+
+        for (expires_at, store_id, key) in due {
+            expiry.remove((expires_at, store_id.clone(), key.clone()))?;
+
+            // Index entries can outlive their store, whose table must not be recreated.
+            if !metadata.kv_stores.contains_key(&store_id) {
+                continue;
+            }
+
+            let mut items = tx.open_table(KVStoreTable::new(&store_id))?;
+            let expired = items
+                .get(&key)?
+                .map(|record| record.value().0)
+                .filter(|item| {
+                    item.is_expired(now)
+                        && item.expires_at.is_some_and(|item_expires_at| {
+                            item_expires_at.timestamp_millis() == expires_at
+                        })
+                });
+            if let Some(item) = expired {
+                items.remove(&key)?;
+                stats.entry(store_id).or_default().remove(item.size(&key));
+                reaped += 1;
+            }
+        }
+    }
+
+    for (store_id, stats) in stats {
+        stats.commit(&tx, &store_id)?;
+    }
+
+    tx.commit()?;
+
+    Ok(reaped)
+}
+
+/// Convert a time to live in seconds, as sent to the API, if it's in range.
+///
+/// TTLs too far out for an expiry time are accepted and never expire.
+pub fn time_to_live_secs(secs: u64) -> Option<chrono::Duration> {
+    chrono::Duration::try_seconds(i64::try_from(secs).ok()?)
+}
+
+/// One page of a KV store key listing.
+#[derive(Debug, Clone, Default)]
+pub struct KeyPage {
+    pub keys: Vec<String>,
+    /// Cursor to pass to get the next page, if there is one
+    pub next_cursor: Option<String>,
+}
+
+/// List the live keys of a KV store table in key order, starting after `cursor`.
+///
+/// Cursors are opaque to callers; they encode the last key of the previous page, so listing
+/// resumes correctly even when keys are added or removed between pages. They are encoded like
+/// Viceroy's, so cursors from guests and from the API can be used with either.
+pub fn list_keys(
+    table: &impl ReadableTable<String, JsonRecord<KVStoreItemMetadata>>,
+    prefix: &str,
+    cursor: Option<&str>,
+    limit: usize,
+    now: DateTime<Utc>,
+) -> Result<KeyPage, ListKeysError> {
+    let after = cursor.map(decode_cursor).transpose()?;
+
+    let start = match after {
+        Some(after) if after.as_str() >= prefix => Bound::Excluded(after),
+        _ => Bound::Included(prefix.to_string()),
+    };
+
+    let mut keys = Vec::new();
+    let mut has_more = false;
+
+    for entry in table.range::<String>((start, Bound::Unbounded))? {
+        let (key, record) = entry?;
+        let key = key.value();
+        if !key.starts_with(prefix) {
+            break;
+        }
+        if record.value().0.is_expired(now) {
+            continue;
+        }
+        if keys.len() == limit {
+            has_more = true;
+            break;
+        }
+        keys.push(key);
+    }
+
+    let next_cursor = if has_more {
+        keys.last().map(|key| STANDARD.encode(key))
+    } else {
+        None
+    };
+
+    Ok(KeyPage { keys, next_cursor })
+}
+
+/// Cursors handed out before they matched Viceroy's were URL-safe and unpadded, and are still
+/// accepted.
+fn decode_cursor(cursor: &str) -> Result<String, ListKeysError> {
+    let key = STANDARD
+        .decode(cursor)
+        .or_else(|_| URL_SAFE_NO_PAD.decode(cursor))
+        .map_err(|_| ListKeysError::InvalidCursor)?;
+    String::from_utf8(key).map_err(|_| ListKeysError::InvalidCursor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_to_live_range() {
+        let max = i64::MAX as u64 / 1000;
+
+        assert_eq!(time_to_live_secs(60), Some(chrono::Duration::minutes(1)));
+        assert_eq!(
+            time_to_live_secs(max),
+            Some(chrono::Duration::seconds(max as i64))
+        );
+        assert_eq!(time_to_live_secs(max + 1), None);
+        assert_eq!(time_to_live_secs(u64::MAX), None);
+    }
+
+    #[test]
+    fn time_to_live_past_the_last_expiry_time_never_expires() {
+        let db = crate::context::memory_db();
+        let tx = db.begin_write().unwrap();
+        let mut tables = KVTables::open(&tx, "store").unwrap();
+
+        let write = Write {
+            time_to_live: time_to_live_secs(i64::MAX as u64 / 1000),
+            ..Write::default()
+        };
+        tables
+            .write_item("key".to_string(), write, Utc::now())
+            .unwrap();
+
+        let item = tables.get_item("key".to_string()).unwrap().unwrap();
+        assert_eq!(item.expires_at, None);
+    }
+
+    #[test]
+    fn listing_skips_expired_keys() {
+        let db = crate::context::memory_db();
+        let tx = db.begin_write().unwrap();
+        let mut tables = KVTables::open(&tx, "store").unwrap();
+        let now = Utc::now();
+
+        for (key, time_to_live) in [("a", None), ("b", Some(1)), ("c", None)] {
+            let write = Write {
+                time_to_live: time_to_live.map(chrono::Duration::seconds),
+                ..Write::default()
+            };
+            tables.write_item(key.to_string(), write, now).unwrap();
+        }
+
+        let page = list_keys(&tables.items, "", None, 10, now).unwrap();
+        assert_eq!(page.keys, ["a", "b", "c"]);
+
+        let later = now + chrono::Duration::seconds(2);
+        let page = list_keys(&tables.items, "", None, 1, later).unwrap();
+        assert_eq!(page.keys, ["a"]);
+        let cursor = page.next_cursor.unwrap();
+        let page = list_keys(&tables.items, "", Some(&cursor), 1, later).unwrap();
+        assert_eq!(page.keys, ["c"]);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn cursors_match_viceroy() {
+        let db = crate::context::memory_db();
+        let tx = db.begin_write().unwrap();
+        let mut tables = KVTables::open(&tx, "store").unwrap();
+        let now = Utc::now();
+        for key in ["a>?", "b>?", "c"] {
+            tables
+                .write_item(key.to_string(), Write::default(), now)
+                .unwrap();
+        }
+
+        let page = list_keys(&tables.items, "", None, 1, now).unwrap();
+        let cursor = page.next_cursor.unwrap();
+        assert_eq!(cursor, "YT4/");
+
+        let page = list_keys(&tables.items, "", Some(&cursor), 1, now).unwrap();
+        assert_eq!(page.keys, ["b>?"]);
+
+        let legacy_cursor = URL_SAFE_NO_PAD.encode("a>?");
+        let page = list_keys(&tables.items, "", Some(&legacy_cursor), 1, now).unwrap();
+        assert_eq!(page.keys, ["b>?"]);
+    }
+
+    #[test]
+    fn live_stats_skip_expired_items() {
+        use redb::ReadableDatabase;
+
+        let db = crate::context::memory_db();
+        let now = Utc::now();
+
+        let tx = db.begin_write().unwrap();
+        let stats = {
+            let mut tables = KVTables::open(&tx, "store").unwrap();
+            for (key, time_to_live) in [("a", None), ("b", Some(1)), ("c", Some(60))] {
+                let write = Write {
+                    value: Bytes::from_static(b"value"),
+                    time_to_live: time_to_live.map(chrono::Duration::seconds),
+                    ..Write::default()
+                };
+                tables.write_item(key.to_string(), write, now).unwrap();
+            }
+            tables.stats()
+        };
+        tx.commit().unwrap();
+
+        let mut stats_before = StoreStats::default();
+        stats_before.apply(stats);
+
+        let tx = db.begin_read().unwrap();
+        let stats = live_stats(&tx, "store", stats_before, now).unwrap();
+        assert_eq!(stats.item_count, 3);
+        assert_eq!(stats.total_size, 18);
+
+        let later = now + chrono::Duration::seconds(2);
+        let stats = live_stats(&tx, "store", stats_before, later).unwrap();
+        assert_eq!(stats.item_count, 2);
+        assert_eq!(stats.total_size, 12);
+
+        let stats = live_stats(&tx, "other", StoreStats::default(), later).unwrap();
+        assert_eq!(stats.item_count, 0);
+    }
+}
@@ -7,7 +7,12 @@ mod api;
 mod cli;
 mod compute;
 mod context;
+mod crypto;
+mod kv;
+mod package;
+mod sweeper;
 mod tables;
+mod tokens;
 mod trace;
 mod util;
 
@@ -20,6 +25,18 @@ struct Options {
         env = "FASTLY_DEV_SERVER_STORE_PATH"
     )]
     pub store_path: PathBuf,
+    /// File holding the key secrets are encrypted with at rest, created if missing [default:
+    /// `fastly-dev-server/secret.key` in the user's config directory]
+    #[clap(long, env = "FASTLY_DEV_SERVER_SECRET_KEY_FILE")]
+    pub secret_key_file: Option<PathBuf>,
+    /// Base64 encoded key secrets are encrypted with at rest, instead of `--secret-key-file`
+    #[clap(
+        long,
+        env = "FASTLY_DEV_SERVER_SECRET_KEY",
+        hide_env_values = true,
+        conflicts_with = "secret_key_file"
+    )]
+    pub secret_key: Option<String>,
 
     #[clap(subcommand)]
     pub command: cli::Command,
@@ -33,5 +50,13 @@ async fn main() -> Result<()> {
 
     let db = context::open_db(&opts.store_path)?;
 
-    cli::run(opts.command, db).await
+    let load_master_key = || {
+        context::load_master_key(
+            opts.secret_key.as_deref(),
+            opts.secret_key_file.as_deref(),
+            &opts.store_path,
+        )
+    };
+
+    cli::run(opts.command, db, load_master_key).await
 }
@@ -0,0 +1,317 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Component, Path};
+
+use bytes::Bytes;
+use serde::Deserialize;
+
+const MANIFEST_PATH: &str = "fastly.toml";
+const MODULE_PATH: &str = "bin/main.wasm";
+
+/// Maximum size of a package archive, matching Fastly's upload limit
+pub const MAX_PACKAGE_SIZE: usize = 100 * 1024 * 1024;
+
+/// Maximum total size of the files of a package once decompressed
+pub const MAX_UNPACKED_SIZE: usize = 256 * 1024 * 1024;
+
+/// Manifest versions understood by the Fastly CLI
+const MANIFEST_VERSIONS: std::ops::RangeInclusive<u32> = 1..=3;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const WASM_MAGIC: &[u8] = b"\0asm";
+
+#[derive(Debug, thiserror::Error)]
+pub enum PackageError {
+    #[error("Failed to read package archive: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Package is {size} bytes, larger than the {max} bytes limit")]
+    TooLarge { size: usize, max: usize },
+    #[error("Package files are larger than the {max} bytes limit once decompressed")]
+    UnpackedTooLarge { max: usize },
+    #[error("Invalid path in package: `{0}`")]
+    InvalidPath(String),
+    #[error("Package must contain a single top-level directory")]
+    MultipleRoots,
+    #[error("Package is missing `{0}`")]
+    MissingFile(&'static str),
+    #[error("Invalid package manifest: {0}")]
+    InvalidManifest(#[from] toml::de::Error),
+    #[error("Package manifest is missing `{0}`")]
+    MissingManifestField(&'static str),
+    #[error("Unsupported manifest version {0}")]
+    UnsupportedManifestVersion(u32),
+    #[error("`{MODULE_PATH}` is not a WebAssembly module")]
+    InvalidModule,
+}
+
+/// The subset of `fastly.toml` the dev-server cares about.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Manifest {
+    pub manifest_version: Option<u32>,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub authors: Vec<String>,
+    #[serde(default)]
+    pub language: String,
+}
+
+/// A Fastly Compute package, as built by `fastly compute build` into `pkg/<name>.tar.gz`.
+#[derive(Debug, Clone)]
+pub struct Package {
+    pub manifest: Manifest,
+    pub module: Bytes,
+    /// Hex SHA-512 of the packaged files with their paths, in path order
+    pub files_hash: String,
+    /// Hex SHA-256 of the module
+    pub module_hash: String,
+}
+
+impl Package {
+    /// Read and validate a package archive, applying the same layout rules as Fastly's upload
+    /// endpoint: a single top-level directory holding `fastly.toml` and `bin/main.wasm`.
+    pub fn from_archive(archive: &[u8]) -> Result<Self, PackageError> {
+        use sha2::{Digest, Sha256, Sha512};
+
+        if archive.len() > MAX_PACKAGE_SIZE {
+            return Err(PackageError::TooLarge {
+                size: archive.len(),
+                max: MAX_PACKAGE_SIZE,
+            });
+        }
+
+        let mut files = unpack(archive, MAX_UNPACKED_SIZE)?;
+
+        // Each path and file is prefixed with its length, so moving bytes from a file to the next,
+        // or renaming a file, changes the hash.
+        let mut hasher = Sha512::new();
+        for (path, contents) in &files {
+            hasher.update((path.len() as u64).to_le_bytes());
+            hasher.update(path);
+            hasher.update((contents.len() as u64).to_le_bytes());
+            hasher.update(contents);
+        }
+        let files_hash = format!("{:x}", hasher.finalize());
+
+        let manifest = files
+            .get(MANIFEST_PATH)
+            .ok_or(PackageError::MissingFile(MANIFEST_PATH))?;
+        let manifest: Manifest = toml::from_slice(manifest)?;
+
+        let Some(manifest_version) = manifest.manifest_version else {
+            return Err(PackageError::MissingManifestField("manifest_version"));
+        };
+        if !MANIFEST_VERSIONS.contains(&manifest_version) {
+            return Err(PackageError::UnsupportedManifestVersion(manifest_version));
+        }
+        if manifest.name.is_empty() {
+            return Err(PackageError::MissingManifestField("name"));
+        }
+
+        let module = files
+            .remove(MODULE_PATH)
+            .ok_or(PackageError::MissingFile(MODULE_PATH))?;
+        if !module.starts_with(WASM_MAGIC) {
+            return Err(PackageError::InvalidModule);
+        }
+
+        Ok(Package {
+            manifest,
+            module_hash: format!("{:x}", Sha256::digest(&module)),
+            module: Bytes::from(module),
+            files_hash,
+        })
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, PackageError> {
+        let archive = std::fs::read(path)?;
+        Self::from_archive(&archive)
+    }
+}
+
+/// Check whether a file is a package archive rather than a bare Wasm (or WAT) module.
+pub fn is_package_file(path: &Path) -> std::io::Result<bool> {
+    let mut file = std::fs::File::open(path)?;
+
+    let mut magic = [0; GZIP_MAGIC.len()];
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == GZIP_MAGIC),
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Read the files of a package archive by their path relative to its top-level directory,
+/// reading at most `max_size` bytes in total.
+fn unpack(archive: &[u8], max_size: usize) -> Result<BTreeMap<String, Vec<u8>>, PackageError> {
+    use flate2::read::GzDecoder;
+
+    let mut root = None;
+    let mut files = BTreeMap::new();
+    let mut unpacked_size = 0;
+
+    let mut archive = tar::Archive::new(GzDecoder::new(archive));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+
+        let path = entry.path()?.into_owned();
+        let (entry_root, relative_path) = split_root(&path)?;
+
+        match &root {
+            None => root = Some(entry_root.to_string()),
+            Some(root) if root != entry_root => return Err(PackageError::MultipleRoots),
+            Some(_) => {}
+        }
+
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        // Sizes in entry headers can't be trusted, so stop reading past the limit instead.
+        let remaining = max_size - unpacked_size;
+        let mut contents = Vec::new();
+        (&mut entry)
+            .take(remaining as u64 + 1)
+            .read_to_end(&mut contents)?;
+
+        unpacked_size += contents.len();
+        if unpacked_size > max_size {
+            return Err(PackageError::UnpackedTooLarge { max: max_size });
+        }
+
+        files.insert(relative_path, contents);
+    }
+
+    Ok(files)
+}
+
+/// Split an archive path into its top-level directory and the path relative to it, rejecting
+/// paths that could escape the package.
+fn split_root(path: &Path) -> Result<(&str, String), PackageError> {
+    let invalid_path = || PackageError::InvalidPath(path.display().to_string());
+
+    let mut components = path.components().filter(|c| *c != Component::CurDir);
+
+    let root = match components.next() {
+        Some(Component::Normal(root)) => root.to_str().ok_or_else(invalid_path)?,
+        _ => return Err(invalid_path()),
+    };
+
+    let mut relative_path = Vec::new();
+    for component in components {
+        let Component::Normal(component) = component else {
+            return Err(invalid_path());
+        };
+        relative_path.push(component.to_str().ok_or_else(invalid_path)?);
+    }
+
+    Ok((root, relative_path.join("/")))
+}
+
+/// Build a package archive holding these files.
+#[cfg(test)]
+pub fn archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+    use flate2::{Compression, write::GzEncoder};
+
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+    for (path, contents) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, path, *contents).unwrap();
+    }
+
+    builder.into_inner().unwrap().finish().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &[u8] = b"manifest_version = 3\nname = \"app\"\n";
+
+    #[test]
+    fn reads_a_package() {
+        let package = Package::from_archive(&archive(&[
+            ("app/fastly.toml", MANIFEST),
+            ("app/bin/main.wasm", b"\0asm\x01\0\0\0"),
+        ]))
+        .unwrap();
+
+        assert_eq!(package.manifest.name, "app");
+        assert_eq!(package.module.as_ref(), b"\0asm\x01\0\0\0");
+        assert_eq!(package.files_hash.len(), 128);
+        assert_eq!(
+            package.module_hash,
+            "93a44bbb96c751218e4c00d479e4c14358122a389acca16205b1e4d0dc5f9476"
+        );
+    }
+
+    #[test]
+    fn files_hash_covers_paths_and_file_boundaries() {
+        let files_hash = |files: &[(&str, &[u8])]| {
+            let mut files = files.to_vec();
+            files.push(("app/fastly.toml", MANIFEST));
+            files.push(("app/bin/main.wasm", b"\0asm\x01\0\0\0"));
+            Package::from_archive(&archive(&files)).unwrap().files_hash
+        };
+
+        let hash = files_hash(&[("app/a", b"ab"), ("app/b", b"")]);
+        assert_ne!(hash, files_hash(&[("app/a", b"a"), ("app/b", b"b")]));
+        assert_ne!(hash, files_hash(&[("app/c", b"ab"), ("app/b", b"")]));
+        assert_eq!(hash, files_hash(&[("app/b", b""), ("app/a", b"ab")]));
+    }
+
+    #[test]
+    fn module_hash_only_covers_the_module() {
+        let module_hash = |files: &[(&str, &[u8])]| {
+            let mut files = files.to_vec();
+            files.push(("app/fastly.toml", MANIFEST));
+            Package::from_archive(&archive(&files)).unwrap().module_hash
+        };
+
+        let module: &[u8] = b"\0asm\x01\0\0\0";
+        assert_eq!(
+            module_hash(&[("app/bin/main.wasm", module)]),
+            module_hash(&[("app/bin/main.wasm", module), ("app/README", b"docs")])
+        );
+        assert_ne!(
+            module_hash(&[("app/bin/main.wasm", module)]),
+            module_hash(&[("app/bin/main.wasm", b"\0asm\x01\0\0\0\0\x02\x01a")])
+        );
+    }
+
+    #[test]
+    fn unpacked_size_is_limited() {
+        let archive = archive(&[("app/a", &[0; 600]), ("app/b", &[0; 600])]);
+
+        assert!(unpack(&archive, 1200).is_ok());
+        assert!(matches!(
+            unpack(&archive, 1199),
+            Err(PackageError::UnpackedTooLarge { max: 1199 })
+        ));
+        assert!(matches!(
+            unpack(&archive, 100),
+            Err(PackageError::UnpackedTooLarge { max: 100 })
+        ));
+    }
+
+    #[test]
+    fn paths_must_stay_in_one_root() {
+        assert!(matches!(
+            unpack(&archive(&[("app/a", b""), ("other/b", b"")]), 100),
+            Err(PackageError::MultipleRoots)
+        ));
+        assert!(matches!(
+            split_root(Path::new("app/../../etc/passwd")),
+            Err(PackageError::InvalidPath(_))
+        ));
+        assert!(matches!(
+            split_root(Path::new("/app/bin")),
+            Err(PackageError::InvalidPath(_))
+        ));
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use redb::Database;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+/// How often expired KV store items and deleted stores are looked for
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long soft deleted stores can be restored before they're purged
+const DELETED_STORE_RETENTION: chrono::TimeDelta = chrono::TimeDelta::days(7);
+
+/// Periodically delete expired KV store items and purge deleted stores until shutdown.
+pub async fn run(subsys: &mut SubsystemHandle, db: Arc<Database>) -> miette::Result<()> {
+    use opentelemetry::KeyValue;
+    use tokio::time::MissedTickBehavior;
+
+    let meter = opentelemetry::global::meter("fastly-dev-server");
+    let expired_items = meter
+        .u64_counter("fastly_dev_server.expired_items")
+        .with_description("Items deleted after their TTL passed")
+        .build();
+
+    let cancel = subsys.create_cancellation_token();
+
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            _ = interval.tick() => {}
+        }
+
+        let sweep = tokio::task::spawn_blocking({
+            let db = db.clone();
+            move || crate::kv::sweep_expired(&db, Utc::now())
+        });
+
+        match sweep.await {
+            Ok(Ok(0)) => {}
+            Ok(Ok(reaped)) => {
+                tracing::debug!("Deleted {reaped} expired KV store items");
+                expired_items.add(reaped, &[KeyValue::new("store.type", "kv")]);
+            }
+            Ok(Err(err)) => tracing::error!("Failed to delete expired KV store items: {err}"),
+            Err(err) => tracing::error!("KV store sweep panicked: {err}"),
+        }
+
+        let purge = tokio::task::spawn_blocking({
+            let db = db.clone();
+            move || purge_deleted_stores(&db, Utc::now())
+        });
+
+        match purge.await {
+            Ok(Ok(0)) => {}
+            Ok(Ok(purged)) => tracing::info!("Purged {purged} deleted stores"),
+            Ok(Err(err)) => tracing::error!("Failed to purge deleted stores: {err}"),
+            Err(err) => tracing::error!("Store purge panicked: {err}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Permanently delete the stores soft deleted more than [`DELETED_STORE_RETENTION`] ago, with
+/// their items and the resource links to them, returning how many were purged.
+fn purge_deleted_stores(db: &Database, now: DateTime<Utc>) -> Result<u64, redb::Error> {
+    use redb::ReadableTable;
+
+    use crate::tables::{ConfigStoreTable, KVStoreTable, METADATA_TABLE, SecretStoreTable};
+    use crate::util::JsonRecord;
+
+    fn due<T>(
+        stores: &HashMap<String, T>,
+        deleted_at: impl Fn(&T) -> Option<DateTime<Utc>>,
+        now: DateTime<Utc>,
+    ) -> Vec<String> {
+        stores
+            .iter()
+            .filter(|(_, store)| {
+                deleted_at(store)
+                    .is_some_and(|deleted_at| deleted_at + DELETED_STORE_RETENTION <= now)
+            })
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    let tx = db.begin_write()?;
+
+    let (config_stores, kv_stores, secret_stores) = {
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let Some(mut metadata) = metadata_table.get(&())?.map(|record| record.value().0) else {
+            return Ok(0);
+        };
+
+        let config_stores = due(&metadata.config_stores, |store| store.deleted_at, now);
+        let kv_stores = due(&metadata.kv_stores, |store| store.deleted_at, now);
+        let secret_stores = due(&metadata.secret_stores, |store| store.deleted_at, now);
+        if config_stores.is_empty() && kv_stores.is_empty() && secret_stores.is_empty() {
+            return Ok(0);
+        }
+
+        for id in &config_stores {
+            metadata.config_stores.remove(id);
+        }
+        for id in &kv_stores {
+            metadata.kv_stores.remove(id);
+        }
+        for id in &secret_stores {
+            metadata.secret_stores.remove(id);
+        }
+        metadata.resource_links.retain(|_, link| {
+            !(config_stores.contains(&link.resource_id)
+                || kv_stores.contains(&link.resource_id)
+                || secret_stores.contains(&link.resource_id))
+        });
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+
+        (config_stores, kv_stores, secret_stores)
+    };
+
+    for id in &config_stores {
+        tx.delete_table(ConfigStoreTable::new(id))?;
+    }
+    for id in &kv_stores {
+        tx.delete_table(KVStoreTable::new(id))?;
+    }
+    for id in &secret_stores {
+        tx.delete_table(SecretStoreTable::new(id))?;
+    }
+
+    tx.commit()?;
+
+    Ok((config_stores.len() + kv_stores.len() + secret_stores.len()) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tables::{KVStoreMetadata, METADATA_TABLE, Metadata, ResourceLinkMetadata};
+    use crate::util::JsonRecord;
+
+    fn read_metadata(db: &Database) -> Metadata {
+        use redb::ReadableDatabase;
+
+        let tx = db.begin_read().unwrap();
+        let table = tx.open_table(METADATA_TABLE).unwrap();
+        table.get(&()).unwrap().unwrap().value().0
+    }
+
+    #[test]
+    fn stores_are_purged_with_their_links_after_the_retention() {
+        let db = crate::context::memory_db();
+        let deleted_at = Utc::now();
+
+        let mut metadata = Metadata::default();
+        for (id, deleted_at) in [("deleted", Some(deleted_at)), ("live", None)] {
+            metadata.kv_stores.insert(
+                id.to_string(),
+                KVStoreMetadata {
+                    name: id.to_string(),
+                    stats: None,
+                    created_at: deleted_at.unwrap_or_default(),
+                    updated_at: deleted_at.unwrap_or_default(),
+                    deleted_at,
+                },
+            );
+            metadata.resource_links.insert(
+                format!("link-{id}"),
+                ResourceLinkMetadata {
+                    service_id: "service".to_string(),
+                    version: 1,
+                    name: id.to_string(),
+                    resource_id: id.to_string(),
+                    created_at: deleted_at.unwrap_or_default(),
+                    updated_at: deleted_at.unwrap_or_default(),
+                },
+            );
+        }
+        let tx = db.begin_write().unwrap();
+        tx.open_table(METADATA_TABLE)
+            .unwrap()
+            .insert(&(), &JsonRecord(metadata))
+            .unwrap();
+        tx.commit().unwrap();
+
+        let just_before = deleted_at + DELETED_STORE_RETENTION - chrono::TimeDelta::milliseconds(1);
+        assert_eq!(purge_deleted_stores(&db, just_before).unwrap(), 0);
+        assert!(read_metadata(&db).kv_stores.contains_key("deleted"));
+
+        let at_the_end = deleted_at + DELETED_STORE_RETENTION;
+        assert_eq!(purge_deleted_stores(&db, at_the_end).unwrap(), 1);
+
+        let metadata = read_metadata(&db);
+        assert!(!metadata.kv_stores.contains_key("deleted"));
+        assert!(metadata.kv_stores.contains_key("live"));
+        let links: Vec<_> = metadata.resource_links.keys().collect();
+        assert_eq!(links, ["link-live"]);
+    }
+}
@@ -15,17 +15,122 @@ pub struct Metadata {
     pub kv_stores: HashMap<String, KVStoreMetadata>,
     #[serde(default)]
     pub secret_stores: HashMap<String, SecretStoreMetadata>,
+    #[serde(default)]
+    pub resource_links: HashMap<String, ResourceLinkMetadata>,
+    #[serde(default)]
+    pub services: HashMap<String, ServiceMetadata>,
 }
 
 pub type MetaDataTable<'a> = TableDefinition<'a, (), JsonRecord<Metadata>>;
 
 pub const METADATA_TABLE: MetaDataTable = TableDefinition::new("__meta__");
 
+impl Metadata {
+    /// Item statistics of the config, KV or secret store with this ID.
+    pub fn store_stats(&self, store_id: &str) -> Option<StoreStats> {
+        let stats = if let Some(store) = self.config_stores.get(store_id) {
+            store.stats
+        } else if let Some(store) = self.kv_stores.get(store_id) {
+            store.stats
+        } else if let Some(store) = self.secret_stores.get(store_id) {
+            store.stats
+        } else {
+            return None;
+        };
+
+        Some(stats.unwrap_or_default())
+    }
+
+    /// Item statistics of the config, KV or secret store with this ID, to update.
+    pub fn store_stats_mut(&mut self, store_id: &str) -> Option<&mut StoreStats> {
+        let stats = if let Some(store) = self.config_stores.get_mut(store_id) {
+            &mut store.stats
+        } else if let Some(store) = self.kv_stores.get_mut(store_id) {
+            &mut store.stats
+        } else if let Some(store) = self.secret_stores.get_mut(store_id) {
+            &mut store.stats
+        } else {
+            return None;
+        };
+
+        Some(stats.get_or_insert_default())
+    }
+}
+
+/// Number of items in a store and their total size, kept up to date by every write so they can
+/// be reported without scanning the store.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+pub struct StoreStats {
+    pub item_count: u64,
+    /// Total size of the keys and values, in bytes
+    pub total_size: u64,
+}
+
+impl StoreStats {
+    pub fn apply(&mut self, delta: StatsDelta) {
+        self.item_count = self.item_count.saturating_add_signed(delta.items);
+        self.total_size = self.total_size.saturating_add_signed(delta.bytes);
+    }
+}
+
+/// Change to the [`StoreStats`] of a store made by a transaction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatsDelta {
+    items: i64,
+    bytes: i64,
+}
+
+impl StatsDelta {
+    /// Record a write of an item of `size` bytes, replacing an item of `previous` bytes if any.
+    pub fn write(&mut self, previous: Option<usize>, size: usize) {
+        match previous {
+            Some(previous) => self.bytes += size as i64 - previous as i64,
+            None => {
+                self.items += 1;
+                self.bytes += size as i64;
+            }
+        }
+    }
+
+    pub fn remove(&mut self, size: usize) {
+        self.items -= 1;
+        self.bytes -= size as i64;
+    }
+
+    /// Add the change to the statistics of a store in `__meta__`, unless it has none.
+    pub fn commit(self, tx: &redb::WriteTransaction, store_id: &str) -> Result<(), redb::Error> {
+        use redb::ReadableTable;
+
+        if self == StatsDelta::default() {
+            return Ok(());
+        }
+
+        let mut metadata_table = tx.open_table(METADATA_TABLE)?;
+        let Some(mut metadata) = metadata_table.get(&())?.map(|record| record.value().0) else {
+            return Ok(());
+        };
+        let Some(stats) = metadata.store_stats_mut(store_id) else {
+            return Ok(());
+        };
+        stats.apply(self);
+
+        metadata_table.insert(&(), &JsonRecord(metadata))?;
+
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ConfigStoreMetadata {
     pub name: String,
+    /// Missing in databases written before stores kept statistics, until backfilled on open
+    #[serde(default)]
+    pub stats: Option<StoreStats>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    /// Set while the store is soft deleted, until it's restored or purged
+    #[serde(default)]
+    pub deleted_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -35,37 +140,232 @@ pub struct ConfigStoreItemMetadata {
     pub updated_at: DateTime<Utc>,
 }
 
+impl ConfigStoreItemMetadata {
+    /// Size counted in [`StoreStats::total_size`].
+    pub fn size(&self, key: &str) -> usize {
+        key.len() + self.item_value.len()
+    }
+}
+
 pub type ConfigStoreTable<'a> = TableDefinition<'a, String, JsonRecord<ConfigStoreItemMetadata>>;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct KVStoreMetadata {
     pub name: String,
+    #[serde(default)]
+    pub stats: Option<StoreStats>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    #[serde(default)]
+    pub deleted_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct KVStoreItemMetadata {
     #[serde(with = "serde_with::As::<serde_with::base64::Base64>")]
     pub value: Bytes,
+    /// User metadata, returned to guests by `lookup().metadata()`
+    #[serde(default)]
+    pub metadata: Option<String>,
+    /// Incremented on every write to the key
+    #[serde(default = "default_generation")]
+    pub generation: u64,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
+impl KVStoreItemMetadata {
+    /// Size counted in [`StoreStats::total_size`].
+    pub fn size(&self, key: &str) -> usize {
+        key.len() + self.value.len() + self.metadata.as_ref().map_or(0, String::len)
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Remaining time to live, if the item expires.
+    pub fn time_to_live(&self, now: DateTime<Utc>) -> Option<std::time::Duration> {
+        self.expires_at
+            .map(|expires_at| (expires_at - now).to_std().unwrap_or_default())
+    }
+}
+
+fn default_generation() -> u64 {
+    1
+}
+
 pub type KVStoreTable<'a> = TableDefinition<'a, String, JsonRecord<KVStoreItemMetadata>>;
 
+/// KV store items with a TTL, by expiry time in milliseconds, store ID and key
+pub type KVExpiryTable<'a> = TableDefinition<'a, (i64, String, String), ()>;
+
+pub const KV_EXPIRY_TABLE: KVExpiryTable = TableDefinition::new("__kv_expiry__");
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct SecretStoreMetadata {
     pub name: String,
+    #[serde(default)]
+    pub stats: Option<StoreStats>,
     pub created_at: DateTime<Utc>,
+    /// Unset for stores last updated before updates were recorded
+    #[serde(default)]
+    pub updated_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub deleted_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SecretStoreItemMetadata {
     pub name: String,
+    /// The secret, encrypted with the data key of `envelope`, or in plaintext without one as
+    /// written before secrets were encrypted at rest
     #[serde(with = "serde_with::As::<serde_with::base64::Base64>")]
     pub secret: Bytes,
+    #[serde(default)]
+    pub envelope: Option<Envelope>,
+    /// Digest of the plaintext, so it can be reported without decrypting the secret
+    #[serde(default, with = "serde_with::As::<Option<serde_with::base64::Base64>>")]
+    pub digest: Option<Bytes>,
+    /// ID of the master key `digest` is keyed with, unset for digests computed before they were
+    /// keyed with it
+    #[serde(default)]
+    pub digest_key_id: Option<String>,
     pub created_at: DateTime<Utc>,
 }
 
+/// How a secret is encrypted: with its own data key, itself encrypted with the master key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    /// ID of the master key the data key is encrypted with
+    pub key_id: String,
+    #[serde(with = "serde_with::As::<serde_with::base64::Base64>")]
+    pub data_key: Bytes,
+    #[serde(with = "serde_with::As::<serde_with::base64::Base64>")]
+    pub data_key_nonce: Bytes,
+    #[serde(with = "serde_with::As::<serde_with::base64::Base64>")]
+    pub nonce: Bytes,
+}
+
+impl SecretStoreItemMetadata {
+    /// Size counted in [`StoreStats::total_size`].
+    pub fn size(&self) -> usize {
+        self.name.len() + self.secret.len()
+    }
+}
+
 pub type SecretStoreTable<'a> = TableDefinition<'a, String, JsonRecord<SecretStoreItemMetadata>>;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ResourceLinkMetadata {
+    pub service_id: String,
+    pub version: u32,
+    pub name: String,
+    pub resource_id: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ServiceMetadata {
+    pub name: String,
+    #[serde(default)]
+    pub comment: String,
+    /// Versions of the service, where version `n` is at index `n - 1`
+    pub versions: Vec<ServiceVersionMetadata>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl ServiceMetadata {
+    pub fn version(&self, number: u32) -> Option<&ServiceVersionMetadata> {
+        let index = usize::try_from(number).ok()?.checked_sub(1)?;
+        self.versions.get(index)
+    }
+
+    pub fn version_mut(&mut self, number: u32) -> Option<&mut ServiceVersionMetadata> {
+        let index = usize::try_from(number).ok()?.checked_sub(1)?;
+        self.versions.get_mut(index)
+    }
+
+    pub fn active_version(&self) -> Option<&ServiceVersionMetadata> {
+        self.versions.iter().find(|version| version.active)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ServiceVersionMetadata {
+    pub number: u32,
+    pub active: bool,
+    pub locked: bool,
+    #[serde(default)]
+    pub comment: String,
+    #[serde(default)]
+    pub domains: Vec<DomainMetadata>,
+    pub package: Option<PackageMetadata>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DomainMetadata {
+    pub name: String,
+    #[serde(default)]
+    pub comment: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PackageMetadata {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub authors: Vec<String>,
+    pub language: String,
+    pub size: u64,
+    pub hashsum: String,
+    pub files_hash: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Uploaded Compute package archives, by service ID and version
+pub type PackagesTable<'a> = TableDefinition<'a, (String, u32), &'static [u8]>;
+
+pub const PACKAGES_TABLE: PackagesTable = TableDefinition::new("__packages__");
+
+/// Scope of an API token, limiting the requests it authorizes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TokenScope {
+    /// Any request
+    #[serde(rename = "global")]
+    Global,
+    /// Read-only requests
+    #[serde(rename = "global:read")]
+    GlobalRead,
+    /// Purging by URL or surrogate key
+    #[serde(rename = "purge_select")]
+    PurgeSelect,
+    /// Purging everything cached for a service
+    #[serde(rename = "purge_all")]
+    PurgeAll,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenMetadata {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<TokenScope>,
+    /// Services the token is restricted to, any service if empty
+    pub services: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// API tokens, by the hex encoded SHA-256 digest of the token
+pub type TokensTable<'a> = TableDefinition<'a, String, JsonRecord<TokenMetadata>>;
+
+pub const TOKENS_TABLE: TokensTable = TableDefinition::new("__tokens__");
@@ -0,0 +1,291 @@
+//! API tokens, checked like Fastly checks the `Fastly-Key` header of API requests.
+
+use chrono::{DateTime, Utc};
+use http::Method;
+use redb::{ReadableTable, WriteTransaction};
+
+use crate::tables::{TOKENS_TABLE, TokenMetadata, TokenScope};
+use crate::util::JsonRecord;
+
+impl std::str::FromStr for TokenScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "global" => Ok(TokenScope::Global),
+            "global:read" => Ok(TokenScope::GlobalRead),
+            "purge_select" => Ok(TokenScope::PurgeSelect),
+            "purge_all" => Ok(TokenScope::PurgeAll),
+            _ => Err(format!("unknown token scope `{s}`")),
+        }
+    }
+}
+
+impl TokenScope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TokenScope::Global => "global",
+            TokenScope::GlobalRead => "global:read",
+            TokenScope::PurgeSelect => "purge_select",
+            TokenScope::PurgeAll => "purge_all",
+        }
+    }
+
+    /// Parse Fastly's space separated list of scopes.
+    pub fn parse_list(s: &str) -> Result<Vec<Self>, String> {
+        s.split_whitespace().map(str::parse).collect()
+    }
+
+    fn allows(self, method: &Method, path: &str) -> bool {
+        let purge_all = path.ends_with("/purge_all");
+        let purge = purge_all || path.starts_with("/purge/") || path.contains("/purge/");
+
+        match self {
+            TokenScope::Global => true,
+            TokenScope::GlobalRead => matches!(*method, Method::GET | Method::HEAD),
+            TokenScope::PurgeSelect => *method == Method::POST && purge && !purge_all,
+            TokenScope::PurgeAll => *method == Method::POST && purge_all,
+        }
+    }
+}
+
+/// Why a token doesn't authorize a request.
+#[derive(Debug, thiserror::Error)]
+pub enum Denied {
+    #[error("Token scope does not allow this request")]
+    Scope,
+    #[error("Token is not authorized for service {0}")]
+    Service(String),
+    #[error("Managing tokens requires a global token not restricted to services")]
+    TokenManagement,
+    #[error("Token is restricted to services and can't access account resources")]
+    AccountResources,
+}
+
+impl TokenMetadata {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Check that the token authorizes a request with this method and path.
+    ///
+    /// Tokens can create tokens of any scope, so managing them needs an unrestricted `global`
+    /// token, and stores belong to the account rather than to services.
+    pub fn authorize(&self, method: &Method, path: &str) -> Result<(), Denied> {
+        let restricted = !self.services.is_empty();
+        let under = |prefix: &str| {
+            path.strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        };
+
+        if under("/tokens") {
+            return match !restricted && self.scopes.contains(&TokenScope::Global) {
+                true => Ok(()),
+                false => Err(Denied::TokenManagement),
+            };
+        }
+
+        if !self.scopes.iter().any(|scope| scope.allows(method, path)) {
+            return Err(Denied::Scope);
+        }
+
+        if restricted && under("/resources") {
+            return Err(Denied::AccountResources);
+        }
+
+        if restricted
+            && let Some(service_id) = path
+                .strip_prefix("/service/")
+                .and_then(|rest| rest.split('/').next())
+                .filter(|service_id| !service_id.is_empty() && *service_id != "search")
+            && !self.services.iter().any(|id| id == service_id)
+        {
+            return Err(Denied::Service(service_id.to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Scopes as Fastly lists them, separated by spaces.
+    pub fn scope(&self) -> String {
+        self.scopes
+            .iter()
+            .map(|scope| scope.as_str())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+fn token_hash(token: &str) -> String {
+    use sha2::{Digest, Sha256};
+
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Store a new token, returning it. Only its digest is stored, so it can't be shown again.
+pub fn create_token(tx: &WriteTransaction, meta: &TokenMetadata) -> Result<String, redb::Error> {
+    use base64::Engine;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use crypto_box::aead::OsRng;
+    use crypto_box::aead::rand_core::RngCore;
+
+    let mut bytes = [0; 24];
+    OsRng.fill_bytes(&mut bytes);
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+
+    tx.open_table(TOKENS_TABLE)?
+        .insert(&token_hash(&token), &JsonRecord(meta.clone()))?;
+
+    Ok(token)
+}
+
+/// Look up the token sent in a `Fastly-Key` header.
+pub fn find_token(
+    tx: &redb::ReadTransaction,
+    token: &str,
+) -> Result<Option<TokenMetadata>, redb::Error> {
+    let table = match tx.open_table(TOKENS_TABLE) {
+        Ok(table) => table,
+        Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(table
+        .get(&token_hash(token))?
+        .map(|record| record.value().0))
+}
+
+/// Delete the token with this ID, returning whether it existed.
+pub fn delete_token(tx: &WriteTransaction, id: &str) -> Result<bool, redb::Error> {
+    let mut table = tx.open_table(TOKENS_TABLE)?;
+
+    let hash = table
+        .iter()?
+        .filter_map(|entry| entry.ok())
+        .find(|(_, record)| record.value().0.id == id)
+        .map(|(hash, _)| hash.value());
+
+    match hash {
+        Some(hash) => {
+            table.remove(&hash)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(scopes: &[TokenScope], services: &[&str]) -> TokenMetadata {
+        TokenMetadata {
+            id: "token".to_string(),
+            name: String::new(),
+            scopes: scopes.to_vec(),
+            services: services.iter().map(|id| id.to_string()).collect(),
+            created_at: Utc::now(),
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn unrestricted_global_tokens_allow_everything() {
+        let token = token(&[TokenScope::Global], &[]);
+
+        for (method, path) in [
+            (Method::POST, "/tokens"),
+            (Method::GET, "/tokens"),
+            (Method::DELETE, "/tokens/other"),
+            (Method::POST, "/resources/stores/kv"),
+            (Method::DELETE, "/service/a"),
+        ] {
+            assert!(token.authorize(&method, path).is_ok(), "{method} {path}");
+        }
+    }
+
+    #[test]
+    fn service_tokens_are_limited_to_their_services() {
+        let token = token(&[TokenScope::Global], &["a"]);
+
+        assert!(token.authorize(&Method::POST, "/service/a/version").is_ok());
+        assert!(token.authorize(&Method::GET, "/service/search").is_ok());
+        assert!(matches!(
+            token.authorize(&Method::POST, "/service/b/version"),
+            Err(Denied::Service(id)) if id == "b"
+        ));
+        assert!(matches!(
+            token.authorize(&Method::POST, "/tokens"),
+            Err(Denied::TokenManagement)
+        ));
+        assert!(matches!(
+            token.authorize(&Method::DELETE, "/tokens/other"),
+            Err(Denied::TokenManagement)
+        ));
+        for method in [Method::GET, Method::POST] {
+            assert!(matches!(
+                token.authorize(&method, "/resources/stores/kv"),
+                Err(Denied::AccountResources)
+            ));
+        }
+    }
+
+    #[test]
+    fn scopes_limit_methods_and_paths() {
+        let read = token(&[TokenScope::GlobalRead], &[]);
+        assert!(read.authorize(&Method::GET, "/resources/stores/kv").is_ok());
+        assert!(matches!(
+            read.authorize(&Method::POST, "/resources/stores/kv"),
+            Err(Denied::Scope)
+        ));
+        assert!(matches!(
+            read.authorize(&Method::GET, "/tokens"),
+            Err(Denied::TokenManagement)
+        ));
+
+        let purge = token(&[TokenScope::PurgeSelect], &[]);
+        assert!(
+            purge
+                .authorize(&Method::POST, "/service/a/purge/key")
+                .is_ok()
+        );
+        assert!(
+            purge
+                .authorize(&Method::POST, "/purge/example.com/a")
+                .is_ok()
+        );
+        assert!(matches!(
+            purge.authorize(&Method::POST, "/service/a/purge_all"),
+            Err(Denied::Scope)
+        ));
+        assert!(matches!(
+            purge.authorize(&Method::POST, "/tokens"),
+            Err(Denied::TokenManagement)
+        ));
+
+        let purge_all = token(&[TokenScope::PurgeAll], &["a"]);
+        assert!(
+            purge_all
+                .authorize(&Method::POST, "/service/a/purge_all")
+                .is_ok()
+        );
+        assert!(matches!(
+            purge_all.authorize(&Method::POST, "/service/b/purge_all"),
+            Err(Denied::Service(_))
+        ));
+        assert!(matches!(
+            purge_all.authorize(&Method::GET, "/service/a"),
+            Err(Denied::Scope)
+        ));
+
+        let both = token(&[TokenScope::GlobalRead, TokenScope::PurgeAll], &[]);
+        assert!(both.authorize(&Method::GET, "/service/a").is_ok());
+        assert!(
+            both.authorize(&Method::POST, "/service/a/purge_all")
+                .is_ok()
+        );
+    }
+}
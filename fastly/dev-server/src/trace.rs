@@ -1,7 +1,10 @@
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::metrics::SdkMeterProvider;
 use opentelemetry_sdk::trace::SdkTracerProvider;
 
 pub struct TraceGuard {
     tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
 }
 
 impl Drop for TraceGuard {
@@ -9,6 +12,9 @@ impl Drop for TraceGuard {
         if let Err(err) = self.tracer_provider.shutdown() {
             eprintln!("Failed to shutdown tracer provider: {err}");
         }
+        if let Err(err) = self.meter_provider.shutdown() {
+            eprintln!("Failed to shutdown meter provider: {err}");
+        }
     }
 }
 
@@ -22,11 +28,16 @@ pub fn setup_tracing() -> TraceGuard {
         .with_target(false)
         .with_writer(std::io::stderr);
 
+    let resource = otel_resource();
+
+    let meter_provider = setup_otel_meter_provider(resource.clone());
+    opentelemetry::global::set_meter_provider(meter_provider.clone());
+
     let (otel_layer, tracer_provider) = {
         use opentelemetry::trace::TracerProvider;
         use tracing_subscriber::filter::{EnvFilter, LevelFilter};
 
-        let tracer_provider = setup_otel_tracer_provider();
+        let tracer_provider = setup_otel_tracer_provider(resource);
 
         let tracer = tracer_provider.tracer("fastly-dev-server");
         let layer = tracing_opentelemetry::layer().with_tracer(tracer);
@@ -50,24 +61,31 @@ pub fn setup_tracing() -> TraceGuard {
         .with(otel_layer)
         .init();
 
-    TraceGuard { tracer_provider }
+    TraceGuard {
+        tracer_provider,
+        meter_provider,
+    }
 }
 
-fn setup_otel_tracer_provider() -> SdkTracerProvider {
-    use opentelemetry::{KeyValue, global};
-    use opentelemetry_otlp::SpanExporter;
-    use opentelemetry_sdk::{Resource, propagation::TraceContextPropagator};
+fn otel_resource() -> Resource {
+    use opentelemetry::KeyValue;
     use opentelemetry_semantic_conventions::{SCHEMA_URL, attribute::SERVICE_VERSION};
 
-    global::set_text_map_propagator(TraceContextPropagator::new());
-
-    let resource = Resource::builder()
+    Resource::builder()
         .with_service_name("fastly-dev-server")
         .with_schema_url(
             [KeyValue::new(SERVICE_VERSION, env!("CARGO_PKG_VERSION"))],
             SCHEMA_URL,
         )
-        .build();
+        .build()
+}
+
+fn setup_otel_tracer_provider(resource: Resource) -> SdkTracerProvider {
+    use opentelemetry::global;
+    use opentelemetry_otlp::SpanExporter;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
 
     let exporter = SpanExporter::builder()
         .with_http()
@@ -79,3 +97,17 @@ fn setup_otel_tracer_provider() -> SdkTracerProvider {
         .with_resource(resource)
         .build()
 }
+
+fn setup_otel_meter_provider(resource: Resource) -> SdkMeterProvider {
+    use opentelemetry_otlp::MetricExporter;
+
+    let exporter = MetricExporter::builder()
+        .with_http()
+        .build()
+        .expect("Failed to build OTLP metric exporter");
+
+    SdkMeterProvider::builder()
+        .with_periodic_exporter(exporter)
+        .with_resource(resource)
+        .build()
+}
@@ -12,6 +12,7 @@ pub struct OtelTrace;
 impl<B> trace::MakeSpan<B> for OtelTrace {
     fn make_span(&mut self, request: &http::Request<B>) -> tracing::Span {
         use axum::extract::MatchedPath;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
 
         let path = if let Some(matched_path) = request.extensions().get::<MatchedPath>() {
             matched_path.as_str()
@@ -19,7 +20,7 @@ impl<B> trace::MakeSpan<B> for OtelTrace {
             request.uri().path()
         };
 
-        tracing::debug_span!(
+        let span = tracing::debug_span!(
             "request",
             otel.name = format!("{} {path}", request.method()),
             otel.kind = "server",
@@ -30,7 +31,53 @@ impl<B> trace::MakeSpan<B> for OtelTrace {
             { otel::NETWORK_PROTOCOL_VERSION } = ?request.version(),
             { otel::OTEL_STATUS_CODE } = field::Empty,
             { otel::HTTP_RESPONSE_STATUS_CODE } = field::Empty,
-        )
+        );
+
+        // Continue the caller's trace, so requests between chained services share a trace.
+        let parent_cx = opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(request.headers()))
+        });
+        let _ = span.set_parent(parent_cx);
+
+        span
+    }
+}
+
+struct HeaderExtractor<'a>(&'a http::HeaderMap);
+
+impl opentelemetry::propagation::Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Headers carrying the trace context of the current span, such as `traceparent`.
+pub fn trace_context_headers() -> http::HeaderMap {
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    let mut headers = http::HeaderMap::new();
+    let cx = tracing::Span::current().context();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&cx, &mut HeaderInjector(&mut headers));
+    });
+
+    headers
+}
+
+struct HeaderInjector<'a>(&'a mut http::HeaderMap);
+
+impl opentelemetry::propagation::Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            http::HeaderName::try_from(key),
+            http::HeaderValue::try_from(value),
+        ) {
+            self.0.insert(name, value);
+        }
     }
 }
 